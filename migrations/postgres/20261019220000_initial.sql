@@ -0,0 +1,163 @@
+-- The whole schema as of the SQLite migrations up to post_replies. Queries
+-- address rows by SQLite's implicit rowid, so every table here spells it out.
+-- Integers are BIGINT throughout, flags are 0/1 and timestamps are RFC 3339
+-- text, compared bytewise the way SQLite compares them.
+
+CREATE TABLE users (
+    rowid BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    name TEXT NOT NULL DEFAULT 'Default User',
+    bio TEXT NOT NULL DEFAULT 'Default Bio'
+);
+
+CREATE UNIQUE INDEX users_username ON users(username);
+
+CREATE TABLE posts (
+    rowid BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    content TEXT NOT NULL,
+    posted_timestamp TEXT COLLATE "C" NOT NULL,
+    short_url TEXT,
+    images TEXT NOT NULL DEFAULT '[]',
+    published BIGINT NOT NULL DEFAULT 1,
+    edited_timestamp TEXT COLLATE "C",
+    deleted_timestamp TEXT COLLATE "C",
+    visibility TEXT NOT NULL DEFAULT 'public',
+    pinned_timestamp TEXT COLLATE "C",
+    in_reply_to TEXT
+);
+
+CREATE UNIQUE INDEX posts_short_url ON posts(short_url);
+CREATE INDEX posts_posted_timestamp ON posts(posted_timestamp);
+CREATE INDEX posts_deleted_timestamp ON posts(deleted_timestamp);
+
+CREATE TABLE image_drafts (
+    rowid BIGSERIAL PRIMARY KEY,
+    image_thumbnail_path TEXT,
+    image_medium_path TEXT,
+    image_full_path TEXT
+);
+
+CREATE TABLE post_revisions (
+    rowid BIGSERIAL PRIMARY KEY,
+    post_id BIGINT NOT NULL,
+    content TEXT NOT NULL,
+    short_url TEXT,
+    revised_timestamp TEXT COLLATE "C" NOT NULL
+);
+
+CREATE INDEX post_revisions_post_id ON post_revisions(post_id);
+
+CREATE TABLE api_tokens (
+    rowid BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    created_timestamp TEXT COLLATE "C" NOT NULL,
+    last_used_timestamp TEXT COLLATE "C"
+);
+
+CREATE UNIQUE INDEX api_tokens_token_hash ON api_tokens(token_hash);
+
+CREATE TABLE indieauth_codes (
+    rowid BIGSERIAL PRIMARY KEY,
+    code_hash TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    redirect_uri TEXT NOT NULL,
+    code_challenge TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    created_timestamp TEXT COLLATE "C" NOT NULL
+);
+
+CREATE UNIQUE INDEX indieauth_codes_code_hash ON indieauth_codes(code_hash);
+
+CREATE TABLE webmention_outbox (
+    rowid BIGSERIAL PRIMARY KEY,
+    post_id BIGINT NOT NULL,
+    target TEXT NOT NULL,
+    attempts BIGINT NOT NULL DEFAULT 0,
+    next_attempt_timestamp TEXT COLLATE "C" NOT NULL,
+    sent_timestamp TEXT COLLATE "C",
+    failed_timestamp TEXT COLLATE "C"
+);
+
+CREATE UNIQUE INDEX webmention_outbox_post_target ON webmention_outbox(post_id, target);
+
+CREATE TABLE webmentions (
+    rowid BIGSERIAL PRIMARY KEY,
+    post_id BIGINT NOT NULL,
+    source TEXT NOT NULL,
+    target TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    approved BIGINT NOT NULL DEFAULT 0,
+    title TEXT,
+    received_timestamp TEXT COLLATE "C" NOT NULL,
+    verified_timestamp TEXT COLLATE "C"
+);
+
+CREATE UNIQUE INDEX webmentions_source_target ON webmentions(source, target);
+CREATE INDEX webmentions_post_id ON webmentions(post_id);
+
+CREATE TABLE activitypub_keys (
+    rowid BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    private_key TEXT NOT NULL,
+    public_key TEXT NOT NULL,
+    created_timestamp TEXT COLLATE "C" NOT NULL
+);
+
+CREATE UNIQUE INDEX activitypub_keys_user_id ON activitypub_keys(user_id);
+
+CREATE TABLE activitypub_followers (
+    rowid BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    actor TEXT NOT NULL,
+    inbox TEXT NOT NULL,
+    followed_timestamp TEXT COLLATE "C" NOT NULL
+);
+
+CREATE UNIQUE INDEX activitypub_followers_user_actor ON activitypub_followers(user_id, actor);
+
+CREATE TABLE activitypub_deliveries (
+    rowid BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    inbox TEXT NOT NULL,
+    activity TEXT NOT NULL,
+    attempts BIGINT NOT NULL DEFAULT 0,
+    next_attempt_timestamp TEXT COLLATE "C" NOT NULL,
+    delivered_timestamp TEXT COLLATE "C",
+    failed_timestamp TEXT COLLATE "C"
+);
+
+CREATE INDEX activitypub_deliveries_next_attempt ON activitypub_deliveries(next_attempt_timestamp);
+
+CREATE TABLE profile_links (
+    rowid BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    url TEXT NOT NULL,
+    position BIGINT NOT NULL,
+    verified_timestamp TEXT COLLATE "C",
+    checked_timestamp TEXT COLLATE "C"
+);
+
+CREATE UNIQUE INDEX profile_links_user_url ON profile_links(user_id, url);
+
+CREATE TABLE imported_posts (
+    rowid BIGSERIAL PRIMARY KEY,
+    source TEXT NOT NULL,
+    source_id TEXT NOT NULL,
+    post_id BIGINT NOT NULL,
+    imported_timestamp TEXT COLLATE "C" NOT NULL
+);
+
+CREATE UNIQUE INDEX imported_posts_source_id ON imported_posts(source, source_id);
+
+CREATE TABLE restores (
+    rowid BIGSERIAL PRIMARY KEY,
+    checksum TEXT NOT NULL,
+    source_path TEXT NOT NULL,
+    restored_timestamp TEXT COLLATE "C" NOT NULL
+);
+
+CREATE UNIQUE INDEX restores_checksum ON restores(checksum);
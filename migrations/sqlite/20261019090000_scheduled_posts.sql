@@ -0,0 +1,4 @@
+-- Posts with a posted_timestamp in the future stay unpublished until the
+-- scheduler publishes them
+
+ALTER TABLE posts ADD COLUMN published INT NOT NULL DEFAULT 1;
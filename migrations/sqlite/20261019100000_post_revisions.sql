@@ -0,0 +1,12 @@
+-- Every edit keeps the version it replaced, so edits can be diffed and undone
+
+CREATE TABLE post_revisions (
+    post_id INT NOT NULL,
+    content TEXT NOT NULL,
+    short_url TEXT,
+    revised_timestamp TEXT NOT NULL
+);
+
+CREATE INDEX post_revisions_post_id ON post_revisions(post_id);
+
+ALTER TABLE posts ADD COLUMN edited_timestamp TEXT;
@@ -0,0 +1,5 @@
+-- Deleted posts are kept in the trash until they are purged
+
+ALTER TABLE posts ADD COLUMN deleted_timestamp TEXT;
+
+CREATE INDEX posts_deleted_timestamp ON posts(deleted_timestamp);
@@ -0,0 +1,3 @@
+-- One of 'public', 'unlisted' or 'private'
+
+ALTER TABLE posts ADD COLUMN visibility TEXT NOT NULL DEFAULT 'public';
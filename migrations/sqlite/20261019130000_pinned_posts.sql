@@ -0,0 +1,3 @@
+-- Pinned posts have the time they were pinned, which also orders them
+
+ALTER TABLE posts ADD COLUMN pinned_timestamp TEXT;
@@ -0,0 +1,6 @@
+-- Pagination uses (posted_timestamp, rowid) cursors now, so posts may share a
+-- timestamp
+
+DROP INDEX posts_posted_timestamp;
+
+CREATE INDEX posts_posted_timestamp ON posts(posted_timestamp);
@@ -0,0 +1,13 @@
+-- Personal access tokens for the API. Only a hash of the token is kept, the
+-- token itself is shown once when it's created
+
+CREATE TABLE api_tokens (
+    user_id INT NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    created_timestamp TEXT NOT NULL,
+    last_used_timestamp TEXT
+);
+
+CREATE UNIQUE INDEX api_tokens_token_hash ON api_tokens(token_hash);
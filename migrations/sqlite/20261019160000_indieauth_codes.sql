@@ -0,0 +1,13 @@
+-- Authorization codes handed out by the IndieAuth endpoint. They're single
+-- use and short lived, and like API tokens only their hash is kept
+
+CREATE TABLE indieauth_codes (
+    code_hash TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    redirect_uri TEXT NOT NULL,
+    code_challenge TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    created_timestamp TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX indieauth_codes_code_hash ON indieauth_codes(code_hash);
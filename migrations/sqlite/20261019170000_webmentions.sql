@@ -0,0 +1,32 @@
+-- Webmentions we owe other sites because one of our posts links to them.
+-- Rows are reset rather than duplicated when a post is edited, so the
+-- receiver hears about the change (or the removed link) too
+
+CREATE TABLE webmention_outbox (
+    post_id INT NOT NULL,
+    target TEXT NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt_timestamp TEXT NOT NULL,
+    sent_timestamp TEXT,
+    failed_timestamp TEXT
+);
+
+CREATE UNIQUE INDEX webmention_outbox_post_target ON webmention_outbox(post_id, target);
+
+-- Webmentions other sites have sent us. They start out pending until the
+-- source page has been fetched and checked, and only show up on the post
+-- once approved
+
+CREATE TABLE webmentions (
+    post_id INT NOT NULL,
+    source TEXT NOT NULL,
+    target TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    approved INT NOT NULL DEFAULT 0,
+    title TEXT,
+    received_timestamp TEXT NOT NULL,
+    verified_timestamp TEXT
+);
+
+CREATE UNIQUE INDEX webmentions_source_target ON webmentions(source, target);
+CREATE INDEX webmentions_post_id ON webmentions(post_id);
@@ -0,0 +1,37 @@
+-- Each user's signing key for ActivityPub, generated the first time it's
+-- needed. Other servers fetch the public half from the actor document.
+
+CREATE TABLE activitypub_keys (
+    user_id INT NOT NULL,
+    private_key TEXT NOT NULL,
+    public_key TEXT NOT NULL,
+    created_timestamp TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX activitypub_keys_user_id ON activitypub_keys(user_id);
+
+-- Remote actors following one of our users, and the inbox to deliver to
+
+CREATE TABLE activitypub_followers (
+    user_id INT NOT NULL,
+    actor TEXT NOT NULL,
+    inbox TEXT NOT NULL,
+    followed_timestamp TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX activitypub_followers_user_actor ON activitypub_followers(user_id, actor);
+
+-- Signed activities waiting to be delivered to an inbox, retried with
+-- backoff until they're accepted or we give up
+
+CREATE TABLE activitypub_deliveries (
+    user_id INT NOT NULL,
+    inbox TEXT NOT NULL,
+    activity TEXT NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    next_attempt_timestamp TEXT NOT NULL,
+    delivered_timestamp TEXT,
+    failed_timestamp TEXT
+);
+
+CREATE INDEX activitypub_deliveries_next_attempt ON activitypub_deliveries(next_attempt_timestamp);
@@ -0,0 +1,12 @@
+-- Links to a user's other profiles, shown as rel="me". A link is verified
+-- once the page it points at links back to us with rel="me" too.
+
+CREATE TABLE profile_links (
+    user_id INT NOT NULL,
+    url TEXT NOT NULL,
+    position INT NOT NULL,
+    verified_timestamp TEXT,
+    checked_timestamp TEXT
+);
+
+CREATE UNIQUE INDEX profile_links_user_url ON profile_links(user_id, url);
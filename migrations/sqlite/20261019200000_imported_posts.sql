@@ -0,0 +1,11 @@
+-- Where imported posts came from, so importing the same archive again
+-- skips the posts it already brought in
+
+CREATE TABLE imported_posts (
+    source TEXT NOT NULL,
+    source_id TEXT NOT NULL,
+    post_id INT NOT NULL,
+    imported_timestamp TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX imported_posts_source_id ON imported_posts(source, source_id);
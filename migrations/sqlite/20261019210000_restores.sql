@@ -0,0 +1,10 @@
+-- Backups restored through RESTORE_PATH, by checksum, so a restart with the
+-- variable still set doesn't restore the same backup over newer data
+
+CREATE TABLE restores (
+    checksum TEXT NOT NULL,
+    source_path TEXT NOT NULL,
+    restored_timestamp TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX restores_checksum ON restores(checksum);
@@ -0,0 +1,4 @@
+-- The post this one replies to, by URL. Replies within a thread of our own
+-- posts point at the earlier post's /post/view/:post_id URL.
+
+ALTER TABLE posts ADD COLUMN in_reply_to TEXT;
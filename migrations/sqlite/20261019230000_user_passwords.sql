@@ -0,0 +1,4 @@
+-- Passwords set from the command line, as PBKDF2 hashes. Users without one
+-- log in with ADMIN_PASSWORD from the config, as before.
+
+ALTER TABLE users ADD COLUMN password_hash TEXT;
@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_std::io::ReadExt;
+use chrono::prelude::*;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tide::http::Url;
+use tide::Request;
+
+use super::State;
+use super::config::Config;
+use super::outbound;
+use super::repo::PostRepo;
+use super::routes::Post;
+
+/// Addressing an activity to this collection makes it public
+pub const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// Content type for ActivityPub documents
+pub const CONTENT_TYPE: &str = "application/activity+json";
+
+/// How often the background task delivers queued activities
+const DELIVERY_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long to wait on another server before giving up on this attempt
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest remote document we're willing to read
+const MAX_DOCUMENT_BYTES: u64 = 1024 * 1024;
+
+/// Deliveries that keep failing are retried with exponential backoff, then dropped
+const MAX_DELIVERY_ATTEMPTS: i64 = 8;
+
+/// How many queued deliveries to send per check
+const BATCH_SIZE: i64 = 20;
+
+/// Signed requests dated further than this from now are rejected as replays
+const MAX_SIGNATURE_AGE_HOURS: i64 = 12;
+
+/// Where a user's actor document lives
+pub fn actor_url(state: &State, username: &str) -> String {
+    format!("{}/users/{}", state.config.site_url, username)
+}
+
+/// The id of a post's Note, which is also where people read it
+pub fn note_url(state: &State, post_id: i64) -> String {
+    format!("{}/post/view/{}", state.config.site_url, post_id)
+}
+
+/// Our host as it appears in acct: URIs, including a non-default port
+pub fn domain(state: &State) -> String {
+    let url = Url::parse(&state.config.site_url).unwrap();
+
+    match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string()
+    }
+}
+
+/// Whether a request asked for ActivityPub JSON rather than HTML
+pub fn wants_activity_json(req: &Request<State>) -> bool {
+    req.header("Accept").is_some_and(|accept| {
+        let accept = accept.as_str();
+
+        accept.contains(CONTENT_TYPE) || accept.contains("application/ld+json")
+    })
+}
+
+/// Public and unlisted posts federate; private ones never leave the site
+pub fn is_federated(post: &Post) -> bool {
+    post.published && post.deleted_timestamp.is_none() && post.visibility != "private"
+}
+
+/// A user's signing key as (private, public) PEM, generated on first use
+pub async fn user_key(state: &State, user_id: i64) -> tide::Result<(String, String)> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let row: Option<(String, String)> = sqlx::query_as("SELECT private_key, public_key FROM activitypub_keys WHERE user_id=$1")
+        .bind(user_id)
+        .fetch_optional(&mut db_conn)
+        .await?;
+
+    if let Some(keys) = row {
+        return Ok(keys);
+    }
+
+    let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let private_key = String::from_utf8(key.private_key_to_pem_pkcs8()?)?;
+    let public_key = String::from_utf8(key.public_key_to_pem()?)?;
+    let now = Utc::now().to_rfc3339();
+
+    // Two requests racing to create the key keep whichever was stored first
+    sqlx::query(
+            r#"INSERT INTO activitypub_keys (user_id, private_key, public_key, created_timestamp)
+            VALUES ($1, $2, $3, $4) ON CONFLICT (user_id) DO NOTHING"#
+        )
+        .bind(user_id)
+        .bind(&private_key)
+        .bind(&public_key)
+        .bind(&now)
+        .execute(&mut db_conn)
+        .await?;
+
+    let keys = sqlx::query_as("SELECT private_key, public_key FROM activitypub_keys WHERE user_id=$1")
+        .bind(user_id)
+        .fetch_one(&mut db_conn)
+        .await?;
+
+    Ok(keys)
+}
+
+fn render_markdown(content: &str) -> String {
+    let mut html = String::new();
+
+    pulldown_cmark::html::push_html(&mut html, pulldown_cmark::Parser::new(content));
+
+    html
+}
+
+/// Who a post is addressed to, as (to, cc). Unlisted posts are public but
+/// kept off public timelines, which is how Mastodon treats them too.
+fn addressing(state: &State, post: &Post) -> (Value, Value) {
+    let followers = format!("{}/followers", actor_url(state, &post.username));
+
+    match post.visibility.as_str() {
+        "unlisted" => (json!([followers]), json!([PUBLIC])),
+        _ => (json!([PUBLIC]), json!([followers]))
+    }
+}
+
+/// A post as an ActivityStreams Note
+pub fn note(state: &State, post: &Post) -> Value {
+    let (to, cc) = addressing(state, post);
+
+    let attachments: Vec<Value> = post.images.iter()
+        .map(|image| json!({
+            "type": "Document",
+            "mediaType": "image/jpeg",
+            "url": format!("{}/uploads/{}", state.config.site_url, image.full_path)
+        }))
+        .collect();
+
+    json!({
+        "id": note_url(state, post.post_id),
+        "type": "Note",
+        "attributedTo": actor_url(state, &post.username),
+        "content": render_markdown(&post.content),
+        "url": note_url(state, post.post_id),
+        "published": post.posted_timestamp,
+        "updated": post.edited_timestamp,
+        "to": to,
+        "cc": cc,
+        "attachment": attachments
+    })
+}
+
+/// Wrap a Note in the activity that announces it
+pub fn activity(state: &State, post: &Post, kind: &str) -> Value {
+    let note_id = note_url(state, post.post_id);
+    let (to, cc) = addressing(state, post);
+
+    let (id, object) = match kind {
+        "Create" => (format!("{}#create", note_id), note(state, post)),
+        "Delete" => (
+            format!("{}#delete-{}", note_id, Utc::now().timestamp_millis()),
+            json!({ "id": note_id, "type": "Tombstone" })
+        ),
+        _ => {
+            let mut object = note(state, post);
+            object["updated"] = json!(post.edited_timestamp.clone().unwrap_or_else(|| Utc::now().to_rfc3339()));
+
+            (format!("{}#update-{}", note_id, Utc::now().timestamp_millis()), object)
+        }
+    };
+
+    json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": id,
+        "type": kind,
+        "actor": actor_url(state, &post.username),
+        "to": to,
+        "cc": cc,
+        "object": object
+    })
+}
+
+/// Queue an activity for delivery to a single inbox
+pub async fn queue_delivery(state: &State, user_id: i64, inbox: &str, activity: &Value) -> tide::Result<()> {
+    let mut db_conn = state.db_pool.acquire().await?;
+    let activity = activity.to_string();
+    let now = Utc::now().to_rfc3339();
+
+    sqlx::query(
+            "INSERT INTO activitypub_deliveries (user_id, inbox, activity, next_attempt_timestamp) VALUES ($1, $2, $3, $4)"
+        )
+        .bind(user_id)
+        .bind(inbox)
+        .bind(&activity)
+        .bind(&now)
+        .execute(&mut db_conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Queue an activity about a post for every follower of its author. Followers
+/// on the same server often share an inbox, which only needs it once.
+async fn queue_for_followers(state: &State, post: &Post, kind: &str) -> tide::Result<()> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let inboxes: Vec<(String,)> = sqlx::query_as("SELECT DISTINCT inbox FROM activitypub_followers WHERE user_id=$1")
+        .bind(post.user_id)
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    let activity = activity(state, post, kind);
+
+    for (inbox,) in inboxes {
+        queue_delivery(state, post.user_id, &inbox, &activity).await?;
+    }
+
+    Ok(())
+}
+
+async fn load_post(state: &State, post_id: i64) -> tide::Result<Option<Post>> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    PostRepo::find(&mut db_conn, post_id).await
+}
+
+/// Announce a newly published post to followers
+pub async fn post_published(state: &State, post_id: i64) -> tide::Result<()> {
+    match load_post(state, post_id).await? {
+        Some(post) if is_federated(&post) => queue_for_followers(state, &post, "Create").await,
+        _ => Ok(())
+    }
+}
+
+/// Tell followers a post changed. A post that was made private is deleted
+/// from their servers instead.
+pub async fn post_edited(state: &State, post_id: i64) -> tide::Result<()> {
+    match load_post(state, post_id).await? {
+        Some(post) if is_federated(&post) => queue_for_followers(state, &post, "Update").await,
+        Some(post) if post.published && post.deleted_timestamp.is_none() => {
+            queue_for_followers(state, &post, "Delete").await
+        },
+        _ => Ok(())
+    }
+}
+
+/// Tell followers a post was deleted
+pub async fn post_trashed(state: &State, post_id: i64) -> tide::Result<()> {
+    match load_post(state, post_id).await? {
+        Some(post) if post.published && post.visibility != "private" => {
+            queue_for_followers(state, &post, "Delete").await
+        },
+        _ => Ok(())
+    }
+}
+
+fn sha256_digest(body: &[u8]) -> String {
+    format!("SHA-256={}", base64::encode(Sha256::digest(body)))
+}
+
+/// Headers for a signed POST, following the draft-cavage HTTP Signatures
+/// scheme Mastodon uses. Host, Date and Digest are all covered.
+pub fn signed_headers(key_id: &str, private_key: &str, url: &str, body: &[u8]) -> tide::Result<Vec<(String, String)>> {
+    let url = Url::parse(url)?;
+
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string()
+    };
+
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string()
+    };
+
+    let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    let digest = sha256_digest(body);
+
+    let signing_string = format!(
+        "(request-target): post {}\nhost: {}\ndate: {}\ndigest: {}",
+        target, host, date, digest
+    );
+
+    let key = PKey::private_key_from_pem(private_key.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+
+    signer.update(signing_string.as_bytes())?;
+
+    let signature = format!(
+        r#"keyId="{}",algorithm="rsa-sha256",headers="(request-target) host date digest",signature="{}""#,
+        key_id,
+        base64::encode(signer.sign_to_vec()?)
+    );
+
+    Ok(vec![
+        ("Host".to_string(), host),
+        ("Date".to_string(), date),
+        ("Digest".to_string(), digest),
+        ("Signature".to_string(), signature)
+    ])
+}
+
+/// Fetch a remote ActivityPub document, unless it's on a private address
+pub async fn fetch_document(config: &Config, url: &str) -> Option<Value> {
+    if !outbound::is_allowed(config, url).await {
+        return None;
+    }
+
+    let request = async {
+        let mut response = surf::get(url).header("Accept", CONTENT_TYPE).await.ok()?;
+
+        if !response.status().is_success() {
+            return None;
+        }
+
+        let mut body = Vec::new();
+
+        response.take_body()
+            .into_reader()
+            .take(MAX_DOCUMENT_BYTES)
+            .read_to_end(&mut body)
+            .await
+            .ok()?;
+
+        serde_json::from_slice(&body).ok()
+    };
+
+    async_std::future::timeout(REQUEST_TIMEOUT, request).await.ok().flatten()
+}
+
+/// Parse a Signature header into its parameters
+fn signature_params(header: &str) -> HashMap<String, String> {
+    header.split(',')
+        .filter_map(|param| {
+            let (name, value) = param.split_once('=')?;
+
+            Some((name.trim().to_string(), value.trim().trim_matches('"').to_string()))
+        })
+        .collect()
+}
+
+/// Check an incoming request's HTTP signature, returning the actor that
+/// signed it. The key is fetched from the actor, and the request target, Host,
+/// Date and Digest headers must be signed, and the date fresh, so a captured
+/// request can't be replayed, redirected or have its body swapped.
+pub async fn verify_signature(req: &Request<State>, body: &[u8]) -> Option<String> {
+    let params = signature_params(req.header("Signature")?.as_str());
+    let key_id = params.get("keyId")?;
+    let signature = base64::decode(params.get("signature")?).ok()?;
+
+    let headers: Vec<&str> = params.get("headers").map_or(vec!["date"], |headers| headers.split(' ').collect());
+
+    if !["(request-target)", "host", "date", "digest"].iter().all(|required| headers.contains(required)) {
+        return None;
+    }
+
+    if req.header("Digest")?.as_str() != sha256_digest(body) {
+        return None;
+    }
+
+    let date = DateTime::parse_from_rfc2822(req.header("Date")?.as_str()).ok()?;
+
+    if (Utc::now() - date.with_timezone(&Utc)).num_hours().abs() > MAX_SIGNATURE_AGE_HOURS {
+        return None;
+    }
+
+    let mut lines = Vec::new();
+
+    for header in headers {
+        let value = match header {
+            "(request-target)" => {
+                let url = req.url();
+
+                match url.query() {
+                    Some(query) => format!("{} {}?{}", req.method().to_string().to_lowercase(), url.path(), query),
+                    None => format!("{} {}", req.method().to_string().to_lowercase(), url.path())
+                }
+            },
+            "host" => match req.header("Host") {
+                Some(host) => host.as_str().to_string(),
+                None => req.url().host_str()?.to_string()
+            },
+            name => req.header(name)?.as_str().to_string()
+        };
+
+        lines.push(format!("{}: {}", header, value));
+    }
+
+    let signing_string = lines.join("\n");
+
+    // The key usually lives on the actor document, under a fragment
+    let mut key_url = Url::parse(key_id).ok()?;
+    key_url.set_fragment(None);
+
+    let config = &req.state().config;
+    let document = fetch_document(config, key_url.as_str()).await?;
+
+    let key = match document.get("publicKey") {
+        Some(key) => key.clone(),
+        None => document.clone()
+    };
+
+    if key["id"].as_str() != Some(key_id) {
+        return None;
+    }
+
+    let owner = key["owner"].as_str()?.to_string();
+    let public_key = PKey::public_key_from_pem(key["publicKeyPem"].as_str()?.as_bytes()).ok()?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key).ok()?;
+
+    verifier.update(signing_string.as_bytes()).ok()?;
+
+    if verifier.verify(&signature).ok() != Some(true) {
+        return None;
+    }
+
+    // Anyone can publish a key claiming any owner, so the owner's own actor
+    // document has to vouch for it too
+    let actor = match document["id"].as_str() == Some(owner.as_str()) {
+        true => document,
+        false => fetch_document(config, &owner).await?
+    };
+
+    match actor["publicKey"]["id"].as_str() == Some(key_id.as_str()) {
+        true => Some(owner),
+        false => None
+    }
+}
+
+/// Deliver every queued activity that's due, returning how many were accepted
+pub async fn deliver_due(state: &State) -> tide::Result<usize> {
+    let mut db_conn = state.db_pool.acquire().await?;
+    let now = Utc::now();
+    let now_timestamp = now.to_rfc3339();
+
+    let rows: Vec<(i64, i64, String, String, i64, String)> = sqlx::query_as(
+            r#"SELECT activitypub_deliveries.rowid AS delivery_id, user_id, inbox, activity, attempts, users.username
+            FROM activitypub_deliveries, users
+            WHERE users.rowid=activitypub_deliveries.user_id
+                AND delivered_timestamp IS NULL AND failed_timestamp IS NULL AND next_attempt_timestamp <= $1
+            ORDER BY next_attempt_timestamp LIMIT $2"#
+        )
+        .bind(&now_timestamp)
+        .bind(BATCH_SIZE)
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    let mut delivered = 0;
+
+    for (delivery_id, user_id, inbox, activity, attempts, username) in rows {
+        let (private_key, _) = user_key(state, user_id).await?;
+        let key_id = format!("{}#main-key", actor_url(state, &username));
+
+        let status = match signed_headers(&key_id, &private_key, &inbox, activity.as_bytes()) {
+            // Inboxes come from actor documents anyone can write, so they
+            // get the same check as fetches
+            Ok(_) if !outbound::is_allowed(&state.config, &inbox).await => Some(403),
+            Ok(headers) => {
+                let mut request = surf::post(&inbox)
+                    .body(activity.clone())
+                    .content_type(CONTENT_TYPE);
+
+                for (name, value) in headers {
+                    request = request.header(name.as_str(), value);
+                }
+
+                match async_std::future::timeout(REQUEST_TIMEOUT, request).await {
+                    Ok(Ok(response)) => Some(u16::from(response.status())),
+                    _ => None
+                }
+            },
+            Err(_) => Some(400)
+        };
+
+        match status {
+            Some(status) if (200..300).contains(&status) => {
+                let delivered_timestamp = Utc::now().to_rfc3339();
+
+                sqlx::query("UPDATE activitypub_deliveries SET delivered_timestamp=$1 WHERE rowid=$2")
+                    .bind(&delivered_timestamp)
+                    .bind(delivery_id)
+                    .execute(&mut db_conn)
+                    .await?;
+
+                delivered += 1;
+                continue;
+            },
+            // The server understood and refused, so retrying won't help
+            Some(status) if (400..500).contains(&status) && status != 408 && status != 429 => {
+                tide::log::warn!("Activity rejected", { inbox: inbox, status: status });
+
+                sqlx::query("UPDATE activitypub_deliveries SET failed_timestamp=$1 WHERE rowid=$2")
+                    .bind(&now_timestamp)
+                    .bind(delivery_id)
+                    .execute(&mut db_conn)
+                    .await?;
+
+                continue;
+            },
+            _ => {}
+        }
+
+        let attempts = attempts + 1;
+
+        if attempts >= MAX_DELIVERY_ATTEMPTS {
+            tide::log::warn!("Giving up on activity delivery", { inbox: inbox });
+
+            sqlx::query("UPDATE activitypub_deliveries SET attempts=$1, failed_timestamp=$2 WHERE rowid=$3")
+                .bind(attempts)
+                .bind(&now_timestamp)
+                .bind(delivery_id)
+                .execute(&mut db_conn)
+                .await?;
+        } else {
+            let next_attempt = (now + chrono::Duration::minutes(1 << attempts)).to_rfc3339();
+
+            sqlx::query("UPDATE activitypub_deliveries SET attempts=$1, next_attempt_timestamp=$2 WHERE rowid=$3")
+                .bind(attempts)
+                .bind(&next_attempt)
+                .bind(delivery_id)
+                .execute(&mut db_conn)
+                .await?;
+        }
+    }
+
+    Ok(delivered)
+}
+
+/// Spawn the background task that delivers queued activities
+pub fn spawn_worker(state: State) {
+    let shutdown = state.shutdown.clone();
+
+    shutdown.spawn_job("deliver activities", DELIVERY_CHECK_INTERVAL, move || {
+        let state = state.clone();
+
+        async move { deliver_due(&state).await.map(|_| ()) }
+    });
+}
@@ -0,0 +1,178 @@
+use std::collections::HashSet;
+use std::io::{BufRead, IsTerminal, Read};
+use std::time::{Duration, SystemTime};
+
+use super::State;
+use super::db;
+use super::passwords;
+use super::repo::{MediaRepo, PostRepo, User, UserRepo};
+use super::routes::{self, NewPost, Visibility};
+
+// Administration commands run from the command line, so operators don't have
+// to open the database by hand. main picks the command; these do the work.
+
+/// Uploads newer than this are left alone by gc-uploads, since they may be
+/// mid-way through being resized and not attached to anything yet
+const UPLOAD_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// The newest migration applied to the database
+pub async fn schema_version(state: &State) -> tide::Result<i64> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let (version,): (i64,) = sqlx::query_as("SELECT COALESCE(MAX(version), 0) FROM _sqlx_migrations WHERE success")
+        .fetch_one(&mut db_conn)
+        .await?;
+
+    Ok(version)
+}
+
+/// Read a password, without echoing it if it's typed at a terminal. Piped
+/// passwords are read up to the end of the first line.
+pub fn read_password() -> tide::Result<String> {
+    let password = match std::io::stdin().is_terminal() {
+        true => {
+            let password = rpassword::read_password_from_tty(Some("Password: "))?;
+            let confirmation = rpassword::read_password_from_tty(Some("Confirm password: "))?;
+
+            if password != confirmation {
+                return Err(tide::Error::from_str(400, "The passwords didn't match"));
+            }
+
+            password
+        },
+        false => {
+            let mut password = String::new();
+            std::io::stdin().lock().read_line(&mut password)?;
+
+            password.trim_end_matches(&['\r', '\n'][..]).to_string()
+        }
+    };
+
+    if password.is_empty() {
+        return Err(tide::Error::from_str(400, "The password can't be empty"));
+    }
+
+    Ok(password)
+}
+
+/// Add a co-admin. The site has a single author, so everything acts on the
+/// owner's posts and profile, and a co-admin who logs in can do anything the
+/// owner can.
+pub async fn create_co_admin(state: &State, username: &str, password: &str) -> tide::Result<i64> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    if UserRepo::find_by_username(&mut db_conn, username).await?.is_some() {
+        return Err(tide::Error::from_str(400, format!("There's already a user called {}", username)));
+    }
+
+    UserRepo::create(&mut db_conn, username, username, &passwords::hash_password(password).await).await
+}
+
+pub async fn set_password(state: &State, username: &str, password: &str) -> tide::Result<()> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let user = UserRepo::find_by_username(&mut db_conn, username).await?
+        .ok_or_else(|| tide::Error::from_str(404, format!("There's no user called {}", username)))?;
+
+    UserRepo::set_password_hash(&mut db_conn, user.user_id, &passwords::hash_password(password).await).await
+}
+
+/// Every user, and whether they have a password of their own
+pub async fn list_users(state: &State) -> tide::Result<Vec<(User, bool)>> {
+    let mut db_conn = state.db_pool.acquire().await?;
+    let mut users = Vec::new();
+
+    for user in UserRepo::list(&mut db_conn).await? {
+        let has_password = UserRepo::password_hash(&mut db_conn, &user.username).await?.is_some();
+
+        users.push((user, has_password));
+    }
+
+    Ok(users)
+}
+
+/// Publish a post right away, reading its content from a file, or stdin for
+/// "-". Draft images are left for the next post made on the site.
+pub async fn create_post(state: &State, path: &str) -> tide::Result<i64> {
+    let content = match path {
+        "-" => {
+            let mut content = String::new();
+            std::io::stdin().read_to_string(&mut content)?;
+
+            content
+        },
+        _ => async_std::fs::read_to_string(path).await?
+    };
+
+    let content = content.trim();
+
+    if content.is_empty() {
+        return Err(tide::Error::from_str(400, "Content can't be empty"));
+    }
+
+    routes::insert_post(state, NewPost {
+        content,
+        short_url: None,
+        publish_at: None,
+        visibility: Visibility::Public,
+        images: Vec::new()
+    }).await
+}
+
+/// Remove files in the uploads directory that no post or draft refers to,
+/// returning their names. With dry_run, they're only listed.
+pub async fn gc_uploads(state: &State, dry_run: bool) -> tide::Result<Vec<String>> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let mut images = PostRepo::images(&mut db_conn).await?;
+    images.extend(MediaRepo::drafts(&mut db_conn).await?);
+
+    let referenced: HashSet<String> = images.into_iter()
+        .flat_map(|image| vec![image.full_path, image.medium_path, image.thumbnail_path])
+        .collect();
+
+    let cutoff = SystemTime::now() - UPLOAD_GRACE_PERIOD;
+    let mut removed = Vec::new();
+
+    for entry in std::fs::read_dir(&state.config.uploads_path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let filename = entry.file_name().to_string_lossy().into_owned();
+
+        if !metadata.is_file() || referenced.contains(&filename) || metadata.modified()? > cutoff {
+            continue;
+        }
+
+        if !dry_run {
+            async_std::fs::remove_file(entry.path()).await?;
+        }
+
+        removed.push(filename);
+    }
+
+    removed.sort();
+
+    Ok(removed)
+}
+
+/// Rebuild the database's indexes and refresh the statistics its query
+/// planner uses
+pub async fn reindex(state: &State) -> tide::Result<()> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    if db::is_sqlite(&state.config.database_url) {
+        sqlx::query("REINDEX").execute(&mut db_conn).await?;
+    } else {
+        let (database,): (String,) = sqlx::query_as("SELECT current_database()")
+            .fetch_one(&mut db_conn)
+            .await?;
+
+        sqlx::query(&format!("REINDEX DATABASE \"{}\"", database.replace('"', "\"\"")))
+            .execute(&mut db_conn)
+            .await?;
+    }
+
+    sqlx::query("ANALYZE").execute(&mut db_conn).await?;
+
+    Ok(())
+}
@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use chrono::prelude::*;
+use serde::{Serialize, Deserialize};
+use tide::StatusCode;
+
+use super::State;
+use super::db;
+use super::repo::{PostRepo, UserRepo};
+use super::routes::{Image, Visibility};
+
+// An export is a tar archive holding JSON for the profile and every post
+// (with its revisions), plus the upload files those posts reference:
+//
+//     manifest.json
+//     profile.json
+//     posts.json
+//     uploads/<filename>
+
+/// Identifies our archives, and the layout version they were written with
+const FORMAT: &str = "microbloggy-export";
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    format: String,
+    version: u32,
+    /// The site the archive was exported from. Together with a post's id it
+    /// identifies the post when importing.
+    source: String,
+    exported_timestamp: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportProfile {
+    username: String,
+    name: String,
+    bio: String,
+    links: Vec<String>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportRevision {
+    content: String,
+    short_url: Option<String>,
+    revised_timestamp: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportPost {
+    id: i64,
+    content: String,
+    short_url: Option<String>,
+    posted_timestamp: String,
+    published: bool,
+    edited_timestamp: Option<String>,
+    deleted_timestamp: Option<String>,
+    visibility: String,
+    pinned_timestamp: Option<String>,
+    /// Not in archives from before replies were kept
+    #[serde(default)]
+    in_reply_to: Option<String>,
+    images: Vec<Image>,
+    revisions: Vec<ExportRevision>
+}
+
+/// A post as it's stored, before it's shaped for the archive
+#[derive(sqlx::FromRow)]
+struct PostRow {
+    post_id: i64,
+    content: String,
+    short_url: Option<String>,
+    posted_timestamp: String,
+    published: i64,
+    edited_timestamp: Option<String>,
+    deleted_timestamp: Option<String>,
+    visibility: String,
+    pinned_timestamp: Option<String>,
+    in_reply_to: Option<String>,
+    images: String
+}
+
+/// What an import did
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize
+}
+
+fn invalid_archive(message: &str) -> tide::Error {
+    tide::Error::from_str(StatusCode::BadRequest, format!("Invalid export archive: {}", message))
+}
+
+/// Build an export archive of the whole account
+pub async fn export(state: &State) -> tide::Result<Vec<u8>> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let user = UserRepo::owner(&mut db_conn).await?;
+
+    let links = super::relme::profile_links(&mut db_conn, 1).await?;
+
+    let profile = ExportProfile {
+        username: user.username,
+        name: user.name,
+        bio: user.bio,
+        links: links.into_iter().map(|link| link.url).collect()
+    };
+
+    let revisions: Vec<(i64, String, Option<String>, String)> = sqlx::query_as(
+            "SELECT post_id, content, short_url, revised_timestamp FROM post_revisions ORDER BY revised_timestamp"
+        )
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    let mut revisions_by_post: HashMap<i64, Vec<ExportRevision>> = HashMap::new();
+
+    for (post_id, content, short_url, revised_timestamp) in revisions {
+        revisions_by_post.entry(post_id).or_default().push(ExportRevision {
+            content,
+            short_url,
+            revised_timestamp
+        });
+    }
+
+    let rows: Vec<PostRow> = sqlx::query_as(
+            r#"SELECT rowid AS post_id, content, short_url, posted_timestamp, published, edited_timestamp,
+                deleted_timestamp, visibility, pinned_timestamp, in_reply_to, images
+            FROM posts WHERE user_id=1 ORDER BY posted_timestamp"#
+        )
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    let mut posts = Vec::new();
+
+    for row in rows {
+        let post_id = row.post_id;
+
+        posts.push(ExportPost {
+            id: post_id,
+            content: row.content,
+            short_url: row.short_url,
+            posted_timestamp: row.posted_timestamp,
+            published: row.published != 0,
+            edited_timestamp: row.edited_timestamp,
+            deleted_timestamp: row.deleted_timestamp,
+            visibility: row.visibility,
+            pinned_timestamp: row.pinned_timestamp,
+            in_reply_to: row.in_reply_to,
+            images: serde_json::from_str(&row.images)?,
+            revisions: revisions_by_post.remove(&post_id).unwrap_or_default()
+        });
+    }
+
+    let manifest = Manifest {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        source: state.config.site_url.clone(),
+        exported_timestamp: Utc::now().to_rfc3339()
+    };
+
+    let uploads_path = state.config.uploads_path.clone();
+
+    // Reading the uploads is plain blocking file IO
+    async_std::task::spawn_blocking(move || -> tide::Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mtime = Utc::now().timestamp() as u64;
+
+        let documents = [
+            ("manifest.json", serde_json::to_vec_pretty(&manifest)?),
+            ("profile.json", serde_json::to_vec_pretty(&profile)?),
+            ("posts.json", serde_json::to_vec_pretty(&posts)?)
+        ];
+
+        for (name, data) in documents.iter() {
+            let mut header = tar::Header::new_gnu();
+
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            header.set_cksum();
+
+            builder.append_data(&mut header, name, data.as_slice())?;
+        }
+
+        for image in posts.iter().flat_map(|post| post.images.iter()) {
+            for filename in &[&image.full_path, &image.medium_path, &image.thumbnail_path] {
+                let path = uploads_path.join(filename);
+
+                if let Err(e) = builder.append_path_with_name(&path, format!("uploads/{}", filename)) {
+                    tide::log::warn!("Left a missing upload out of the export", {
+                        filename: filename,
+                        error: e.to_string()
+                    });
+                }
+            }
+        }
+
+        Ok(builder.into_inner()?)
+    }).await
+}
+
+/// The JSON documents and upload files in an archive
+struct ArchiveContents {
+    manifest: Manifest,
+    profile: ExportProfile,
+    posts: Vec<ExportPost>,
+    uploads: HashMap<String, Vec<u8>>
+}
+
+fn read_archive(data: &[u8]) -> tide::Result<ArchiveContents> {
+    let mut archive = tar::Archive::new(data);
+    let mut documents: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut uploads = HashMap::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut contents = Vec::new();
+
+        entry.read_to_end(&mut contents)?;
+
+        match path.strip_prefix("uploads/") {
+            // Only bare file names, so nothing can be written outside the uploads directory
+            Some(filename) if !filename.is_empty() && !filename.contains('/') && !filename.starts_with('.') => {
+                uploads.insert(filename.to_string(), contents);
+            },
+            Some(_) => {},
+            None => {
+                documents.insert(path, contents);
+            }
+        }
+    }
+
+    let mut document = |name: &str| documents.remove(name).ok_or_else(|| invalid_archive(&format!("{} is missing", name)));
+
+    let manifest: Manifest = serde_json::from_slice(&document("manifest.json")?)
+        .map_err(|_| invalid_archive("manifest.json can't be read"))?;
+
+    if manifest.format != FORMAT || manifest.version != VERSION {
+        return Err(invalid_archive("unsupported format or version"));
+    }
+
+    let profile = serde_json::from_slice(&document("profile.json")?)
+        .map_err(|_| invalid_archive("profile.json can't be read"))?;
+
+    let posts: Vec<ExportPost> = serde_json::from_slice(&document("posts.json")?)
+        .map_err(|_| invalid_archive("posts.json can't be read"))?;
+
+    // Visibility is stored as it is, so anything else would be neither
+    // public nor private to the timeline
+    if let Some(post) = posts.iter().find(|post| Visibility::parse(&post.visibility).is_none()) {
+        return Err(invalid_archive(&format!("post {} has an unknown visibility {:?}", post.id, post.visibility)));
+    }
+
+    Ok(ArchiveContents { manifest, profile, posts, uploads })
+}
+
+/// Copy an imported image's files into the uploads directory under fresh
+/// names, so they can't collide with uploads already there
+async fn import_image(state: &State, image: &Image, uploads: &HashMap<String, Vec<u8>>) -> tide::Result<Option<Image>> {
+    let files = match (uploads.get(&image.full_path), uploads.get(&image.medium_path), uploads.get(&image.thumbnail_path)) {
+        (Some(full), Some(medium), Some(thumbnail)) => [full, medium, thumbnail],
+        _ => return Ok(None)
+    };
+
+    let image_id = rand::random::<u64>();
+
+    let imported = Image {
+        full_path: format!("{}_full.jpg", image_id),
+        medium_path: format!("{}_medium.jpg", image_id),
+        thumbnail_path: format!("{}_thumbnail.jpg", image_id)
+    };
+
+    let filenames = [&imported.full_path, &imported.medium_path, &imported.thumbnail_path];
+
+    for (filename, contents) in filenames.iter().zip(files.iter()) {
+        async_std::fs::write(state.config.uploads_path.join(filename), contents).await?;
+    }
+
+    Ok(Some(imported))
+}
+
+/// Record where an imported post came from
+pub async fn record_import(db_conn: &mut sqlx::AnyConnection, source: &str, source_id: &str, post_id: i64) -> tide::Result<()> {
+    let now = Utc::now().to_rfc3339();
+
+    sqlx::query(
+            "INSERT INTO imported_posts (source, source_id, post_id, imported_timestamp) VALUES ($1, $2, $3, $4)"
+        )
+        .bind(source)
+        .bind(source_id)
+        .bind(post_id)
+        .bind(&now)
+        .execute(db_conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Whether a post from this source was imported before
+pub async fn already_imported(db_conn: &mut sqlx::AnyConnection, source: &str, source_id: &str) -> tide::Result<bool> {
+    Ok(imported_post_id(db_conn, source, source_id).await?.is_some())
+}
+
+/// The post a source post with this id was imported as
+pub async fn imported_post_id(db_conn: &mut sqlx::AnyConnection, source: &str, source_id: &str) -> tide::Result<Option<i64>> {
+    let row: Option<(i64,)> = sqlx::query_as("SELECT post_id FROM imported_posts WHERE source=$1 AND source_id=$2")
+        .bind(source)
+        .bind(source_id)
+        .fetch_optional(db_conn)
+        .await?;
+
+    Ok(row.map(|(post_id,)| post_id))
+}
+
+/// Point a reply to another post in the same archive at the copy imported
+/// here. Replies to anything else keep their URL.
+async fn reply_url(db_conn: &mut sqlx::AnyConnection, state: &State, source: &str, in_reply_to: Option<&str>) -> tide::Result<Option<String>> {
+    let in_reply_to = match in_reply_to {
+        Some(in_reply_to) => in_reply_to,
+        None => return Ok(None)
+    };
+
+    let source_id = in_reply_to.strip_prefix(&format!("{}/post/view/", source));
+
+    if let Some(source_id) = source_id {
+        if let Some(post_id) = imported_post_id(db_conn, source, source_id).await? {
+            return Ok(Some(format!("{}/post/view/{}", state.config.site_url, post_id)));
+        }
+    }
+
+    Ok(Some(in_reply_to.to_string()))
+}
+
+/// Import an export archive. Importing the same archive again changes
+/// nothing: posts already imported (or already here, when restoring onto the
+/// site that exported them) are skipped. Posts get new ids and their images
+/// new file names. Imported posts don't notify webmention targets or
+/// followers, since they aren't new.
+pub async fn import(state: &State, data: Vec<u8>) -> tide::Result<ImportSummary> {
+    let contents = async_std::task::spawn_blocking(move || read_archive(&data)).await?;
+    let source = contents.manifest.source.as_str();
+
+    let mut db_conn = state.db_pool.acquire().await?;
+    let mut summary = ImportSummary::default();
+
+    UserRepo::update_profile(&mut db_conn, 1, &contents.profile.name, &contents.profile.bio).await?;
+
+    let links: Vec<String> = contents.profile.links.iter()
+        .filter(|link| super::relme::parse_links(link).is_ok())
+        .take(super::relme::MAX_LINKS)
+        .cloned()
+        .collect();
+
+    super::relme::save_links(state, 1, &links).await?;
+
+    for post in &contents.posts {
+        let source_id = post.id.to_string();
+
+        if already_imported(&mut db_conn, source, &source_id).await? {
+            summary.skipped += 1;
+            continue;
+        }
+
+        let existing: Vec<(i64,)> = sqlx::query_as("SELECT rowid AS post_id FROM posts WHERE posted_timestamp=$1 AND content=$2")
+            .bind(&post.posted_timestamp)
+            .bind(&post.content)
+            .fetch_all(&mut db_conn)
+            .await?;
+
+        if let Some((post_id,)) = existing.first() {
+            record_import(&mut db_conn, source, &source_id, *post_id).await?;
+            summary.skipped += 1;
+            continue;
+        }
+
+        let mut images = Vec::new();
+
+        for image in &post.images {
+            if let Some(image) = import_image(state, image, &contents.uploads).await? {
+                images.push(image);
+            }
+        }
+
+        let images = serde_json::to_string(&images)?;
+        let in_reply_to = reply_url(&mut db_conn, state, source, post.in_reply_to.as_deref()).await?;
+        let mut tx = state.db_pool.begin().await?;
+
+        // Short URLs are unique, so one that's taken here is dropped
+        let short_url = match &post.short_url {
+            Some(short_url) if !PostRepo::short_url_taken(&mut tx, short_url, 0).await? => Some(short_url.clone()),
+            _ => None
+        };
+
+        let post_id = db::insert(&mut tx, sqlx::query(
+                r#"INSERT INTO posts
+                    (user_id, content, posted_timestamp, short_url, images, published, edited_timestamp,
+                    deleted_timestamp, visibility, pinned_timestamp, in_reply_to)
+                VALUES (1, $1, $2, $3, $4, $5, $6, $7, $8, $9, $10)"#
+            )
+            .bind(&post.content)
+            .bind(&post.posted_timestamp)
+            .bind(&short_url)
+            .bind(&images)
+            .bind(post.published as i64)
+            .bind(&post.edited_timestamp)
+            .bind(&post.deleted_timestamp)
+            .bind(&post.visibility)
+            .bind(&post.pinned_timestamp)
+            .bind(&in_reply_to)).await?;
+
+        for revision in &post.revisions {
+            sqlx::query(
+                    "INSERT INTO post_revisions (post_id, content, short_url, revised_timestamp) VALUES ($1, $2, $3, $4)"
+                )
+                .bind(post_id)
+                .bind(&revision.content)
+                .bind(&revision.short_url)
+                .bind(&revision.revised_timestamp)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        record_import(&mut tx, source, &source_id, post_id).await?;
+
+        tx.commit().await?;
+
+        summary.imported += 1;
+    }
+
+    Ok(summary)
+}
@@ -0,0 +1,294 @@
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use libsqlite3_sys as ffi;
+use sha2::{Digest, Sha256};
+use sqlx::Connection;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection};
+
+use super::config::Config;
+use super::shutdown::Shutdown;
+
+// Backups are copied with SQLite's online backup API
+// (https://www.sqlite.org/backup.html), so they're consistent even while the
+// server is writing. Restores go through the same API, after the backup has
+// been checked, and before the pool is opened.
+
+/// How long the backup waits on a database another connection is writing
+const BUSY_TIMEOUT_MS: i32 = 5000;
+
+/// Backup files are named with this prefix and the time they were taken
+const BACKUP_PREFIX: &str = "microbloggy-";
+const BACKUP_EXTENSION: &str = ".sqlite";
+
+/// The file a sqlite: DATABASE_URL points to, or None for in-memory databases
+pub fn database_path(database_url: &str) -> Option<PathBuf> {
+    let path = database_url.trim_start_matches("sqlite:").trim_start_matches("//");
+    let path = path.split('?').next().unwrap_or_default();
+
+    if path.is_empty() || path == ":memory:" {
+        return None;
+    }
+
+    Some(PathBuf::from(path))
+}
+
+fn c_path(path: &Path) -> tide::Result<CString> {
+    Ok(CString::new(path.to_string_lossy().as_bytes())?)
+}
+
+unsafe fn error_message(db: *mut ffi::sqlite3) -> String {
+    CStr::from_ptr(ffi::sqlite3_errmsg(db)).to_string_lossy().into_owned()
+}
+
+unsafe fn open(path: &Path, flags: i32) -> tide::Result<*mut ffi::sqlite3> {
+    let path = c_path(path)?;
+    let mut db = ptr::null_mut();
+
+    if ffi::sqlite3_open_v2(path.as_ptr(), &mut db, flags, ptr::null()) != ffi::SQLITE_OK {
+        let message = error_message(db);
+        ffi::sqlite3_close(db);
+
+        return Err(tide::Error::from_str(500, format!("Couldn't open {}: {}", path.to_string_lossy(), message)));
+    }
+
+    ffi::sqlite3_busy_timeout(db, BUSY_TIMEOUT_MS);
+
+    Ok(db)
+}
+
+/// Copy one database file into another, page by page, while other
+/// connections may still be using the source
+fn copy_database(source: &Path, destination: &Path) -> tide::Result<()> {
+    unsafe {
+        let source_db = open(source, ffi::SQLITE_OPEN_READONLY)?;
+
+        let destination_db = match open(destination, ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE) {
+            Ok(db) => db,
+            Err(e) => {
+                ffi::sqlite3_close(source_db);
+                return Err(e);
+            }
+        };
+
+        let main = CString::new("main")?;
+        let backup = ffi::sqlite3_backup_init(destination_db, main.as_ptr(), source_db, main.as_ptr());
+
+        let result = if backup.is_null() {
+            Err(error_message(destination_db))
+        } else {
+            loop {
+                match ffi::sqlite3_backup_step(backup, -1) {
+                    ffi::SQLITE_DONE => break,
+                    ffi::SQLITE_OK | ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => {
+                        std::thread::sleep(Duration::from_millis(100));
+                    },
+                    _ => break
+                }
+            }
+
+            match ffi::sqlite3_backup_finish(backup) {
+                ffi::SQLITE_OK => Ok(()),
+                _ => Err(error_message(destination_db))
+            }
+        };
+
+        ffi::sqlite3_close(destination_db);
+        ffi::sqlite3_close(source_db);
+
+        result.map_err(|message| tide::Error::from_str(500, format!("Backup failed: {}", message)))
+    }
+}
+
+async fn copy(source: &Path, destination: &Path) -> tide::Result<()> {
+    let source = source.to_path_buf();
+    let destination = destination.to_path_buf();
+
+    async_std::task::spawn_blocking(move || copy_database(&source, &destination)).await
+}
+
+/// Latest migration this build knows about
+fn latest_migration() -> i64 {
+    sqlx::migrate!("./migrations/sqlite").iter().map(|migration| migration.version).max().unwrap_or(0)
+}
+
+/// Check that a backup is an intact database from this or an older version of
+/// the app, returning the migration it was taken at. Checking may change the
+/// file's journal mode, so this is run on copies.
+pub async fn verify(path: &Path) -> tide::Result<i64> {
+    let invalid = |message: String| tide::Error::from_str(500, format!("{} isn't a usable backup: {}", path.display(), message));
+
+    let options = SqliteConnectOptions::new().filename(path);
+    let mut connection = SqliteConnection::connect_with(&options).await
+        .map_err(|e| invalid(e.to_string()))?;
+
+    let (integrity,): (String,) = sqlx::query_as("PRAGMA integrity_check")
+        .fetch_one(&mut connection)
+        .await
+        .map_err(|e| invalid(e.to_string()))?;
+
+    if integrity != "ok" {
+        return Err(invalid(format!("integrity check failed ({})", integrity)));
+    }
+
+    let (version,): (Option<i64>,) = sqlx::query_as("SELECT MAX(version) FROM _sqlx_migrations WHERE success=1")
+        .fetch_one(&mut connection)
+        .await
+        .map_err(|_| invalid("it has no migration history".to_string()))?;
+
+    connection.close().await?;
+
+    match version {
+        Some(version) if version <= latest_migration() => Ok(version),
+        Some(version) => Err(invalid(format!("it's from a newer version of the app (migration {})", version))),
+        None => Err(invalid("it has no migration history".to_string()))
+    }
+}
+
+/// Delete a database file along with any journal files left next to it
+async fn remove_database(path: &Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        async_std::fs::remove_file(format!("{}{}", path.display(), suffix)).await.ok();
+    }
+}
+
+fn backup_files(directory: &Path) -> tide::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_EXTENSION)
+        })
+        .collect();
+
+    // The timestamp in the name sorts oldest first
+    files.sort();
+
+    Ok(files)
+}
+
+/// Take a verified snapshot of the database into a directory, then prune
+/// old snapshots down to the configured retention. Returns the new file.
+pub async fn backup_now(config: &Config, directory: &Path) -> tide::Result<PathBuf> {
+    let database = match database_path(&config.database_url) {
+        Some(database) => database,
+        None => return Err(tide::Error::from_str(500, "Only file databases can be backed up"))
+    };
+
+    async_std::fs::create_dir_all(directory).await?;
+
+    let name = format!("{}{}{}", BACKUP_PREFIX, Utc::now().format("%Y%m%dT%H%M%SZ"), BACKUP_EXTENSION);
+    let destination = directory.join(&name);
+    let partial = directory.join(format!("{}.partial", name));
+
+    copy(&database, &partial).await?;
+
+    if let Err(e) = verify(&partial).await {
+        remove_database(&partial).await;
+        return Err(e);
+    }
+
+    async_std::fs::rename(&partial, &destination).await?;
+
+    let files = backup_files(directory)?;
+
+    if files.len() > config.backup_retention {
+        for old in &files[..files.len() - config.backup_retention] {
+            async_std::fs::remove_file(old).await?;
+        }
+    }
+
+    Ok(destination)
+}
+
+/// Spawn the background task that takes scheduled backups, if enabled
+pub fn spawn_scheduler(config: Config, shutdown: Shutdown) {
+    let directory = match &config.backup_path {
+        Some(directory) if config.backup_interval_hours > 0 => directory.clone(),
+        _ => return
+    };
+
+    let interval = Duration::from_secs(config.backup_interval_hours * 60 * 60);
+
+    shutdown.spawn_job("back up the database", interval, move || {
+        let config = config.clone();
+        let directory = directory.clone();
+
+        async move {
+            let path = backup_now(&config, &directory).await?;
+            tide::log::info!("Backed up the database", { path: path.display().to_string() });
+
+            Ok(())
+        }
+    });
+}
+
+/// Whether a backup with this checksum was already restored into the database
+async fn already_restored(database: &Path, checksum: &str) -> bool {
+    if !database.exists() {
+        return false;
+    }
+
+    let options = SqliteConnectOptions::new().filename(database);
+
+    let mut connection = match SqliteConnection::connect_with(&options).await {
+        Ok(connection) => connection,
+        Err(_) => return false
+    };
+
+    // Databases from before restores were recorded have no table to look in
+    let restored: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM restores WHERE checksum=?")
+        .bind(checksum)
+        .fetch_optional(&mut connection)
+        .await
+        .unwrap_or(None);
+
+    connection.close().await.ok();
+
+    restored.is_some()
+}
+
+/// Replace the database with a backup, unless that backup was restored
+/// before. The backup is verified first, and the database it replaces is
+/// kept next to it. Returns the backup's checksum when it was restored, for
+/// recording once the database is migrated.
+pub async fn restore_once(config: &Config, path: &Path) -> tide::Result<Option<String>> {
+    let database = match database_path(&config.database_url) {
+        Some(database) => database,
+        None => return Err(tide::Error::from_str(500, "Only file databases can be restored"))
+    };
+
+    let checksum = format!("{:x}", Sha256::digest(&async_std::fs::read(path).await?));
+
+    if already_restored(&database, &checksum).await {
+        tide::log::info!("Backup was already restored, skipping", { path: path.display().to_string() });
+        return Ok(None);
+    }
+
+    // Verify a copy, so the backup itself is never touched
+    let staging = PathBuf::from(format!("{}.restoring", database.display()));
+
+    copy(path, &staging).await?;
+
+    if let Err(e) = verify(&staging).await {
+        remove_database(&staging).await;
+        return Err(e);
+    }
+
+    if database.exists() {
+        let kept = PathBuf::from(format!("{}.pre-restore-{}", database.display(), Utc::now().format("%Y%m%dT%H%M%SZ")));
+
+        copy(&database, &kept).await?;
+        tide::log::info!("Kept the replaced database", { path: kept.display().to_string() });
+    }
+
+    copy(&staging, &database).await?;
+    remove_database(&staging).await;
+
+    tide::log::info!("Restored the database from a backup", { path: path.display().to_string() });
+
+    Ok(Some(checksum))
+}
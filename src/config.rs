@@ -1,5 +1,12 @@
-use std::env::var;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::path::PathBuf;
+use chrono_tz::Tz;
+
+// Settings are read from a TOML config file, then the environment, then
+// command line flags, each overriding the one before. Every setting has the
+// same name in all three: `posts_per_page` in the file, POSTS_PER_PAGE in the
+// environment and --posts-per-page on the command line.
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -8,38 +15,424 @@ pub struct Config {
     pub admin_password: String,
     pub session_secret: String,
     pub bind_host: String,
+    pub site_url: String,
     pub uploads_path: PathBuf,
     pub graphicsmagick_path: PathBuf,
     pub posts_per_page: u64,
-    pub restore_path: Option<PathBuf>
+    pub trash_retention_days: u64,
+    pub timezone: Tz,
+    pub restore_path: Option<PathBuf>,
+    pub backup_path: Option<PathBuf>,
+    pub backup_interval_hours: u64,
+    pub backup_retention: usize,
+    pub shutdown_timeout_seconds: u64,
+    pub allow_private_addresses: bool
+}
+
+/// A setting, and its default if it's optional
+struct Setting {
+    key: &'static str,
+    default: Option<&'static str>,
+    secret: bool
+}
+
+const fn required(key: &'static str) -> Setting {
+    Setting { key, default: None, secret: false }
+}
+
+const fn optional(key: &'static str, default: &'static str) -> Setting {
+    Setting { key, default: Some(default), secret: false }
+}
+
+const fn secret(key: &'static str) -> Setting {
+    Setting { key, default: None, secret: true }
+}
+
+/// Settings left empty by default, like backup_path, have "" as their default
+const SETTINGS: &[Setting] = &[
+    required("database_url"),
+    required("admin_username"),
+    secret("admin_password"),
+    secret("session_secret"),
+    optional("bind_host", "127.0.0.1:8080"),
+    optional("site_url", ""),
+    required("uploads_path"),
+    optional("graphicsmagick_path", "gm"),
+    optional("posts_per_page", "50"),
+    optional("trash_retention_days", "30"),
+    optional("timezone", "UTC"),
+    optional("restore_path", ""),
+    optional("backup_path", ""),
+    optional("backup_interval_hours", "0"),
+    optional("backup_retention", "7"),
+    optional("shutdown_timeout_seconds", "30"),
+    optional("allow_private_addresses", "false")
+];
+
+/// The config file read when none is named
+pub const DEFAULT_FILE: &str = "microbloggy.toml";
+
+fn env_name(key: &str) -> String {
+    key.to_uppercase()
+}
+
+fn flag_name(key: &str) -> String {
+    format!("--{}", key.replace('_', "-"))
+}
+
+/// Where a setting's value came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Environment,
+    Flag
+}
+
+/// Every problem found while loading the config, so they can all be fixed at once
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// A loaded config, with where each setting came from and the command line
+/// arguments that weren't config flags
+pub struct Loaded {
+    pub config: Config,
+    pub file: Option<PathBuf>,
+    pub args: Vec<String>,
+    values: BTreeMap<&'static str, (String, Source)>
 }
 
 impl Config {
-    pub fn from_env() -> Config {
-        let session_secret = var("SESSION_SECRET").expect(
-            "Expected environment variable SESSION_SECRET"
-        );
+    /// Load the config from the file, the environment and the given command
+    /// line arguments
+    pub fn load(args: &[String]) -> Result<Loaded, ConfigErrors> {
+        Self::load_from(args, |name| std::env::var(name).ok())
+    }
 
-        if session_secret.len() < 32 {
-            panic!("SESSION_SECRET must be at least 32 bytes long")
+    /// Load the config, looking environment variables up with `env`
+    pub fn load_from(args: &[String], env: impl Fn(&str) -> Option<String>) -> Result<Loaded, ConfigErrors> {
+        let mut errors = Vec::new();
+        let mut values: BTreeMap<&'static str, (String, Source)> = BTreeMap::new();
+
+        let flags = parse_flags(args, &mut errors);
+
+        // The file is named by --config or MICROBLOGGY_CONFIG, and is only
+        // optional if it isn't named at all
+        let named_file = flags.file.clone()
+            .or_else(|| env("MICROBLOGGY_CONFIG").map(PathBuf::from));
+
+        let file = match named_file {
+            Some(path) => Some(path),
+            None if std::path::Path::new(DEFAULT_FILE).exists() => Some(PathBuf::from(DEFAULT_FILE)),
+            None => None
+        };
+
+        if let Some(path) = &file {
+            read_file(path, &mut values, &mut errors);
+        }
+
+        for setting in SETTINGS {
+            if let Some(value) = env(&env_name(setting.key)) {
+                values.insert(setting.key, (value, Source::Environment));
+            }
+        }
+
+        for (key, value) in flags.values {
+            values.insert(key, (value, Source::Flag));
+        }
+
+        for setting in SETTINGS {
+            if let (false, Some(default)) = (values.contains_key(setting.key), setting.default) {
+                values.insert(setting.key, (default.to_string(), Source::Default));
+            }
+        }
+
+        let config = build(&values, &mut errors);
+
+        match (config, errors.is_empty()) {
+            (Some(config), true) => Ok(Loaded {
+                config,
+                file,
+                args: flags.rest,
+                values
+            }),
+            _ => Err(ConfigErrors(errors))
+        }
+    }
+}
+
+impl Source {
+    fn describe(&self, key: &str) -> String {
+        match self {
+            Source::Default => "default".to_string(),
+            Source::File(path) => format!("{} in {}", key, path.display()),
+            Source::Environment => format!("environment variable {}", env_name(key)),
+            Source::Flag => format!("flag {}", flag_name(key))
+        }
+    }
+}
+
+impl Loaded {
+    /// The effective config as TOML, with secrets redacted and each setting's
+    /// source noted, for `config check`
+    pub fn report(&self) -> String {
+        let mut report = match &self.file {
+            Some(path) => format!("# Effective configuration, using {}\n", path.display()),
+            None => "# Effective configuration, no config file\n".to_string()
+        };
+
+        for setting in SETTINGS {
+            let (value, source) = match self.values.get(setting.key) {
+                Some(entry) => entry,
+                None => continue
+            };
+
+            let value = match setting.key {
+                _ if setting.secret => "[redacted]".to_string(),
+                "database_url" => redact_url_password(value),
+                "site_url" => self.config.site_url.clone(),
+                _ => value.clone()
+            };
+
+            let value = match value.parse::<i64>() {
+                Ok(number) => toml::Value::Integer(number),
+                Err(_) => toml::Value::String(value)
+            };
+
+            report.push_str(&format!("{} = {}  # {}\n", setting.key, value, source.describe(setting.key)));
+        }
+
+        report
+    }
+}
+
+struct Flags {
+    file: Option<PathBuf>,
+    values: Vec<(&'static str, String)>,
+    rest: Vec<String>
+}
+
+/// Take config flags (--name value or --name=value) out of the arguments.
+/// Anything else is left for the command to deal with.
+fn parse_flags(args: &[String], errors: &mut Vec<String>) -> Flags {
+    let mut flags = Flags {
+        file: None,
+        values: Vec::new(),
+        rest: Vec::new()
+    };
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None)
+        };
+
+        let setting = SETTINGS.iter().find(|setting| flag_name(setting.key) == name);
+
+        if name != "--config" && setting.is_none() {
+            flags.rest.push(arg.clone());
+            continue;
+        }
+
+        let value = match inline_value.or_else(|| args.next().cloned()) {
+            Some(value) => value,
+            None => {
+                errors.push(format!("{} needs a value", name));
+                continue;
+            }
+        };
+
+        match setting {
+            Some(setting) => flags.values.push((setting.key, value)),
+            None => flags.file = Some(PathBuf::from(value))
+        }
+    }
+
+    flags
+}
+
+fn read_file(path: &std::path::Path, values: &mut BTreeMap<&'static str, (String, Source)>, errors: &mut Vec<String>) {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            errors.push(format!("Couldn't read the config file {}: {}", path.display(), error));
+            return;
+        }
+    };
+
+    let table = match contents.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => return,
+        Err(error) => {
+            errors.push(format!("{} isn't valid TOML: {}", path.display(), error));
+            return;
+        }
+    };
+
+    for (key, value) in table {
+        let setting = match SETTINGS.iter().find(|setting| setting.key == key) {
+            Some(setting) => setting,
+            None => {
+                errors.push(format!("{} in {} isn't a setting", key, path.display()));
+                continue;
+            }
         };
 
-        Config {
-            database_url: var("DATABASE_URL").unwrap(),
-            admin_username: var("ADMIN_USERNAME").unwrap(),
-            admin_password: var("ADMIN_PASSWORD").unwrap(),
-            session_secret: session_secret,
-            bind_host: var("BIND_HOST").unwrap_or("127.0.0.1:8080".to_string()),
-            posts_per_page: match var("POSTS_PER_PAGE") {
-                Ok(value) => value.parse().unwrap(),
-                Err(_) => 50
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Float(value) => value.to_string(),
+            toml::Value::Boolean(value) => value.to_string(),
+            _ => {
+                errors.push(format!("{} in {} should be a string, a number or a boolean", key, path.display()));
+                continue;
+            }
+        };
+
+        values.insert(setting.key, (value, Source::File(path.to_path_buf())));
+    }
+}
+
+/// Check every setting and build the config, adding a message for each problem
+fn build(values: &BTreeMap<&'static str, (String, Source)>, errors: &mut Vec<String>) -> Option<Config> {
+    let text = |key: &'static str, errors: &mut Vec<String>| -> Option<String> {
+        match values.get(key) {
+            Some((value, _)) if !value.is_empty() => Some(value.clone()),
+            Some((_, source)) if *source != Source::Default => {
+                errors.push(format!("{} is empty ({})", key, source.describe(key)));
+                None
             },
-            uploads_path: PathBuf::from(var("UPLOADS_PATH").unwrap()),
-            graphicsmagick_path: PathBuf::from(var("GRAPHICSMAGICK_PATH").unwrap_or("gm".to_string())),
-            restore_path: match var("RESTORE_PATH") {
-                Ok(value) => Some(PathBuf::from(value)),
-                Err(_) => None
+            _ => {
+                errors.push(format!(
+                    "{} is required; set it in the config file, as {} or with {}",
+                    key, env_name(key), flag_name(key)
+                ));
+                None
+            }
+        }
+    };
+
+    // Optional settings that are off when empty
+    let path = |key: &'static str| -> Option<PathBuf> {
+        values.get(key)
+            .filter(|(value, _)| !value.is_empty())
+            .map(|(value, _)| PathBuf::from(value))
+    };
+
+    fn parse<T: std::str::FromStr>(
+        values: &BTreeMap<&'static str, (String, Source)>,
+        key: &'static str,
+        expected: &str,
+        errors: &mut Vec<String>
+    ) -> Option<T> {
+        let (value, source) = values.get(key)?;
+
+        match value.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                errors.push(format!("{} should be {}, not {:?} ({})", key, expected, value, source.describe(key)));
+                None
             }
         }
     }
+
+    let database_url = text("database_url", errors);
+    let admin_username = text("admin_username", errors);
+    let admin_password = text("admin_password", errors);
+    let session_secret = text("session_secret", errors);
+    let bind_host = text("bind_host", errors);
+    let uploads_path = text("uploads_path", errors);
+    let graphicsmagick_path = text("graphicsmagick_path", errors);
+
+    let posts_per_page = parse::<u64>(values, "posts_per_page", "a whole number", errors);
+    let trash_retention_days = parse::<u64>(values, "trash_retention_days", "a whole number of days", errors);
+    let timezone = parse::<Tz>(values, "timezone", "a timezone name like Europe/London", errors);
+    let backup_interval_hours = parse::<u64>(values, "backup_interval_hours", "a whole number of hours", errors);
+    let backup_retention = parse::<usize>(values, "backup_retention", "a whole number of backups", errors);
+    let shutdown_timeout_seconds = parse::<u64>(values, "shutdown_timeout_seconds", "a whole number of seconds", errors);
+    let allow_private_addresses = parse::<bool>(values, "allow_private_addresses", "true or false", errors);
+
+    if let Some(database_url) = &database_url {
+        if !["sqlite:", "postgres:", "postgresql:"].iter().any(|scheme| database_url.starts_with(scheme)) {
+            errors.push("database_url should start with sqlite: or postgres:".to_string());
+        }
+    }
+
+    if let Some(session_secret) = &session_secret {
+        if session_secret.len() < 32 {
+            errors.push("session_secret must be at least 32 bytes long".to_string());
+        }
+    }
+
+    if posts_per_page == Some(0) {
+        errors.push("posts_per_page should be at least 1".to_string());
+    }
+
+    // Pruning keeps this many backups, including the one just taken
+    if backup_retention == Some(0) {
+        let (value, source) = &values["backup_retention"];
+
+        errors.push(format!(
+            "backup_retention should be at least 1 backup, not {:?} ({})",
+            value, source.describe("backup_retention")
+        ));
+    }
+
+    // The site is served from the bind address unless told otherwise
+    let site_url = match values.get("site_url") {
+        Some((value, _)) if !value.is_empty() => value.clone(),
+        _ => format!("http://{}", bind_host.as_deref().unwrap_or_default())
+    };
+
+    let site_url = site_url.trim_end_matches('/').to_string();
+
+    if tide::http::Url::parse(&site_url).is_err() {
+        errors.push(format!("site_url {:?} isn't a valid URL", site_url));
+    }
+
+    Some(Config {
+        database_url: database_url?,
+        admin_username: admin_username?,
+        admin_password: admin_password?,
+        session_secret: session_secret?,
+        bind_host: bind_host?,
+        site_url,
+        uploads_path: PathBuf::from(uploads_path?),
+        graphicsmagick_path: PathBuf::from(graphicsmagick_path?),
+        posts_per_page: posts_per_page?,
+        trash_retention_days: trash_retention_days?,
+        timezone: timezone?,
+        restore_path: path("restore_path"),
+        backup_path: path("backup_path"),
+        backup_interval_hours: backup_interval_hours?,
+        backup_retention: backup_retention?,
+        shutdown_timeout_seconds: shutdown_timeout_seconds?,
+        allow_private_addresses: allow_private_addresses?
+    })
+}
+
+fn redact_url_password(url: &str) -> String {
+    match tide::http::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("redacted"));
+            parsed.to_string()
+        },
+        _ => url.to_string()
+    }
 }
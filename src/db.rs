@@ -0,0 +1,71 @@
+use std::str::FromStr;
+
+use sqlx::any::{AnyArguments, AnyConnectOptions, AnyKind, AnyPool, AnyPoolOptions};
+use sqlx::query::Query;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
+use sqlx::{Any, AnyConnection};
+
+use super::config::Config;
+
+// Storage runs on SQLite or PostgreSQL, picked by DATABASE_URL's scheme
+// (sqlite: or postgres:). Queries are written once, in SQL both understand:
+// numbered $N parameters, rowid (an explicit column on Postgres), 0/1 flags
+// and RFC 3339 text timestamps. Each backend keeps its own migrations.
+
+/// A query with its arguments bound, ready to run on either backend
+pub type AnyQuery<'q> = Query<'q, Any, AnyArguments<'q>>;
+
+/// Whether a DATABASE_URL points at SQLite
+pub fn is_sqlite(database_url: &str) -> bool {
+    database_url.starts_with("sqlite:")
+}
+
+/// Open a pool for DATABASE_URL and bring its schema up-to-date
+pub async fn connect(config: &Config) -> tide::Result<AnyPool> {
+    let options = AnyConnectOptions::from_str(&config.database_url)?;
+
+    let pool = match options.kind() {
+        AnyKind::Sqlite => {
+            let options = SqliteConnectOptions::from_str(&config.database_url)?
+                .journal_mode(SqliteJournalMode::Delete)
+                .create_if_missing(true);
+
+            // Every connection to sqlite::memory: opens its own empty database,
+            // so those get a single connection that's kept for good
+            let pool_options = match config.database_url.contains(":memory:") {
+                true => AnyPoolOptions::new().max_connections(1).idle_timeout(None).max_lifetime(None),
+                false => AnyPoolOptions::new()
+            };
+
+            pool_options
+                .connect_with(AnyConnectOptions::from(options))
+                .await?
+        },
+        _ => AnyPoolOptions::new().connect_with(options).await?
+    };
+
+    if is_sqlite(&config.database_url) {
+        sqlx::migrate!("./migrations/sqlite").run(&pool).await?;
+    } else {
+        sqlx::migrate!("./migrations/postgres").run(&pool).await?;
+    }
+
+    Ok(pool)
+}
+
+/// Run an INSERT and return the new row's rowid. SQLite reports it with the
+/// result; Postgres has it as the session's last sequence value.
+pub async fn insert(db_conn: &mut AnyConnection, query: AnyQuery<'_>) -> tide::Result<i64> {
+    let done = query.execute(&mut *db_conn).await?;
+
+    match done.last_insert_id() {
+        Some(rowid) => Ok(rowid),
+        None => {
+            let (rowid,): (i64,) = sqlx::query_as("SELECT lastval()")
+                .fetch_one(db_conn)
+                .await?;
+
+            Ok(rowid)
+        }
+    }
+}
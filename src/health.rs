@@ -0,0 +1,55 @@
+use std::path::Path;
+use std::time::Duration;
+
+use super::State;
+use super::config::Config;
+use super::images::GmImageConvert;
+
+// Checks that the server can do its job, for /readyz. Each returns a short
+// description of what's wrong, suitable for showing to whoever's probing.
+
+/// How long a check may take before it counts as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn with_timeout(check: impl std::future::Future<Output = Result<(), String>>) -> Result<(), String> {
+    match async_std::future::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {} seconds", CHECK_TIMEOUT.as_secs()))
+    }
+}
+
+pub async fn check_database(state: &State) -> Result<(), String> {
+    with_timeout(async {
+        let mut db_conn = state.db_pool.acquire().await.map_err(|e| e.to_string())?;
+
+        sqlx::query("SELECT 1")
+            .execute(&mut db_conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }).await
+}
+
+/// Write and remove a file in the uploads directory
+pub async fn check_uploads(uploads_path: &Path) -> Result<(), String> {
+    let path = uploads_path.join(format!(".write-check-{}", rand::random::<u64>()));
+
+    with_timeout(async {
+        async_std::fs::write(&path, b"ok")
+            .await
+            .map_err(|e| format!("{} isn't writable: {}", uploads_path.display(), e))?;
+
+        async_std::fs::remove_file(&path)
+            .await
+            .map_err(|e| format!("couldn't remove {}: {}", path.display(), e))
+    }).await
+}
+
+pub async fn check_image_backend(config: &Config) -> Result<(), String> {
+    let image_resizer = GmImageConvert::new(config.graphicsmagick_path.to_string_lossy().into_owned());
+
+    with_timeout(async {
+        image_resizer.check().await.map_err(|e| e.to_string())
+    }).await
+}
@@ -14,6 +14,23 @@ impl GmImageConvert {
         }
     }
 
+    /// Check GraphicsMagick can be run, by asking for its version
+    pub async fn check(&self) -> Result<()> {
+        let gm_path = self.gm_path.clone();
+
+        async_std::task::spawn_blocking(move || {
+            let output = Command::new(&gm_path)
+                .arg("version")
+                .output()
+                .map_err(|e| tide::Error::from_str(500, format!("Couldn't run {}: {}", gm_path, e)))?;
+
+            match output.status.success() {
+                true => Result::Ok(()),
+                false => Result::Err(tide::Error::from_str(500, format!("{} version exited with {}", gm_path, output.status)))
+            }
+        }).await
+    }
+
     pub async fn convert_image(&self, source: &Path, dest: &Path) -> Result<()> {
         let source = PathBuf::from(source);
         let dest = PathBuf::from(dest);
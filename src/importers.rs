@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use chrono::prelude::*;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use tide::StatusCode;
+
+use super::State;
+use super::db;
+use super::archive::{self, ImportSummary};
+use super::routes::{self, Image};
+
+// Importers for history kept on other platforms: the zip archive Twitter
+// lets you download, and Mastodon's account export (outbox.json plus the
+// media_attachments directory, usually as a .tar.gz). Posts keep their
+// original time, replies keep what they replied to, and each source post is
+// recorded in imported_posts so running an import again skips it.
+
+const TWITTER_SOURCE: &str = "twitter";
+const MASTODON_SOURCE: &str = "mastodon";
+
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+fn invalid_archive(kind: &str, message: &str) -> tide::Error {
+    tide::Error::from_str(StatusCode::BadRequest, format!("Invalid {} archive: {}", kind, message))
+}
+
+/// A post read out of another platform's archive
+struct SourcePost {
+    source_id: String,
+    content: String,
+    posted_timestamp: DateTime<Utc>,
+    visibility: &'static str,
+    /// The source id of the post replied to, and its URL on the platform
+    in_reply_to: Option<(String, String)>,
+    images: Vec<Vec<u8>>
+}
+
+/// Add the posts that weren't imported before, oldest first so a reply can
+/// point at its already imported parent
+async fn import_posts(state: &State, source: &str, mut posts: Vec<SourcePost>) -> tide::Result<ImportSummary> {
+    posts.sort_by_key(|post| post.posted_timestamp);
+
+    let mut db_conn = state.db_pool.acquire().await?;
+    let mut summary = ImportSummary::default();
+
+    for post in posts {
+        if archive::already_imported(&mut db_conn, source, &post.source_id).await? {
+            summary.skipped += 1;
+            continue;
+        }
+
+        let in_reply_to = match &post.in_reply_to {
+            Some((parent_id, url)) => match archive::imported_post_id(&mut db_conn, source, parent_id).await? {
+                Some(post_id) => Some(format!("{}/post/view/{}", state.config.site_url, post_id)),
+                None => Some(url.clone())
+            },
+            None => None
+        };
+
+        let mut images: Vec<Image> = Vec::new();
+
+        for data in &post.images {
+            match routes::resize_image(state, data.as_slice()).await {
+                Ok(image) => images.push(image),
+                Err(e) => tide::log::warn!("Left an image that couldn't be resized out of an import", {
+                    source_id: post.source_id,
+                    error: e.to_string()
+                })
+            }
+        }
+
+        let images = serde_json::to_string(&images)?;
+        let posted_timestamp = post.posted_timestamp.to_rfc3339();
+
+        let mut tx = state.db_pool.begin().await?;
+
+        let post_id = db::insert(&mut tx, sqlx::query(
+                r#"INSERT INTO posts (user_id, content, posted_timestamp, images, published, visibility, in_reply_to)
+                VALUES (1, $1, $2, $3, 1, $4, $5)"#
+            )
+            .bind(&post.content)
+            .bind(&posted_timestamp)
+            .bind(&images)
+            .bind(post.visibility)
+            .bind(&in_reply_to)).await?;
+
+        archive::record_import(&mut tx, source, &post.source_id, post_id).await?;
+
+        tx.commit().await?;
+
+        summary.imported += 1;
+    }
+
+    Ok(summary)
+}
+
+/// Undo the HTML escaping both platforms apply to post text
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Escape text for storing as markdown. Posts are rendered as HTML without
+/// sanitising, so an imported `&lt;script&gt;` has to stay escaped rather than
+/// turn into a script.
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Post text from an archive, unescaped and escaped again so that whatever
+/// the archive holds, it reads as text
+fn plain_text(text: &str) -> String {
+    escape_markup(&unescape_html(text))
+}
+
+#[derive(Deserialize)]
+struct TweetItem {
+    tweet: Tweet
+}
+
+#[derive(Deserialize)]
+struct Tweet {
+    id_str: String,
+    full_text: String,
+    created_at: String,
+    in_reply_to_status_id_str: Option<String>,
+    in_reply_to_screen_name: Option<String>,
+    #[serde(default)]
+    entities: TweetEntities,
+    #[serde(default)]
+    extended_entities: TweetEntities
+}
+
+#[derive(Default, Deserialize)]
+struct TweetEntities {
+    #[serde(default)]
+    urls: Vec<TweetUrl>,
+    #[serde(default)]
+    media: Vec<TweetMedia>
+}
+
+#[derive(Deserialize)]
+struct TweetUrl {
+    url: String,
+    expanded_url: String
+}
+
+#[derive(Deserialize)]
+struct TweetMedia {
+    url: String,
+    media_url_https: String,
+    #[serde(rename = "type")]
+    kind: String
+}
+
+/// Pull the tweets and their photos out of a Twitter archive zip. Tweets are
+/// in data/tweets.js (split into tweets-partN.js for big accounts), as JSON
+/// assigned to a JavaScript variable; photos are in data/tweets_media, named
+/// after the tweet.
+fn read_twitter_archive(data: &[u8]) -> tide::Result<Vec<SourcePost>> {
+    let invalid = |message: &str| invalid_archive("Twitter", message);
+
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(data))
+        .map_err(|_| invalid("it isn't a zip file"))?;
+
+    let tweets_file = Regex::new(r"^data/tweets?(-part\d+)?\.js$").unwrap();
+    let media_file = Regex::new(r"^data/tweets?_media/([^/]+)$").unwrap();
+
+    let mut items: Vec<TweetItem> = Vec::new();
+    let mut media: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut found_tweets = false;
+
+    for index in 0..zip.len() {
+        let mut file = zip.by_index(index).map_err(|_| invalid("it can't be read"))?;
+        let name = file.name().to_string();
+
+        if tweets_file.is_match(&name) {
+            let mut text = String::new();
+            file.read_to_string(&mut text)?;
+
+            let json = text.split_once('=').map(|(_, json)| json).unwrap_or(&text);
+            let mut part: Vec<TweetItem> = serde_json::from_str(json)
+                .map_err(|_| invalid(&format!("{} can't be read", name)))?;
+
+            items.append(&mut part);
+            found_tweets = true;
+        } else if let Some(captures) = media_file.captures(&name) {
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents)?;
+
+            media.insert(captures[1].to_string(), contents);
+        }
+    }
+
+    if !found_tweets {
+        return Err(invalid("data/tweets.js is missing"));
+    }
+
+    let mut posts = Vec::new();
+
+    for TweetItem { tweet } in items {
+        // Retweets are someone else's post
+        if tweet.full_text.starts_with("RT @") {
+            continue;
+        }
+
+        let posted_timestamp = DateTime::parse_from_str(&tweet.created_at, "%a %b %d %H:%M:%S %z %Y")
+            .map_err(|_| invalid(&format!("tweet {} has an unreadable date", tweet.id_str)))?
+            .with_timezone(&Utc);
+
+        let mut content = tweet.full_text.clone();
+
+        for url in &tweet.entities.urls {
+            content = content.replace(&url.url, &url.expanded_url);
+        }
+
+        // Photos are attached instead of linked
+        let photos: Vec<&TweetMedia> = tweet.extended_entities.media.iter()
+            .chain(tweet.entities.media.iter())
+            .collect();
+
+        let mut images = Vec::new();
+
+        for photo in &photos {
+            content = content.replace(&photo.url, "");
+
+            let filename = photo.media_url_https.rsplit('/').next().unwrap_or_default();
+
+            if photo.kind == "photo" {
+                if let Some(data) = media.get(&format!("{}-{}", tweet.id_str, filename)) {
+                    if !images.contains(data) {
+                        images.push(data.clone());
+                    }
+                }
+            }
+        }
+
+        let in_reply_to = match (&tweet.in_reply_to_status_id_str, &tweet.in_reply_to_screen_name) {
+            (Some(status_id), Some(screen_name)) => {
+                Some((status_id.clone(), format!("https://twitter.com/{}/status/{}", screen_name, status_id)))
+            },
+            (Some(status_id), None) => {
+                Some((status_id.clone(), format!("https://twitter.com/i/web/status/{}", status_id)))
+            },
+            _ => None
+        };
+
+        posts.push(SourcePost {
+            source_id: tweet.id_str,
+            content: plain_text(content.trim()),
+            posted_timestamp,
+            visibility: "public",
+            in_reply_to,
+            images
+        });
+    }
+
+    Ok(posts)
+}
+
+/// Import the tweets from a Twitter archive zip
+pub async fn import_twitter(state: &State, data: Vec<u8>) -> tide::Result<ImportSummary> {
+    let posts = async_std::task::spawn_blocking(move || read_twitter_archive(&data)).await?;
+
+    import_posts(state, TWITTER_SOURCE, posts).await
+}
+
+/// Turn a status's HTML into the markdown posts are written in
+fn html_to_markdown(html: &str) -> String {
+    let paragraph = Regex::new(r"(?i)</p>\s*<p[^>]*>").unwrap();
+    let line_break = Regex::new(r"(?i)<br\s*/?>").unwrap();
+    let link = Regex::new(r#"(?is)<a\s[^>]*href="([^"]*)"[^>]*>(.*?)</a>"#).unwrap();
+    let tag = Regex::new(r"<[^>]*>").unwrap();
+
+    let text = paragraph.replace_all(html, "\n\n");
+    let text = line_break.replace_all(&text, "\n");
+
+    let plain = |html: &str| plain_text(&tag.replace_all(html, ""));
+    let mut markdown = String::new();
+    let mut end = 0;
+
+    // Mentions and hashtags read fine as text; other links keep their target,
+    // as long as it's a web address that can't break out of the link
+    for captures in link.captures_iter(&text) {
+        let whole = captures.get(0).unwrap();
+        let href = unescape_html(&captures[1]);
+        let label = plain(&captures[2]);
+
+        let linkable = (href.starts_with("https://") || href.starts_with("http://"))
+            && !href.contains(|c: char| c.is_whitespace() || "<>()".contains(c));
+
+        markdown.push_str(&plain(&text[end..whole.start()]));
+
+        if label.starts_with('@') || label.starts_with('#') || !linkable {
+            markdown.push_str(&label);
+        } else if label == escape_markup(&href) {
+            markdown.push_str(&format!("<{}>", href));
+        } else {
+            markdown.push_str(&format!("[{}]({})", label, href));
+        }
+
+        end = whole.end();
+    }
+
+    markdown.push_str(&plain(&text[end..]));
+
+    markdown.trim().to_string()
+}
+
+/// Whether an audience list (to or cc) includes everyone
+fn addresses_public(value: &Value) -> bool {
+    match value {
+        Value::String(address) => address == PUBLIC || address == "as:Public" || address == "Public",
+        Value::Array(addresses) => addresses.iter().any(addresses_public),
+        _ => false
+    }
+}
+
+/// Key media files by their path from media_attachments/ on, which is how
+/// both the archive and attachment URLs name them
+fn media_key(path: &str) -> Option<&str> {
+    path.find("media_attachments/").map(|start| &path[start..])
+}
+
+/// Pull the statuses and their images out of a Mastodon export, either the
+/// .tar.gz Mastodon offers or an unpacked-and-repacked plain tar
+fn read_mastodon_archive(data: &[u8]) -> tide::Result<Vec<SourcePost>> {
+    let invalid = |message: &str| invalid_archive("Mastodon", message);
+
+    let reader: Box<dyn Read + '_> = if data.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::GzDecoder::new(data))
+    } else {
+        Box::new(data)
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    let mut outbox: Option<Value> = None;
+    let mut media: HashMap<String, Vec<u8>> = HashMap::new();
+
+    for entry in archive.entries().map_err(|_| invalid("it isn't a tar archive"))? {
+        let mut entry = entry.map_err(|_| invalid("it can't be read"))?;
+
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path()?.to_string_lossy().into_owned();
+
+        if path.trim_start_matches("./") == "outbox.json" {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+
+            outbox = Some(serde_json::from_slice(&contents).map_err(|_| invalid("outbox.json can't be read"))?);
+        } else if let Some(key) = media_key(&path) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+
+            media.insert(key.to_string(), contents);
+        }
+    }
+
+    let outbox = outbox.ok_or_else(|| invalid("outbox.json is missing"))?;
+    let items = outbox["orderedItems"].as_array().ok_or_else(|| invalid("outbox.json has no statuses"))?;
+
+    let mut posts = Vec::new();
+
+    for item in items {
+        // Boosts are someone else's post
+        if item["type"].as_str() != Some("Create") {
+            continue;
+        }
+
+        let object = &item["object"];
+
+        let source_id = match object["id"].as_str() {
+            Some(source_id) => source_id.to_string(),
+            None => continue
+        };
+
+        let posted_timestamp = object["published"].as_str()
+            .or_else(|| item["published"].as_str())
+            .and_then(|published| DateTime::parse_from_rfc3339(published).ok())
+            .ok_or_else(|| invalid(&format!("{} has an unreadable date", source_id)))?
+            .with_timezone(&Utc);
+
+        let mut content = html_to_markdown(object["content"].as_str().unwrap_or_default());
+
+        // Keep a content warning in front of what it warned about
+        if let Some(summary) = object["summary"].as_str().filter(|summary| !summary.is_empty()) {
+            content = format!("**{}**\n\n{}", plain_text(summary), content);
+        }
+
+        let visibility = if addresses_public(&object["to"]) {
+            "public"
+        } else if addresses_public(&object["cc"]) {
+            "unlisted"
+        } else {
+            "private"
+        };
+
+        let in_reply_to = object["inReplyTo"].as_str()
+            .map(|parent| (parent.to_string(), parent.to_string()));
+
+        let images = object["attachment"].as_array().into_iter().flatten()
+            .filter(|attachment| attachment["mediaType"].as_str().is_some_and(|kind| kind.starts_with("image/")))
+            .filter_map(|attachment| attachment["url"].as_str().and_then(media_key))
+            .filter_map(|key| media.get(key).cloned())
+            .collect();
+
+        posts.push(SourcePost {
+            source_id,
+            content,
+            posted_timestamp,
+            visibility,
+            in_reply_to,
+            images
+        });
+    }
+
+    Ok(posts)
+}
+
+/// Import the statuses from a Mastodon account export
+pub async fn import_mastodon(state: &State, data: Vec<u8>) -> tide::Result<ImportSummary> {
+    let posts = async_std::task::spawn_blocking(move || read_mastodon_archive(&data)).await?;
+
+    import_posts(state, MASTODON_SOURCE, posts).await
+}
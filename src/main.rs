@@ -1,27 +1,73 @@
+use chrono::prelude::*;
 use rand::Rng;
 
 use tide::Request;
 use tera::Tera;
 
-use sqlx::{Sqlite, SqlitePool};
-use sqlx::pool::PoolConnection;
-use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
-use sqlx::Executor;
+use sqlx::AnyPool;
 
 use serde_json::Value;
-use std::str::FromStr;
 
+mod activitypub;
+mod admin;
+mod archive;
+mod backup;
 mod config;
+mod db;
+mod health;
 mod routes;
+mod routes_activitypub;
 mod routes_api;
+mod routes_health;
+mod routes_indieauth;
+mod routes_micropub;
+mod routes_webmention;
+#[cfg(test)]
 mod tests;
+#[cfg(test)]
+mod test_app;
 mod images;
+mod importers;
+mod metrics;
+mod openapi;
+mod outbound;
+mod pagination;
+mod passwords;
+mod relme;
+mod repo;
+mod scheduler;
+mod shutdown;
+mod tokens;
+mod trash;
+mod webmention;
+
+const USAGE: &str = "Usage: microbloggy [--config <file>] [--<setting> <value>...] <command>
+
+Commands:
+  serve                           Run the server (the default)
+  migrate                         Bring the database schema up-to-date
+  user create <username> --co-admin
+                                  Add a co-admin, who can do anything the owner can,
+                                  reading their password from stdin
+  user passwd <username>          Change a user's password, reading it from stdin
+  user list                       List users
+  post create [file]              Publish a post from a file, or stdin
+  export <archive.tar>            Export posts, profile and uploads
+  import [twitter | mastodon] <archive>
+                                  Import an export, or a Twitter or Mastodon archive
+  backup [directory]              Back up the database (SQLite only)
+  gc-uploads [--dry-run]          Remove uploads no post or draft uses
+  reindex                         Rebuild database indexes
+  config check                    Show the effective configuration
+";
 
 #[derive(Clone)]
 pub struct State {
     tera: Tera,
-    sqlite_pool: sqlx::SqlitePool,
-    config: config::Config
+    db_pool: AnyPool,
+    config: config::Config,
+    metrics: std::sync::Arc<metrics::Metrics>,
+    shutdown: shutdown::Shutdown
 }
 
 #[derive(Clone, Default, Debug)]
@@ -42,7 +88,31 @@ fn markdown_filter(value: &Value, _: &std::collections::HashMap<String, Value>)
     Ok(serde_json::value::to_value(output).unwrap())
 }
 
+/// Load the page templates, with the filters they use
+fn load_templates() -> tera::Result<Tera> {
+    let mut tera = Tera::new("templates/**/*.html")?;
+
+    tera.register_filter("markdown", markdown_filter);
+    tera.autoescape_on(vec!["html"]);
+
+    Ok(tera)
+}
+
+/// The web server with all its middleware and routes
+fn build_server(state: State, config: &config::Config) -> tide::Server<State> {
+    let mut app = tide::with_state(state);
+
+    register_middleware(&mut app, config);
+    register_routes(&mut app, config);
+
+    app
+}
+
 fn register_middleware(app: &mut tide::Server<State>, config: &config::Config) {
+    // Outermost, so the time spent in the other middleware is counted too
+    app.with(metrics::MeasureRequests);
+    app.with(shutdown::TrackRequests);
+
     app.with(tide::sessions::SessionMiddleware::new(
         tide::sessions::MemoryStore::new(),
         config.session_secret.as_bytes()
@@ -84,6 +154,9 @@ fn register_middleware(app: &mut tide::Server<State>, config: &config::Config) {
 }
 
 fn register_routes(app: &mut tide::Server<State>, config: &config::Config) {
+    // Routes are labelled with their pattern for the request metrics
+    let mut app = metrics::MeasuredRoutes(app);
+
     // Main Routes
     app.at("/").get(routes::index);
 
@@ -92,15 +165,64 @@ fn register_routes(app: &mut tide::Server<State>, config: &config::Config) {
     app.at("/user/profile").get(routes::user_profile);
     app.at("/user/profile/edit").get(routes::user_profile_edit);
     app.at("/user/profile/edit").post(routes::user_profile_update);
+    app.at("/user/tokens/create").post(routes::user_token_create);
+    app.at("/user/tokens/revoke/:token_id").post(routes::user_token_revoke);
+    app.at("/user/export").get(routes::user_export);
 
     app.at("/post/create").post(routes::post_create);
     app.at("/post/view/:post_id").get(routes::post_view);
     app.at("/post/share/:short_url").get(routes::post_view_share);
     app.at("/post/edit/:post_id").post(routes::post_edit);
     app.at("/post/delete/:post_id").post(routes::post_delete);
+    app.at("/post/history/:post_id").get(routes::post_history);
+    app.at("/post/diff/:post_id").get(routes::post_diff);
+    app.at("/post/restore/:post_id/:revision_id").post(routes::post_restore);
+    app.at("/post/trash").get(routes::post_trash);
+    app.at("/post/untrash/:post_id").post(routes::post_untrash);
+    app.at("/post/purge/:post_id").post(routes::post_purge);
+    app.at("/post/pin/:post_id").post(routes::post_pin);
     app.at("/post/image-upload").put(routes::put_image_upload);
+    app.at("/post/scheduled").get(routes::post_scheduled);
+    app.at("/post/reschedule/:post_id").post(routes::post_reschedule);
+    app.at("/post/publish/:post_id").post(routes::post_publish);
+    app.at("/post/mention/approve/:mention_id").post(routes_webmention::mention_approve);
+    app.at("/post/mention/reject/:mention_id").post(routes_webmention::mention_reject);
+
+    app.at("/archive").get(routes::archive);
+    app.at("/archive/on-this-day").get(routes::archive_on_this_day);
+    app.at("/archive/:year/:month").get(routes::archive_month);
 
     app.at("/api/index").get(routes_api::index_api);
+    app.at("/api/openapi.json").get(routes_api::openapi);
+
+    for (method, path, endpoint) in routes_api::routes() {
+        app.at(path).method(method, endpoint);
+    }
+
+    app.at("/.well-known/oauth-authorization-server").get(routes_indieauth::metadata);
+    app.at("/auth")
+        .get(routes_indieauth::authorize)
+        .post(routes_indieauth::authorize_redeem);
+    app.at("/auth/approve").post(routes_indieauth::authorize_approve);
+    app.at("/token").post(routes_indieauth::token);
+    app.at("/token/revoke").post(routes_indieauth::token_revoke);
+
+    app.at("/micropub")
+        .get(routes_micropub::micropub_query)
+        .post(routes_micropub::micropub);
+    app.at("/micropub/media").post(routes_micropub::micropub_media);
+
+    app.at("/healthz").get(routes_health::healthz);
+    app.at("/readyz").get(routes_health::readyz);
+    app.at("/metrics").get(routes_health::metrics);
+
+    app.at("/webmention").post(routes_webmention::webmention_receive);
+
+    app.at("/.well-known/webfinger").get(routes_activitypub::webfinger);
+    app.at("/users/:username").get(routes_activitypub::actor);
+    app.at("/users/:username/outbox").get(routes_activitypub::outbox);
+    app.at("/users/:username/followers").get(routes_activitypub::followers);
+    app.at("/users/:username/inbox").post(routes_activitypub::inbox);
 
     // Static Files (fonts, favicon, css)
     app.at("/static").serve_dir("static").unwrap();
@@ -109,85 +231,261 @@ fn register_routes(app: &mut tide::Server<State>, config: &config::Config) {
     app.at("/uploads").serve_dir(config.uploads_path.as_path()).unwrap();
 }
 
-async fn bootstrap_database(config: &config::Config) -> tide::Result<SqlitePool> {
-    let options = SqliteConnectOptions::from_str(config.database_url.as_str())?
-        .journal_mode(SqliteJournalMode::Delete)
-        .create_if_missing(true);
-
-    let sqlite_pool = SqlitePool::connect_with(options).await?;
+async fn bootstrap_database(config: &config::Config) -> tide::Result<AnyPool> {
+    // Restoring replaces the database file, so it has to happen before the
+    // pool opens it
+    let restored = match &config.restore_path {
+        Some(path) => backup::restore_once(config, path).await?,
+        None => None
+    };
 
-    // Run migrations, bringing the database schema up-to-date
-    sqlx::migrate!("./migrations")
-        .run(&sqlite_pool)
-        .await?;
+    // Connect and run migrations, bringing the database schema up-to-date
+    let db_pool = db::connect(config).await?;
 
-    let mut connection: PoolConnection<Sqlite> = sqlite_pool.acquire().await?;
+    let mut connection = db_pool.acquire().await?;
 
     // Bootstrap user (only 1 user for now hardcoded as user id 1)
-    let user = sqlx::query!("SELECT username FROM users")
+    let user: Option<(String,)> = sqlx::query_as("SELECT username FROM users")
         .fetch_optional(&mut connection)
         .await?;
 
     match user {
         None => {
-            sqlx::query!(
-                "INSERT INTO users (rowid, username, name, bio) VALUES (?, ?, ?, ?)",
-                1,
-                config.admin_username,
-                "Default User",
-                "Default Bio"
-            )
-            .execute(&mut connection)
-            .await?;
+            sqlx::query("INSERT INTO users (rowid, username, name, bio) VALUES ($1, $2, $3, $4)")
+                .bind(1_i64)
+                .bind(&config.admin_username)
+                .bind("Default User")
+                .bind("Default Bio")
+                .execute(&mut connection)
+                .await?;
         },
         _ => {}
     };
 
-    if let Some(path) = &config.restore_path {
-        let restore_contents = std::fs::read_to_string(path).unwrap();
-
-        match connection.execute(restore_contents.as_str()).await {
-            Ok(_) => println!("Successfully restored data"),
-            Err(e) => eprintln!("Failed to restore data: {}", e)
-        }
+    // Remember the restore, so restarting with RESTORE_PATH still set doesn't
+    // roll the database back again
+    if let (Some(checksum), Some(path)) = (restored, &config.restore_path) {
+        sqlx::query("INSERT INTO restores (checksum, source_path, restored_timestamp) VALUES ($1, $2, $3)")
+            .bind(checksum)
+            .bind(path.display().to_string())
+            .bind(Utc::now().to_rfc3339())
+            .execute(&mut connection)
+            .await?;
     }
 
-    Ok(sqlite_pool)
+    Ok(db_pool)
 }
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
-    // Load application config
-    let config = config::Config::from_env();
+    // Load application config: the config file, then the environment, then flags
+    let loaded = match config::Config::load(&std::env::args().skip(1).collect::<Vec<_>>()) {
+        Ok(loaded) => loaded,
+        Err(errors) => {
+            eprint!("{}", errors);
+            std::process::exit(2);
+        }
+    };
 
-    // TODO: test that uploads path is writable
+    let config = loaded.config.clone();
+    let args = loaded.args.clone();
 
-    // Test performance with disabled logging
-    tide::log::start();
+    if args == ["config", "check"] {
+        print!("{}", loaded.report());
 
-    // Tera template stuff
-    let mut tera = Tera::new("templates/**/*.html")?;
+        return Ok(());
+    }
 
-    tera.register_filter("markdown", markdown_filter);
-    tera.autoescape_on(vec!["html"]);
+    // Test performance with disabled logging. Commands other than serving only
+    // log problems, so their own output isn't buried.
+    match args.as_slice() {
+        [] => tide::log::start(),
+        [command] if command == "serve" => tide::log::start(),
+        _ => tide::log::with_level(tide::log::LevelFilter::Warn)
+    }
+
+    // Tera template stuff
+    let tera = load_templates()?;
 
     // Bootstrap Database
-    let sqlite_pool = bootstrap_database(&config).await?;
+    let db_pool = bootstrap_database(&config).await?;
 
     // State
     let state = State {
         tera: tera,
-        sqlite_pool: sqlite_pool,
-        config: config.clone()
+        db_pool: db_pool,
+        config: config.clone(),
+        metrics: std::sync::Arc::new(metrics::Metrics::new()),
+        shutdown: shutdown::Shutdown::new()
     };
 
+    // One-off commands run instead of the server
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] | ["serve"] => {},
+        ["migrate"] => {
+            println!("The database is up-to-date, at migration {}", admin::schema_version(&state).await?);
+
+            return Ok(());
+        },
+        ["user", "create", _] => {
+            eprintln!("Every user can manage the whole site: the owner's posts, tokens, profile and exports.");
+            eprintln!("Add --co-admin to create one anyway.");
+            std::process::exit(2);
+        },
+        ["user", "create", username, "--co-admin"] => {
+            let password = admin::read_password()?;
+            let user_id = admin::create_co_admin(&state, username, &password).await?;
+
+            println!("Created co-admin {} with id {}", username, user_id);
+
+            return Ok(());
+        },
+        ["user", "passwd", username] => {
+            let password = admin::read_password()?;
+            admin::set_password(&state, username, &password).await?;
+
+            println!("Changed the password for {}", username);
+
+            return Ok(());
+        },
+        ["user", "list"] => {
+            for (user, has_password) in admin::list_users(&state).await? {
+                let role = match user.user_id {
+                    1 => "owner",
+                    _ => "co-admin"
+                };
+
+                let password = match has_password {
+                    true => "own password",
+                    false => "admin password from config"
+                };
+
+                println!("{}\t{}\t{}\t{}\t{}", user.user_id, user.username, user.name, role, password);
+            }
+
+            return Ok(());
+        },
+        ["post", "create"] | ["post", "create", _] => {
+            let path = args.get(2).map(String::as_str).unwrap_or("-");
+            let post_id = admin::create_post(&state, path).await?;
+
+            println!("Published {}/post/view/{}", config.site_url, post_id);
+
+            return Ok(());
+        },
+        ["gc-uploads"] | ["gc-uploads", "--dry-run"] => {
+            let dry_run = args.len() == 2;
+            let removed = admin::gc_uploads(&state, dry_run).await?;
+
+            for filename in &removed {
+                println!("{}", filename);
+            }
+
+            match dry_run {
+                true => println!("Would remove {} unused uploads", removed.len()),
+                false => println!("Removed {} unused uploads", removed.len())
+            }
+
+            return Ok(());
+        },
+        ["reindex"] => {
+            admin::reindex(&state).await?;
+            println!("Rebuilt the database indexes");
+
+            return Ok(());
+        },
+        ["export", path] => {
+            let data = archive::export(&state).await?;
+
+            async_std::fs::write(path, data).await?;
+            println!("Exported to {}", path);
+
+            return Ok(());
+        },
+        ["import", path] => {
+            let data = async_std::fs::read(path).await?;
+            let summary = archive::import(&state, data).await?;
+
+            println!("Imported {} posts, skipped {} already here", summary.imported, summary.skipped);
+
+            return Ok(());
+        },
+        ["import", platform @ ("twitter" | "mastodon"), path] => {
+            let data = async_std::fs::read(path).await?;
+
+            let summary = match *platform {
+                "twitter" => importers::import_twitter(&state, data).await?,
+                _ => importers::import_mastodon(&state, data).await?
+            };
+
+            println!("Imported {} posts, skipped {} already here", summary.imported, summary.skipped);
+
+            return Ok(());
+        },
+        ["backup"] | ["backup", _] => {
+            let directory = match (args.get(1), &config.backup_path) {
+                (Some(directory), _) => std::path::PathBuf::from(directory),
+                (None, Some(directory)) => directory.clone(),
+                (None, None) => {
+                    eprintln!("Give a directory to back up to, or set BACKUP_PATH");
+                    std::process::exit(2);
+                }
+            };
+
+            let path = backup::backup_now(&config, &directory).await?;
+
+            println!("Backed up to {}", path.display());
+
+            return Ok(());
+        },
+        _ => {
+            eprint!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+
+    // Uploads can't work without a writable uploads directory, so find out now
+    // rather than on the first upload
+    if let Err(error) = health::check_uploads(&config.uploads_path).await {
+        eprintln!("Can't serve: {}", error);
+        std::process::exit(1);
+    }
+
+    // Publish scheduled posts in the background
+    scheduler::spawn_publisher(state.clone());
+
+    // Empty expired posts from the trash in the background
+    trash::spawn_purger(state.clone());
+
+    // Send and verify webmentions in the background
+    webmention::spawn_worker(state.clone());
+
+    // Deliver activities to fediverse followers in the background
+    activitypub::spawn_worker(state.clone());
+
+    // Verify rel="me" profile links in the background
+    relme::spawn_verifier(state.clone());
+
+    // Take scheduled backups in the background, if BACKUP_INTERVAL_HOURS is set
+    backup::spawn_scheduler(config.clone(), state.shutdown.clone());
+
     // Create Tide app and Middleware
-    let mut app = tide::with_state(state);
+    let app = build_server(state.clone(), &config);
+
+    shutdown::serve_until_signalled(app, config.bind_host.clone()).await?;
+
+    // Let requests and jobs that already started finish, but not forever
+    let timeout = std::time::Duration::from_secs(config.shutdown_timeout_seconds);
+    tide::log::info!("Shutting down", { timeout_seconds: timeout.as_secs() });
 
-    register_middleware(&mut app, &config);
-    register_routes(&mut app, &config);
+    let unfinished = state.shutdown.drain(timeout).await;
+
+    if unfinished > 0 {
+        tide::log::warn!("Gave up waiting for requests and jobs to finish", { unfinished: unfinished });
+    }
 
-    app.listen(config.bind_host).await?;
+    state.db_pool.close().await;
+    tide::log::info!("Shut down");
 
     Ok(())
 }
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use sqlx::AnyPool;
+use tide::{Middleware, Next, Request};
+
+use super::State;
+
+// Metrics are kept in memory and rendered in Prometheus' text format
+// (https://prometheus.io/docs/instrumenting/exposition_formats/) for /metrics.
+// Requests are labelled with the route pattern they matched rather than their
+// path, so /post/view/1 and /post/view/2 count together.
+
+const REQUEST_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Resizing runs GraphicsMagick three times, so it's slower than a request
+const IMAGE_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Requests that didn't match a route, like most 404s
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0
+        }
+    }
+
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if seconds <= *bucket {
+                *count += 1;
+            }
+        }
+
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, output: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter()) {
+            writeln!(output, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bucket, count).unwrap();
+        }
+
+        writeln!(output, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count).unwrap();
+        writeln!(output, "{}_sum{{{}}} {}", name, labels, self.sum).unwrap();
+        writeln!(output, "{}_count{{{}}} {}", name, labels, self.count).unwrap();
+    }
+}
+
+fn header(output: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(output, "# HELP {} {}", name, help).unwrap();
+    writeln!(output, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub struct Metrics {
+    /// Keyed by route, method and status
+    requests: Mutex<BTreeMap<(String, String, u16), Histogram>>,
+    image_processing: Mutex<Histogram>,
+    /// Keyed by whether the upload was processed
+    uploads: Mutex<BTreeMap<&'static str, u64>>
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            image_processing: Mutex::new(Histogram::new(IMAGE_BUCKETS)),
+            uploads: Mutex::new(BTreeMap::new())
+        }
+    }
+
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, duration: Duration) {
+        self.requests.lock().unwrap()
+            .entry((route.to_string(), method.to_string(), status))
+            .or_insert_with(|| Histogram::new(REQUEST_BUCKETS))
+            .observe(duration);
+    }
+
+    /// Record an uploaded image being resized, or failing to be
+    pub fn observe_upload(&self, duration: Duration, processed: bool) {
+        self.image_processing.lock().unwrap().observe(duration);
+
+        let result = if processed { "ok" } else { "error" };
+        *self.uploads.lock().unwrap().entry(result).or_insert(0) += 1;
+    }
+
+    /// Every metric, in Prometheus' text format
+    pub fn render(&self, db_pool: &AnyPool) -> String {
+        let mut output = String::new();
+
+        let name = "microbloggy_http_request_duration_seconds";
+        header(&mut output, name, "histogram", "Time taken to respond to requests, by route pattern");
+
+        for ((route, method, status), histogram) in self.requests.lock().unwrap().iter() {
+            let labels = format!("route=\"{}\",method=\"{}\",status=\"{}\"", escape(route), escape(method), status);
+            histogram.render(&mut output, name, &labels);
+        }
+
+        let name = "microbloggy_image_processing_duration_seconds";
+        header(&mut output, name, "histogram", "Time taken to resize uploaded images");
+        self.image_processing.lock().unwrap().render(&mut output, name, "");
+
+        let name = "microbloggy_image_uploads_total";
+        header(&mut output, name, "counter", "Uploaded images, by whether they could be processed");
+
+        for (result, count) in self.uploads.lock().unwrap().iter() {
+            writeln!(output, "{}{{result=\"{}\"}} {}", name, result, count).unwrap();
+        }
+
+        let name = "microbloggy_db_pool_connections";
+        header(&mut output, name, "gauge", "Open database connections, by whether they're in use");
+
+        let idle = db_pool.num_idle() as u32;
+        writeln!(output, "{}{{state=\"idle\"}} {}", name, idle).unwrap();
+        writeln!(output, "{}{{state=\"in_use\"}} {}", name, db_pool.size().saturating_sub(idle)).unwrap();
+
+        output
+    }
+}
+
+/// Times every request. Routes registered through `MeasuredRoutes` say which
+/// pattern they matched; anything else is counted as unmatched.
+pub struct MeasureRequests;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for MeasureRequests {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let metrics = req.state().metrics.clone();
+
+        let response = next.run(req).await;
+
+        let route = response.ext::<RoutePattern>()
+            .map(|pattern| pattern.0.as_str())
+            .unwrap_or(UNMATCHED_ROUTE);
+
+        metrics.observe_request(route, &method, response.status().into(), started.elapsed());
+
+        Ok(response)
+    }
+}
+
+/// The route pattern a response came from
+#[derive(Clone)]
+struct RoutePattern(String);
+
+#[tide::utils::async_trait]
+impl Middleware<State> for RoutePattern {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut response = next.run(req).await;
+        response.insert_ext(self.clone());
+
+        Ok(response)
+    }
+}
+
+/// Registers routes like `Server::at`, tagging each with its pattern for the
+/// request metrics
+pub struct MeasuredRoutes<'a>(pub &'a mut tide::Server<State>);
+
+impl MeasuredRoutes<'_> {
+    pub fn at(&mut self, path: &str) -> tide::Route<'_, State> {
+        let mut route = self.0.at(path);
+        route.with(RoutePattern(path.to_string()));
+
+        route
+    }
+}
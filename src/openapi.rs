@@ -0,0 +1,294 @@
+use super::routes_api::*;
+use super::tokens;
+
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+/// One operation in the API. Request and response bodies are schemas
+/// generated from the types the handlers actually use, so the document
+/// follows along when they change.
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    /// Scope an API token needs, if the operation needs authentication. A
+    /// personal access token can have the broader scope covering it instead.
+    scope: Option<&'static str>,
+    parameters: Vec<Value>,
+    /// Content of the request body, by media type
+    request_body: Option<Value>,
+    responses: Vec<(u16, &'static str, Option<Value>)>
+}
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(gen.subschema_for::<T>()).unwrap()
+}
+
+fn json_body(schema: Value) -> Value {
+    json!({
+        "application/json": {
+            "schema": schema
+        }
+    })
+}
+
+/// Query string parameters, taken from the fields of the handler's query type
+fn query_parameters<T: JsonSchema>(gen: &mut SchemaGenerator) -> Vec<Value> {
+    let root = serde_json::to_value(gen.root_schema_for::<T>()).unwrap();
+    let required = root["required"].as_array().cloned().unwrap_or_default();
+
+    root["properties"].as_object()
+        .map(|properties| properties.iter().map(|(name, schema)| {
+            json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(&json!(name)),
+                "schema": schema
+            })
+        }).collect())
+        .unwrap_or_default()
+}
+
+fn post_id_parameter() -> Value {
+    json!({
+        "name": "post_id",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "integer", "format": "int64" }
+    })
+}
+
+fn operations(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    let error = schema::<ErrorResponse>(gen);
+    let post = schema::<PostResponse>(gen);
+    let profile = schema::<ProfileResponse>(gen);
+
+    vec![
+        Operation {
+            method: "get",
+            path: "/api/index",
+            summary: "Draft images for the post form",
+            scope: None,
+            parameters: vec![],
+            request_body: None,
+            responses: vec![
+                (200, "Draft images", Some(schema::<IndexResponse>(gen))),
+                (401, "Not logged in", Some(error.clone()))
+            ]
+        },
+        Operation {
+            method: "get",
+            path: "/api/v1/timeline",
+            summary: "A page of the timeline",
+            scope: None,
+            parameters: query_parameters::<TimelineQuery>(gen),
+            request_body: None,
+            responses: vec![
+                (200, "A page of posts", Some(schema::<TimelineResponse>(gen))),
+                (400, "Invalid page cursor", Some(error.clone())),
+                (401, "An unknown token", Some(error.clone()))
+            ]
+        },
+        Operation {
+            method: "post",
+            path: "/api/v1/posts",
+            summary: "Create a post with the current draft images",
+            scope: Some("create"),
+            parameters: vec![],
+            request_body: Some(json_body(schema::<PostCreateRequest>(gen))),
+            responses: vec![
+                (201, "The new post", Some(post.clone())),
+                (400, "Invalid request body", Some(error.clone())),
+                (401, "Not logged in, or an unknown token", Some(error.clone())),
+                (403, "Missing CSRF token or scope", Some(error.clone()))
+            ]
+        },
+        Operation {
+            method: "get",
+            path: "/api/v1/posts/{post_id}",
+            summary: "A single post",
+            scope: None,
+            parameters: vec![post_id_parameter()],
+            request_body: None,
+            responses: vec![
+                (200, "The post", Some(post.clone())),
+                (401, "An unknown token", Some(error.clone())),
+                (404, "No such post", Some(error.clone()))
+            ]
+        },
+        Operation {
+            method: "patch",
+            path: "/api/v1/posts/{post_id}",
+            summary: "Edit a post, keeping the previous version as a revision",
+            scope: Some("update"),
+            parameters: vec![post_id_parameter()],
+            request_body: Some(json_body(schema::<PostUpdateRequest>(gen))),
+            responses: vec![
+                (200, "The edited post", Some(post)),
+                (400, "Invalid request body", Some(error.clone())),
+                (401, "Not logged in, or an unknown token", Some(error.clone())),
+                (403, "Missing CSRF token or scope", Some(error.clone())),
+                (404, "No such post", Some(error.clone())),
+                (409, "The short URL is taken", Some(error.clone()))
+            ]
+        },
+        Operation {
+            method: "delete",
+            path: "/api/v1/posts/{post_id}",
+            summary: "Move a post to the trash",
+            scope: Some("delete"),
+            parameters: vec![post_id_parameter()],
+            request_body: None,
+            responses: vec![
+                (204, "The post was moved to the trash", None),
+                (401, "Not logged in, or an unknown token", Some(error.clone())),
+                (403, "Missing CSRF token or scope", Some(error.clone())),
+                (404, "No such post, or it's already in the trash", Some(error.clone()))
+            ]
+        },
+        Operation {
+            method: "get",
+            path: "/api/v1/profile",
+            summary: "The user's profile",
+            scope: None,
+            parameters: vec![],
+            request_body: None,
+            responses: vec![
+                (200, "The profile", Some(profile.clone()))
+            ]
+        },
+        Operation {
+            method: "patch",
+            path: "/api/v1/profile",
+            summary: "Update the user's profile",
+            scope: Some("profile:write"),
+            parameters: vec![],
+            request_body: Some(json_body(schema::<ProfileUpdateRequest>(gen))),
+            responses: vec![
+                (200, "The updated profile", Some(profile)),
+                (400, "Invalid request body", Some(error.clone())),
+                (401, "Not logged in, or an unknown token", Some(error.clone())),
+                (403, "Missing CSRF token or scope", Some(error.clone()))
+            ]
+        },
+        Operation {
+            method: "post",
+            path: "/api/v1/uploads",
+            summary: "Upload an image as a draft for the next post",
+            scope: Some("media"),
+            parameters: vec![],
+            // Uploads are the raw image rather than JSON
+            request_body: Some(json!({
+                "image/*": {
+                    "schema": { "type": "string", "format": "binary" }
+                }
+            })),
+            responses: vec![
+                (201, "The resized image", Some(schema::<ImageResponse>(gen))),
+                (401, "Not logged in, or an unknown token", Some(error.clone())),
+                (403, "Missing CSRF token or scope", Some(error.clone()))
+            ]
+        },
+        Operation {
+            method: "get",
+            path: "/api/v1/uploads/drafts",
+            summary: "Images waiting to be attached to the next post",
+            scope: Some("media"),
+            parameters: vec![],
+            request_body: None,
+            responses: vec![
+                (200, "Draft images", Some(schema::<DraftsResponse>(gen))),
+                (401, "Not logged in, or an unknown token", Some(error.clone())),
+                (403, "Missing scope", Some(error))
+            ]
+        }
+    ]
+}
+
+/// The OpenAPI 3 document for the JSON API
+pub fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let mut paths = Map::new();
+
+    for operation in operations(&mut gen) {
+        let mut responses = Map::new();
+
+        for (status, description, body) in operation.responses {
+            let mut response = json!({ "description": description });
+
+            if let Some(body) = body {
+                response["content"] = json_body(body);
+            }
+
+            responses.insert(status.to_string(), response);
+        }
+
+        let mut value = json!({
+            "summary": operation.summary,
+            "responses": responses
+        });
+
+        if !operation.parameters.is_empty() {
+            value["parameters"] = json!(operation.parameters);
+        }
+
+        if let Some(content) = operation.request_body {
+            value["requestBody"] = json!({
+                "required": true,
+                "content": content
+            });
+        }
+
+        if let Some(scope) = operation.scope {
+            let mut security = vec![json!({ "bearerToken": [tokens::personal_scope(scope)] })];
+
+            if tokens::personal_scope(scope) != scope {
+                security.push(json!({ "bearerToken": [scope] }));
+            }
+
+            security.push(json!({ "session": [], "csrfToken": [] }));
+            value["security"] = json!(security);
+        }
+
+        paths.entry(operation.path)
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .unwrap()
+            .insert(operation.method.to_string(), value);
+    }
+
+    let schemas: Map<String, Value> = gen.take_definitions()
+        .into_iter()
+        .map(|(name, schema)| (name, serde_json::to_value(schema).unwrap()))
+        .collect();
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "microbloggy",
+            "version": "1"
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearerToken": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A personal access token from the profile page, or an IndieAuth access token"
+                },
+                "session": {
+                    "type": "apiKey",
+                    "in": "cookie",
+                    "name": "tide.sid"
+                },
+                "csrfToken": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "X-CSRF-Token"
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,88 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use async_std::net::ToSocketAddrs;
+use tide::http::Url;
+use tide::http::url::Host;
+
+use super::config::Config;
+
+// Requests to other sites go to URLs anyone can hand us: webmention sources
+// and endpoints, signature keys, actors and inboxes from the ActivityPub inbox,
+// and profile links. So they only go to public addresses, or a crafted URL
+// could reach services on the server's own network, like a cloud provider's
+// metadata endpoint. ALLOW_PRIVATE_ADDRESSES lifts this for sites on a LAN.
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments, 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking, 198.18.0.0/15
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved, 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return is_public_v4(ip);
+    }
+
+    let first = ip.segments()[0];
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10
+        || (first & 0xffc0) == 0xfe80
+        // Documentation, 2001:db8::/32
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip)
+    }
+}
+
+/// Whether a request may be made to a URL: it has to be http(s), and every
+/// address its host resolves to has to be public. The HTTP client looks the
+/// host up again when it connects, so this can't stop a host that changes
+/// its answer in between, but it does stop URLs that simply point inwards.
+pub async fn is_allowed(config: &Config, url: &str) -> bool {
+    let url = match Url::parse(url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        _ => return false
+    };
+
+    if config.allow_private_addresses {
+        return true;
+    }
+
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addresses: Vec<IpAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![IpAddr::V4(ip)],
+        Some(Host::Ipv6(ip)) => vec![IpAddr::V6(ip)],
+        Some(Host::Domain(domain)) => match (domain, port).to_socket_addrs().await {
+            Ok(addresses) => addresses.map(|address| address.ip()).collect(),
+            Err(_) => return false
+        },
+        None => return false
+    };
+
+    !addresses.is_empty() && addresses.into_iter().all(is_public)
+}
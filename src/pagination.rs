@@ -0,0 +1,63 @@
+/// A position in the timeline. Posts are ordered by timestamp and then by id,
+/// so posts that share a timestamp still have a stable order between pages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub posted_timestamp: String,
+    pub post_id: i64
+}
+
+impl Cursor {
+    pub fn new(posted_timestamp: &str, post_id: i64) -> Cursor {
+        Cursor {
+            posted_timestamp: posted_timestamp.to_string(),
+            post_id
+        }
+    }
+
+    /// Encode as an opaque, URL-safe string
+    pub fn encode(&self) -> String {
+        base64::encode_config(
+            format!("{}|{}", self.post_id, self.posted_timestamp),
+            base64::URL_SAFE_NO_PAD
+        )
+    }
+
+    pub fn decode(value: &str) -> Option<Cursor> {
+        let bytes = base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok()?;
+        let decoded = String::from_utf8(bytes).ok()?;
+        let mut parts = decoded.splitn(2, '|');
+
+        let post_id = parts.next()?.parse().ok()?;
+        let posted_timestamp = parts.next()?.to_string();
+
+        Some(Cursor {
+            posted_timestamp,
+            post_id
+        })
+    }
+}
+
+/// Which page of the timeline to show
+#[derive(Debug, Clone, PartialEq)]
+pub enum Page {
+    /// The newest posts
+    First,
+
+    /// Posts older than the cursor
+    Before(Cursor),
+
+    /// Posts newer than the cursor
+    After(Cursor)
+}
+
+impl Page {
+    /// Build from the `before` and `after` query parameters. Returns None if a
+    /// cursor can't be decoded.
+    pub fn from_query(before: Option<&str>, after: Option<&str>) -> Option<Page> {
+        match (before, after) {
+            (Some(cursor), _) => Cursor::decode(cursor).map(Page::Before),
+            (None, Some(cursor)) => Cursor::decode(cursor).map(Page::After),
+            (None, None) => Some(Page::First)
+        }
+    }
+}
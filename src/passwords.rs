@@ -0,0 +1,50 @@
+use openssl::hash::MessageDigest;
+
+// Unlike API tokens, passwords are short and guessable, so they're stored as
+// salted PBKDF2-HMAC-SHA256 hashes, written as
+// "pbkdf2-sha256$<iterations>$<salt>$<hash>" with base64 salt and hash.
+
+const SCHEME: &str = "pbkdf2-sha256";
+
+/// OWASP's recommendation for PBKDF2-HMAC-SHA256
+const ITERATIONS: usize = 600_000;
+
+const HASH_LENGTH: usize = 32;
+
+fn derive(password: &str, salt: &[u8], iterations: usize) -> Vec<u8> {
+    let mut hash = vec![0; HASH_LENGTH];
+
+    openssl::pkcs5::pbkdf2_hmac(password.as_bytes(), salt, iterations, MessageDigest::sha256(), &mut hash)
+        .expect("PBKDF2 with SHA-256 is always available");
+
+    hash
+}
+
+/// Hashing takes a good fraction of a second, so it runs on a blocking thread
+/// rather than holding up other requests
+pub async fn hash_password(password: &str) -> String {
+    let password = password.to_string();
+    let salt: [u8; 16] = rand::random();
+    let hash = async_std::task::spawn_blocking(move || derive(&password, &salt, ITERATIONS)).await;
+
+    format!("{}${}${}${}", SCHEME, ITERATIONS, base64::encode(salt), base64::encode(hash))
+}
+
+/// Check a password against a stored hash, on a blocking thread like hashing.
+/// Malformed hashes never match.
+pub async fn verify_password(password: &str, stored: &str) -> bool {
+    let parts: Vec<&str> = stored.split('$').collect();
+
+    let (iterations, salt, hash) = match parts.as_slice() {
+        [SCHEME, iterations, salt, hash] => match (iterations.parse(), base64::decode(salt), base64::decode(hash)) {
+            (Ok(iterations), Ok(salt), Ok(hash)) if iterations > 0 => (iterations, salt, hash),
+            _ => return false
+        },
+        _ => return false
+    };
+
+    let password = password.to_string();
+    let derived = async_std::task::spawn_blocking(move || derive(&password, &salt, iterations)).await;
+
+    derived.len() == hash.len() && openssl::memcmp::eq(&derived, &hash)
+}
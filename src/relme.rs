@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use chrono::prelude::*;
+use serde::Serialize;
+use tide::http::Url;
+
+use super::State;
+use super::webmention;
+
+/// How often the background task looks for profile links to check
+const VERIFY_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Verified links are checked again after this long, in case the other
+/// profile dropped its link back
+const RECHECK_HOURS: i64 = 24;
+
+/// Most links a profile can list
+pub const MAX_LINKS: usize = 10;
+
+/// A link to one of the user's other profiles
+#[derive(Serialize)]
+pub struct ProfileLink {
+    pub url: String,
+    pub verified: bool
+}
+
+/// A user's profile links, in the order they were entered
+pub async fn profile_links(db_conn: &mut sqlx::AnyConnection, user_id: i64) -> tide::Result<Vec<ProfileLink>> {
+    let result: Vec<(String, Option<String>)> = sqlx::query_as(
+            "SELECT url, verified_timestamp FROM profile_links WHERE user_id=$1 ORDER BY position"
+        )
+        .bind(user_id)
+        .fetch_all(db_conn)
+        .await?;
+
+    Ok(result.into_iter().map(|(url, verified_timestamp)| ProfileLink {
+        url,
+        verified: verified_timestamp.is_some()
+    }).collect())
+}
+
+/// Parse the profile form's links, one per line. Anything that isn't an
+/// http(s) URL is rejected with a message for the user.
+pub fn parse_links(input: &str) -> Result<Vec<String>, String> {
+    let mut links: Vec<String> = Vec::new();
+
+    for line in input.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        match Url::parse(line) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                if !links.iter().any(|link| link == line) {
+                    links.push(line.to_string());
+                }
+            },
+            _ => return Err(format!("{} isn't a web address.", line))
+        }
+    }
+
+    if links.len() > MAX_LINKS {
+        return Err(format!("A profile can list at most {} links.", MAX_LINKS));
+    }
+
+    Ok(links)
+}
+
+/// Replace a user's profile links. Links that were kept keep their verified
+/// state; new ones are checked by the next verification run.
+pub async fn save_links(state: &State, user_id: i64, links: &[String]) -> tide::Result<()> {
+    let mut tx = state.db_pool.begin().await?;
+
+    let existing: Vec<(String,)> = sqlx::query_as("SELECT url FROM profile_links WHERE user_id=$1")
+        .bind(user_id)
+        .fetch_all(&mut tx)
+        .await?;
+
+    for (url,) in existing {
+        if !links.contains(&url) {
+            sqlx::query("DELETE FROM profile_links WHERE user_id=$1 AND url=$2")
+                .bind(user_id)
+                .bind(url)
+                .execute(&mut tx)
+                .await?;
+        }
+    }
+
+    for (position, url) in links.iter().enumerate() {
+        let position = position as i64;
+
+        sqlx::query(
+                r#"INSERT INTO profile_links (user_id, url, position) VALUES ($1, $2, $3)
+                ON CONFLICT (user_id, url) DO UPDATE SET position=excluded.position"#
+            )
+            .bind(user_id)
+            .bind(url)
+            .bind(position)
+            .execute(&mut tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// The URLs that count as us when another page links back with rel="me"
+fn own_urls(state: &State, username: &str) -> Vec<Url> {
+    let site_url = &state.config.site_url;
+
+    [
+        format!("{}/", site_url),
+        format!("{}/user/profile", site_url),
+        format!("{}/users/{}", site_url, username)
+    ]
+        .iter()
+        .filter_map(|url| Url::parse(url).ok())
+        .collect()
+}
+
+/// Whether a page links back to one of our URLs with rel="me"
+async fn links_back(state: &State, url: &str, own_urls: &[Url]) -> bool {
+    let base = match Url::parse(url) {
+        Ok(base) => base,
+        Err(_) => return false
+    };
+
+    let body = match webmention::fetch(&state.config, url).await {
+        Some((status, _, body)) if (200..300).contains(&status) => body,
+        _ => return false
+    };
+
+    webmention::link_tags(&body).into_iter()
+        .filter(|(rel, _)| rel.as_deref().is_some_and(|rel| webmention::has_rel(rel, "me")))
+        .filter_map(|(_, href)| base.join(&href?).ok())
+        .any(|href| own_urls.contains(&href))
+}
+
+/// Check every profile link that's new or due for a recheck, returning how
+/// many are verified
+pub async fn verify_links(state: &State) -> tide::Result<usize> {
+    let mut db_conn = state.db_pool.acquire().await?;
+    let recheck_before = (Utc::now() - chrono::Duration::hours(RECHECK_HOURS)).to_rfc3339();
+
+    let rows: Vec<(i64, String, String)> = sqlx::query_as(
+            r#"SELECT profile_links.rowid AS link_id, url, users.username
+            FROM profile_links, users
+            WHERE users.rowid=profile_links.user_id
+                AND (checked_timestamp IS NULL OR checked_timestamp < $1)"#
+        )
+        .bind(&recheck_before)
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    let mut verified = 0;
+
+    for (link_id, url, username) in rows {
+        let now = Utc::now().to_rfc3339();
+
+        let verified_timestamp = match links_back(state, &url, &own_urls(state, &username)).await {
+            true => Some(now.clone()),
+            false => None
+        };
+
+        if verified_timestamp.is_some() {
+            verified += 1;
+        }
+
+        sqlx::query("UPDATE profile_links SET verified_timestamp=$1, checked_timestamp=$2 WHERE rowid=$3")
+            .bind(&verified_timestamp)
+            .bind(&now)
+            .bind(link_id)
+            .execute(&mut db_conn)
+            .await?;
+    }
+
+    Ok(verified)
+}
+
+/// Spawn the background task that verifies profile links
+pub fn spawn_verifier(state: State) {
+    let shutdown = state.shutdown.clone();
+
+    shutdown.spawn_job("verify profile links", VERIFY_CHECK_INTERVAL, move || {
+        let state = state.clone();
+
+        async move { verify_links(&state).await.map(|_| ()) }
+    });
+}
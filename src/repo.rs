@@ -0,0 +1,608 @@
+use chrono::prelude::*;
+use sqlx::AnyConnection;
+
+use super::db;
+use super::pagination::{Cursor, Page};
+use super::routes::{Image, NewPost, Post};
+
+// Typed access to the posts, users and image drafts tables. Handlers go
+// through these rather than writing SQL, so each query lives in one place.
+// Everything takes a connection, which may also be an open transaction.
+
+/// Columns selected for every post, joined with its author
+const POST_COLUMNS: &str = r#"users.username, users.name, users.rowid AS user_id,
+    posts.rowid AS post_id, posts.content, posts.posted_timestamp, posts.images, posts.short_url,
+    posts.published, posts.edited_timestamp, posts.deleted_timestamp, posts.visibility, posts.pinned_timestamp"#;
+
+/// Published, untrashed posts the session is allowed to see. Binds logged_in
+/// as the given parameter.
+fn visible_filter(param: usize) -> String {
+    format!(
+        "posts.published=1 AND posts.deleted_timestamp IS NULL AND (posts.visibility='public' OR (${} AND posts.visibility='private'))",
+        param
+    )
+}
+
+fn select_posts(filter: &str, order: &str) -> String {
+    format!(
+        "SELECT {} FROM users, posts WHERE users.rowid=posts.user_id AND {} {}",
+        POST_COLUMNS, filter, order
+    )
+}
+
+/// A post joined with its author, as queried
+#[derive(sqlx::FromRow)]
+struct PostRow {
+    username: String,
+    name: String,
+    user_id: i64,
+    post_id: i64,
+    content: String,
+    posted_timestamp: String,
+    short_url: Option<String>,
+    images: String,
+    published: i64,
+    edited_timestamp: Option<String>,
+    deleted_timestamp: Option<String>,
+    visibility: String,
+    pinned_timestamp: Option<String>
+}
+
+impl From<PostRow> for Post {
+    fn from(row: PostRow) -> Self {
+        Post {
+            username: row.username,
+            name: row.name,
+            user_id: row.user_id,
+            post_id: row.post_id,
+            content: row.content,
+            posted_timestamp: row.posted_timestamp,
+            short_url: row.short_url,
+            images: serde_json::from_str(row.images.as_str()).unwrap(),
+            published: row.published != 0,
+            edited_timestamp: row.edited_timestamp,
+            deleted_timestamp: row.deleted_timestamp,
+            visibility: row.visibility,
+            pinned_timestamp: row.pinned_timestamp
+        }
+    }
+}
+
+/// An earlier version of a post, kept when it was edited
+#[derive(sqlx::FromRow)]
+pub struct PostRevision {
+    pub revision_id: i64,
+    pub content: String,
+    pub short_url: Option<String>,
+    pub revised_timestamp: String
+}
+
+pub struct PostRepo;
+
+impl PostRepo {
+    /// Look up a single post, whatever state it's in
+    pub async fn find(db_conn: &mut AnyConnection, post_id: i64) -> tide::Result<Option<Post>> {
+        let row: Option<PostRow> = sqlx::query_as(&select_posts("posts.rowid=$1", ""))
+            .bind(post_id)
+            .fetch_optional(db_conn)
+            .await?;
+
+        Ok(row.map(Post::from))
+    }
+
+    /// Look up a post by its short URL, whatever state it's in
+    pub async fn find_by_short_url(db_conn: &mut AnyConnection, short_url: &str) -> tide::Result<Option<Post>> {
+        let row: Option<PostRow> = sqlx::query_as(&select_posts("posts.short_url=$1", ""))
+            .bind(short_url)
+            .fetch_optional(db_conn)
+            .await?;
+
+        Ok(row.map(Post::from))
+    }
+
+    /// Whether a post other than `post_id` already uses a short URL
+    pub async fn short_url_taken(db_conn: &mut AnyConnection, short_url: &str, post_id: i64) -> tide::Result<bool> {
+        let conflict: Option<(i64,)> = sqlx::query_as("SELECT rowid FROM posts WHERE short_url=$1 AND rowid!=$2")
+            .bind(short_url)
+            .bind(post_id)
+            .fetch_optional(db_conn)
+            .await?;
+
+        Ok(conflict.is_some())
+    }
+
+    /// The URL a post replies to, if it's a reply
+    pub async fn in_reply_to(db_conn: &mut AnyConnection, post_id: i64) -> tide::Result<Option<String>> {
+        let row: Option<(Option<String>,)> = sqlx::query_as("SELECT in_reply_to FROM posts WHERE rowid=$1")
+            .bind(post_id)
+            .fetch_optional(db_conn)
+            .await?;
+
+        Ok(row.and_then(|(in_reply_to,)| in_reply_to))
+    }
+
+    /// Pinned posts visible to the session, most recently pinned first
+    pub async fn pinned(db_conn: &mut AnyConnection, logged_in: bool) -> tide::Result<Vec<Post>> {
+        let filter = format!("{} AND posts.pinned_timestamp IS NOT NULL", visible_filter(1));
+
+        let result: Vec<PostRow> = sqlx::query_as(&select_posts(&filter, "ORDER BY posts.pinned_timestamp desc"))
+            .bind(logged_in)
+            .fetch_all(db_conn)
+            .await?;
+
+        Ok(result.into_iter().map(Post::from).collect())
+    }
+
+    /// Up to `limit` unpinned timeline posts for a page: the newest ones for
+    /// the first page, otherwise those on one side of the cursor, nearest to
+    /// it first
+    pub async fn timeline(
+        db_conn: &mut AnyConnection,
+        logged_in: bool,
+        page: &Page,
+        limit: i64
+    ) -> tide::Result<Vec<Post>> {
+        let (cursor, comparison, order) = match page {
+            Page::First => {
+                let filter = format!("{} AND posts.pinned_timestamp IS NULL", visible_filter(2));
+                let order = "ORDER BY posts.posted_timestamp desc, posts.rowid desc LIMIT $1";
+
+                let result: Vec<PostRow> = sqlx::query_as(&select_posts(&filter, order))
+                    .bind(limit)
+                    .bind(logged_in)
+                    .fetch_all(db_conn)
+                    .await?;
+
+                return Ok(result.into_iter().map(Post::from).collect());
+            },
+            Page::Before(cursor) => (cursor, "<", "desc"),
+            Page::After(cursor) => (cursor, ">", "asc")
+        };
+
+        let filter = format!(
+            "{} AND posts.pinned_timestamp IS NULL AND (posts.posted_timestamp, posts.rowid) {} ($1, $2)",
+            visible_filter(4),
+            comparison
+        );
+        let order = format!("ORDER BY posts.posted_timestamp {0}, posts.rowid {0} LIMIT $3", order);
+
+        let result: Vec<PostRow> = sqlx::query_as(&select_posts(&filter, &order))
+            .bind(&cursor.posted_timestamp)
+            .bind(cursor.post_id)
+            .bind(limit)
+            .bind(logged_in)
+            .fetch_all(db_conn)
+            .await?;
+
+        Ok(result.into_iter().map(Post::from).collect())
+    }
+
+    /// Whether any unpinned timeline posts are newer (or older) than the cursor
+    pub async fn has_timeline_posts(
+        db_conn: &mut AnyConnection,
+        logged_in: bool,
+        cursor: &Cursor,
+        newer: bool
+    ) -> tide::Result<bool> {
+        let comparison = match newer {
+            true => ">",
+            false => "<"
+        };
+
+        let sql = format!(
+            r#"SELECT posts.rowid AS post_id FROM posts
+            WHERE {} AND posts.pinned_timestamp IS NULL AND (posts.posted_timestamp, posts.rowid) {} ($1, $2)
+            LIMIT 1"#,
+            visible_filter(3),
+            comparison
+        );
+
+        let row: Option<(i64,)> = sqlx::query_as(&sql)
+            .bind(&cursor.posted_timestamp)
+            .bind(cursor.post_id)
+            .bind(logged_in)
+            .fetch_optional(db_conn)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Visible posts made in [start, end), newest first. Pinned posts are included.
+    pub async fn between(
+        db_conn: &mut AnyConnection,
+        logged_in: bool,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>
+    ) -> tide::Result<Vec<Post>> {
+        let filter = format!(
+            "{} AND posts.posted_timestamp >= $1 AND posts.posted_timestamp < $2",
+            visible_filter(3)
+        );
+
+        let result: Vec<PostRow> = sqlx::query_as(&select_posts(&filter, "ORDER BY posts.posted_timestamp desc, posts.rowid desc"))
+            .bind(start.to_rfc3339())
+            .bind(end.to_rfc3339())
+            .bind(logged_in)
+            .fetch_all(db_conn)
+            .await?;
+
+        Ok(result.into_iter().map(Post::from).collect())
+    }
+
+    /// When each visible post was made, in no particular order
+    pub async fn posted_timestamps(db_conn: &mut AnyConnection, logged_in: bool) -> tide::Result<Vec<String>> {
+        let sql = format!("SELECT posts.posted_timestamp FROM posts WHERE {}", visible_filter(1));
+
+        let result: Vec<(String,)> = sqlx::query_as(&sql)
+            .bind(logged_in)
+            .fetch_all(db_conn)
+            .await?;
+
+        Ok(result.into_iter().map(|(posted_timestamp,)| posted_timestamp).collect())
+    }
+
+    /// Scheduled posts waiting to be published, soonest first
+    pub async fn scheduled(db_conn: &mut AnyConnection) -> tide::Result<Vec<Post>> {
+        let filter = "posts.published=0 AND posts.deleted_timestamp IS NULL";
+
+        let result: Vec<PostRow> = sqlx::query_as(&select_posts(filter, "ORDER BY posts.posted_timestamp asc"))
+            .fetch_all(db_conn)
+            .await?;
+
+        Ok(result.into_iter().map(Post::from).collect())
+    }
+
+    /// Posts in the trash, most recently trashed first
+    pub async fn trashed(db_conn: &mut AnyConnection) -> tide::Result<Vec<Post>> {
+        let filter = "posts.deleted_timestamp IS NOT NULL";
+
+        let result: Vec<PostRow> = sqlx::query_as(&select_posts(filter, "ORDER BY posts.deleted_timestamp desc"))
+            .fetch_all(db_conn)
+            .await?;
+
+        Ok(result.into_iter().map(Post::from).collect())
+    }
+
+    /// Every image attached to a post, including trashed and unpublished ones
+    pub async fn images(db_conn: &mut AnyConnection) -> tide::Result<Vec<Image>> {
+        let result: Vec<(String,)> = sqlx::query_as("SELECT images FROM posts")
+            .fetch_all(db_conn)
+            .await?;
+
+        let mut images = Vec::new();
+
+        for (post_images,) in result {
+            images.extend(serde_json::from_str::<Vec<Image>>(&post_images)?);
+        }
+
+        Ok(images)
+    }
+
+    /// Store a new post for user 1 and return its id
+    pub async fn insert(
+        db_conn: &mut AnyConnection,
+        post: &NewPost<'_>,
+        posted_timestamp: &str,
+        published: bool
+    ) -> tide::Result<i64> {
+        let images: String = serde_json::to_string(&post.images)?;
+
+        db::insert(db_conn, sqlx::query(
+                r#"INSERT INTO posts (user_id, content, posted_timestamp, images, published, visibility, short_url)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)"#
+            )
+            .bind(1_i64)
+            .bind(post.content)
+            .bind(posted_timestamp)
+            .bind(images)
+            .bind(published as i64)
+            .bind(post.visibility.as_str())
+            .bind(post.short_url)).await
+    }
+
+    /// Replace a post's content and short URL, keeping the version being
+    /// replaced as a revision. Does nothing if neither actually changed.
+    pub async fn revise(
+        db_conn: &mut AnyConnection,
+        post_id: i64,
+        content: &str,
+        short_url: &Option<String>
+    ) -> tide::Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        let current: Option<(String, Option<String>)> = sqlx::query_as("SELECT content, short_url FROM posts WHERE rowid=$1")
+            .bind(post_id)
+            .fetch_optional(&mut *db_conn)
+            .await?;
+
+        // Unchanged (or missing) posts don't get a revision. The comparison is
+        // done here since the backends disagree on NULL-safe equality.
+        let (old_content, old_short_url) = match current {
+            Some(current) if current.0 != content || &current.1 != short_url => current,
+            _ => return Ok(())
+        };
+
+        sqlx::query(
+                "INSERT INTO post_revisions (post_id, content, short_url, revised_timestamp) VALUES ($1, $2, $3, $4)"
+            )
+            .bind(post_id)
+            .bind(&old_content)
+            .bind(&old_short_url)
+            .bind(&now)
+            .execute(&mut *db_conn)
+            .await?;
+
+        sqlx::query("UPDATE posts SET content=$1, short_url=$2, edited_timestamp=$3 WHERE rowid=$4")
+            .bind(content)
+            .bind(short_url)
+            .bind(&now)
+            .bind(post_id)
+            .execute(&mut *db_conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Stored earlier versions of a post, oldest first
+    pub async fn revisions(db_conn: &mut AnyConnection, post_id: i64) -> tide::Result<Vec<PostRevision>> {
+        let revisions = sqlx::query_as(
+                r#"SELECT rowid AS revision_id, content, short_url, revised_timestamp
+                FROM post_revisions WHERE post_id=$1 ORDER BY rowid asc"#
+            )
+            .bind(post_id)
+            .fetch_all(db_conn)
+            .await?;
+
+        Ok(revisions)
+    }
+
+    /// One earlier version of a post, or the most recent one without an id
+    pub async fn revision(
+        db_conn: &mut AnyConnection,
+        post_id: i64,
+        revision_id: Option<i64>
+    ) -> tide::Result<Option<PostRevision>> {
+        let revision = match revision_id {
+            Some(revision_id) => sqlx::query_as(
+                    r#"SELECT rowid AS revision_id, content, short_url, revised_timestamp
+                    FROM post_revisions WHERE rowid=$1 AND post_id=$2"#
+                )
+                .bind(revision_id)
+                .bind(post_id)
+                .fetch_optional(db_conn)
+                .await?,
+            None => sqlx::query_as(
+                    r#"SELECT rowid AS revision_id, content, short_url, revised_timestamp
+                    FROM post_revisions WHERE post_id=$1 ORDER BY rowid desc LIMIT 1"#
+                )
+                .bind(post_id)
+                .fetch_optional(db_conn)
+                .await?
+        };
+
+        Ok(revision)
+    }
+
+    pub async fn set_visibility(db_conn: &mut AnyConnection, post_id: i64, visibility: &str) -> tide::Result<()> {
+        sqlx::query("UPDATE posts SET visibility=$1 WHERE rowid=$2")
+            .bind(visibility)
+            .bind(post_id)
+            .execute(db_conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Pin a post above the timeline, or unpin it with None
+    pub async fn set_pinned(db_conn: &mut AnyConnection, post_id: i64, pinned_timestamp: Option<&str>) -> tide::Result<()> {
+        sqlx::query("UPDATE posts SET pinned_timestamp=$1 WHERE rowid=$2")
+            .bind(pinned_timestamp)
+            .bind(post_id)
+            .execute(db_conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Move a scheduled post to a different publish time
+    pub async fn reschedule(db_conn: &mut AnyConnection, post_id: i64, publish_at: &str) -> tide::Result<()> {
+        sqlx::query("UPDATE posts SET posted_timestamp=$1 WHERE rowid=$2 AND published=0")
+            .bind(publish_at)
+            .bind(post_id)
+            .execute(db_conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Publish a scheduled post as of `posted_timestamp`. Returns false if it
+    /// wasn't waiting to be published.
+    pub async fn publish(db_conn: &mut AnyConnection, post_id: i64, posted_timestamp: &str) -> tide::Result<bool> {
+        let result = sqlx::query(
+                "UPDATE posts SET posted_timestamp=$1, published=1 WHERE rowid=$2 AND published=0 AND deleted_timestamp IS NULL"
+            )
+            .bind(posted_timestamp)
+            .bind(post_id)
+            .execute(db_conn)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Move a post to the trash, where it's purged later. Returns false if the
+    /// post doesn't exist or is already trashed.
+    pub async fn trash(db_conn: &mut AnyConnection, post_id: i64) -> tide::Result<bool> {
+        let now = Utc::now().to_rfc3339();
+
+        let result = sqlx::query("UPDATE posts SET deleted_timestamp=$1 WHERE rowid=$2 AND deleted_timestamp IS NULL")
+            .bind(&now)
+            .bind(post_id)
+            .execute(db_conn)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Take a post back out of the trash. Returns false if it wasn't in there.
+    pub async fn untrash(db_conn: &mut AnyConnection, post_id: i64) -> tide::Result<bool> {
+        let result = sqlx::query("UPDATE posts SET deleted_timestamp=NULL WHERE rowid=$1 AND deleted_timestamp IS NOT NULL")
+            .bind(post_id)
+            .execute(db_conn)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// A user's public profile
+#[derive(sqlx::FromRow)]
+pub struct User {
+    pub user_id: i64,
+    pub username: String,
+    pub name: String,
+    pub bio: String
+}
+
+pub struct UserRepo;
+
+impl UserRepo {
+    pub async fn find(db_conn: &mut AnyConnection, user_id: i64) -> tide::Result<Option<User>> {
+        let user = sqlx::query_as("SELECT rowid AS user_id, username, name, bio FROM users WHERE rowid=$1")
+            .bind(user_id)
+            .fetch_optional(db_conn)
+            .await?;
+
+        Ok(user)
+    }
+
+    pub async fn find_by_username(db_conn: &mut AnyConnection, username: &str) -> tide::Result<Option<User>> {
+        let user = sqlx::query_as("SELECT rowid AS user_id, username, name, bio FROM users WHERE username=$1")
+            .bind(username)
+            .fetch_optional(db_conn)
+            .await?;
+
+        Ok(user)
+    }
+
+    pub async fn list(db_conn: &mut AnyConnection) -> tide::Result<Vec<User>> {
+        let users = sqlx::query_as("SELECT rowid AS user_id, username, name, bio FROM users ORDER BY rowid")
+            .fetch_all(db_conn)
+            .await?;
+
+        Ok(users)
+    }
+
+    /// Add a user and return their id. The id is picked here rather than by
+    /// the database, since the owner is inserted as user 1 explicitly and that
+    /// leaves Postgres' sequence behind.
+    pub async fn create(db_conn: &mut AnyConnection, username: &str, name: &str, password_hash: &str) -> tide::Result<i64> {
+        sqlx::query(
+                r#"INSERT INTO users (rowid, username, name, password_hash)
+                SELECT COALESCE(MAX(rowid), 0) + 1, $1, $2, $3 FROM users"#
+            )
+            .bind(username)
+            .bind(name)
+            .bind(password_hash)
+            .execute(&mut *db_conn)
+            .await?;
+
+        let user = Self::find_by_username(db_conn, username).await?
+            .ok_or_else(|| tide::Error::from_str(500, "The new user is missing from the database"))?;
+
+        Ok(user.user_id)
+    }
+
+    /// The user's password hash, if they have a password set. Users without
+    /// one log in with the configured admin password.
+    pub async fn password_hash(db_conn: &mut AnyConnection, username: &str) -> tide::Result<Option<String>> {
+        let result: Option<(Option<String>,)> = sqlx::query_as("SELECT password_hash FROM users WHERE username=$1")
+            .bind(username)
+            .fetch_optional(db_conn)
+            .await?;
+
+        Ok(result.and_then(|(password_hash,)| password_hash))
+    }
+
+    pub async fn set_password_hash(db_conn: &mut AnyConnection, user_id: i64, password_hash: &str) -> tide::Result<()> {
+        sqlx::query("UPDATE users SET password_hash=$1 WHERE rowid=$2")
+            .bind(password_hash)
+            .bind(user_id)
+            .execute(db_conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// The site's owner, user 1, who is created when the database is set up
+    pub async fn owner(db_conn: &mut AnyConnection) -> tide::Result<User> {
+        Self::find(db_conn, 1).await?
+            .ok_or_else(|| tide::Error::from_str(500, "The site owner is missing from the database"))
+    }
+
+    pub async fn update_profile(db_conn: &mut AnyConnection, user_id: i64, name: &str, bio: &str) -> tide::Result<()> {
+        sqlx::query("UPDATE users SET name=$1, bio=$2 WHERE rowid=$3")
+            .bind(name)
+            .bind(bio)
+            .bind(user_id)
+            .execute(db_conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+pub struct MediaRepo;
+
+impl MediaRepo {
+    /// Uploaded images waiting to be attached to the next post
+    pub async fn drafts(db_conn: &mut AnyConnection) -> tide::Result<Vec<Image>> {
+        let result: Vec<(Option<String>, Option<String>, Option<String>)> = sqlx::query_as(
+                "SELECT image_thumbnail_path, image_medium_path, image_full_path FROM image_drafts ORDER BY rowid"
+            )
+            .fetch_all(db_conn)
+            .await?;
+
+        let images = result.into_iter().map(|(thumbnail_path, medium_path, full_path)| {
+            Image {
+                full_path: full_path.unwrap_or_default(),
+                medium_path: medium_path.unwrap_or_default(),
+                thumbnail_path: thumbnail_path.unwrap_or_default()
+            }
+        }).collect();
+
+        Ok(images)
+    }
+
+    pub async fn add_draft(db_conn: &mut AnyConnection, image: &Image) -> tide::Result<()> {
+        sqlx::query(
+                r#"INSERT INTO image_drafts
+                    (image_thumbnail_path, image_medium_path, image_full_path)
+                    VALUES ($1, $2, $3)"#
+            )
+            .bind(&image.thumbnail_path)
+            .bind(&image.medium_path)
+            .bind(&image.full_path)
+            .execute(db_conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Drop one draft once it's been attached to a post
+    pub async fn remove_draft(db_conn: &mut AnyConnection, full_path: &str) -> tide::Result<()> {
+        sqlx::query("DELETE FROM image_drafts WHERE image_full_path=$1")
+            .bind(full_path)
+            .execute(db_conn)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_drafts(db_conn: &mut AnyConnection) -> tide::Result<()> {
+        sqlx::query("DELETE FROM image_drafts")
+            .execute(db_conn)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -1,11 +1,46 @@
 use super::{State, MessageFlashes};
+use super::repo::{MediaRepo, PostRepo, UserRepo};
+use super::pagination::{Cursor, Page};
 
 use tide_tera::prelude::*;
 use tide::{Request, Response, Redirect};
+use tide::prelude::json;
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 use chrono::prelude::*;
 use std::vec::Vec;
 
+/// Who can see a post. Unlisted posts are reachable by link but left out of
+/// the timeline, private posts are only visible to logged in users.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Unlisted,
+    Private
+}
+
+impl Visibility {
+    /// Read a stored or submitted visibility
+    pub fn parse(value: &str) -> Option<Visibility> {
+        match value {
+            "public" => Some(Visibility::Public),
+            "unlisted" => Some(Visibility::Unlisted),
+            "private" => Some(Visibility::Private),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Private => "private"
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct LoginFormInput {
     username: String,
@@ -19,6 +54,12 @@ pub struct LoginFormInput {
 pub struct PostFormInput {
     content: String,
 
+    #[serde(rename = "publish-at", default)]
+    publish_at: Option<String>,
+
+    #[serde(default)]
+    visibility: Visibility,
+
     #[serde(rename = "csrf-token")]
     csrf_token: String,
 }
@@ -30,6 +71,9 @@ pub struct PostEditFormInput {
     #[serde(rename = "short-url")]
     short_url: String,
 
+    #[serde(default)]
+    visibility: Visibility,
+
     #[serde(rename = "csrf-token")]
     csrf_token: String,
 }
@@ -40,37 +84,245 @@ pub struct PostDeleteFormInput {
     csrf_token: String,
 }
 
+#[derive(Deserialize)]
+pub struct PostRescheduleFormInput {
+    #[serde(rename = "publish-at")]
+    publish_at: String,
+
+    #[serde(rename = "csrf-token")]
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PostPublishFormInput {
+    #[serde(rename = "csrf-token")]
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PostRestoreFormInput {
+    #[serde(rename = "csrf-token")]
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PostUntrashFormInput {
+    #[serde(rename = "csrf-token")]
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PostPurgeFormInput {
+    #[serde(rename = "csrf-token")]
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PostPinFormInput {
+    pinned: bool,
+
+    #[serde(rename = "csrf-token")]
+    csrf_token: String,
+}
+
 #[derive(Deserialize)]
 pub struct ProfileUpdateFormInput {
     name: String,
     bio: String,
+    #[serde(default)]
+    links: String,
+
+    #[serde(rename = "csrf-token")]
+    csrf_token: String,
+}
 
+#[derive(Deserialize)]
+pub struct TokenRevokeFormInput {
     #[serde(rename = "csrf-token")]
     csrf_token: String,
 }
 
 #[derive(Deserialize)]
 struct IndexQuery {
+    before: Option<String>,
+    after: Option<String>,
     before_timestamp: Option<String>
 }
 
+#[derive(Deserialize)]
+struct DiffQuery {
+    from: Option<i64>,
+    to: Option<i64>
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Image {
-    thumbnail_path: String,
-    medium_path: String,
-    full_path: String
+    pub thumbnail_path: String,
+    pub medium_path: String,
+    pub full_path: String
 }
 
 #[derive(Serialize)]
 pub struct Post {
-    username: String,
-    post_id: i64,
+    pub username: String,
+    pub post_id: i64,
+    pub name: String,
+    pub user_id: i64,
+    pub content: String,
+    pub posted_timestamp: String,
+    pub short_url: Option<String>,
+    pub images: Vec<Image>,
+    pub published: bool,
+    pub edited_timestamp: Option<String>,
+    pub deleted_timestamp: Option<String>,
+    pub visibility: String,
+    pub pinned_timestamp: Option<String>
+}
+
+impl Post {
+    /// Scheduled, trashed and private posts are only visible to the author
+    pub fn is_visible(&self, logged_in: bool) -> bool {
+        logged_in || (self.published && self.deleted_timestamp.is_none() && self.visibility != "private")
+    }
+}
+
+/// A personal access token as listed on the profile page
+#[derive(Serialize)]
+pub struct ApiToken {
+    token_id: i64,
     name: String,
-    user_id: i64,
+    scopes: Vec<String>,
+    created_timestamp: String,
+    last_used_timestamp: Option<String>
+}
+
+/// A single stored version of a post. The current version has no revision id.
+#[derive(Serialize)]
+pub struct Revision {
+    revision_id: Option<i64>,
     content: String,
-    posted_timestamp: String,
     short_url: Option<String>,
-    images: Vec<Image>
+    revised_timestamp: Option<String>
+}
+
+/// One line of a diff between two revisions
+#[derive(Serialize)]
+pub struct DiffLine {
+    kind: &'static str,
+    text: String
+}
+
+/// A verified webmention of a post
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Mention {
+    mention_id: i64,
+    source: String,
+    title: Option<String>,
+    approved: bool,
+    verified_timestamp: Option<String>
+}
+
+/// Verified webmentions of a post. Only approved ones are shown publicly; the
+/// owner also sees the ones still waiting for moderation.
+pub async fn post_mentions(db_conn: &mut sqlx::AnyConnection, post_id: i64, logged_in: bool) -> tide::Result<Vec<Mention>> {
+    let mentions = sqlx::query_as(
+            r#"SELECT rowid AS mention_id, source, title, approved=1 AS approved, verified_timestamp FROM webmentions
+            WHERE post_id=$1 AND status='verified' AND (approved=1 OR $2)
+            ORDER BY verified_timestamp"#
+        )
+        .bind(post_id)
+        .bind(logged_in)
+        .fetch_all(db_conn)
+        .await?;
+
+    Ok(mentions)
+}
+
+/// Find the post a URL points at, either its permalink or its short URL
+pub async fn post_id_from_url(state: &State, url: &str) -> tide::Result<Option<i64>> {
+    let base = tide::http::Url::parse(&state.config.site_url)?;
+
+    let path = match base.join(url) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => return Ok(None)
+    };
+
+    if let Some(post_id) = path.strip_prefix("/post/view/") {
+        return Ok(post_id.parse().ok());
+    }
+
+    if let Some(short_url) = path.strip_prefix("/post/share/") {
+        let mut db_conn = state.db_pool.acquire().await?;
+
+        let post = PostRepo::find_by_short_url(&mut db_conn, short_url).await?;
+
+        return Ok(post.map(|post| post.post_id));
+    }
+
+    Ok(None)
+}
+
+/// One page of the timeline, with cursors for the neighbouring pages if
+/// there are any posts on them
+pub struct TimelinePage {
+    pub posts: Vec<Post>,
+    pub newer: Option<Cursor>,
+    pub older: Option<Cursor>
+}
+
+/// Query one page of the timeline, newest first. Pinned posts are left out,
+/// they are shown separately above the first page.
+pub async fn timeline_page(
+    db_conn: &mut sqlx::AnyConnection,
+    logged_in: bool,
+    page: &Page,
+    posts_per_page: i64
+) -> tide::Result<TimelinePage> {
+    // Fetch one extra post to find out whether there's another page
+    let limit = posts_per_page + 1;
+
+    let mut posts = PostRepo::timeline(db_conn, logged_in, page, limit).await?;
+
+    let has_more = posts.len() as i64 > posts_per_page;
+
+    posts.truncate(posts_per_page as usize);
+
+    if let Page::After(_) = page {
+        posts.reverse();
+    }
+
+    let first = posts.first().map(|post| Cursor::new(&post.posted_timestamp, post.post_id));
+    let last = posts.last().map(|post| Cursor::new(&post.posted_timestamp, post.post_id));
+
+    // In the direction we fetched, the extra post tells us if there's more.
+    // The other direction needs its own check.
+    let (newer, older) = match page {
+        Page::First => (None, if has_more { last } else { None }),
+        Page::Before(cursor) => {
+            let anchor = first.unwrap_or_else(|| cursor.clone());
+            let newer = match PostRepo::has_timeline_posts(db_conn, logged_in, &anchor, true).await? {
+                true => Some(anchor),
+                false => None
+            };
+
+            (newer, if has_more { last } else { None })
+        },
+        Page::After(cursor) => {
+            let anchor = last.unwrap_or_else(|| cursor.clone());
+            let older = match PostRepo::has_timeline_posts(db_conn, logged_in, &anchor, false).await? {
+                true => Some(anchor),
+                false => None
+            };
+
+            (if has_more { first } else { None }, older)
+        }
+    };
+
+    Ok(TimelinePage {
+        posts,
+        newer,
+        older
+    })
 }
 
 pub async fn index(req: Request<State>) -> tide::Result<tide::Response> {
@@ -84,68 +336,45 @@ pub async fn index(req: Request<State>) -> tide::Result<tide::Response> {
     let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
     let posts_per_page = config.posts_per_page as i64;
 
-    let mut db_conn = (&req.state()).sqlite_pool.acquire().await?;
+    let mut db_conn = state.db_pool.acquire().await?;
     let mut context = tera::Context::new();
-    let now = Utc::now().to_rfc3339();
 
-    let query: IndexQuery = req.query().unwrap();
-    let before_timestamp: String = match query.before_timestamp {
-        Some(timestamp) => timestamp,
-        None => now
-    };
-
-    // Query for all posts, joined on users of that post
-    let result = sqlx::query!(
-            r#"SELECT
-                users.username, users.name, users.rowid AS user_id,
-                posts.rowid AS post_id, posts.content, posts.posted_timestamp, posts.images, short_url
-            FROM users, posts
-            WHERE users.rowid=posts.user_id AND posts.posted_timestamp < ?1
-            ORDER BY posted_timestamp desc LIMIT ?2"#,
-            before_timestamp,
-            posts_per_page
-        )
-        .fetch_all(&mut db_conn)
-        .await?;
+    let query: IndexQuery = req.query()?;
 
-    let mut posts = Vec::new();
-    let mut post_ids = Vec::new();
+    // Links from before cursors existed only carry a timestamp
+    let page = match query.before_timestamp {
+        Some(timestamp) if query.before.is_none() && query.after.is_none() => {
+            Page::Before(Cursor::new(&timestamp, 0))
+        },
+        _ => match Page::from_query(query.before.as_deref(), query.after.as_deref()) {
+            Some(page) => page,
+            None => return Ok(tide::Response::builder(400).body("Invalid page cursor").build())
+        }
+    };
 
-    for row in result {
-        post_ids.push(row.post_id.unwrap());
+    let timeline = timeline_page(&mut db_conn, logged_in, &page, posts_per_page).await?;
 
-        posts.push(Post{
-            username: row.username,
-            name: row.name,
-            user_id: row.user_id.unwrap(),
-            post_id: row.post_id.unwrap(),
-            content: row.content,
-            posted_timestamp: row.posted_timestamp,
-            short_url: row.short_url,
-            images: serde_json::from_str(row.images.as_str()).unwrap()
-        });
+    // Paging back up to the newest posts lands on the actual first page
+    if let (Page::After(_), None) = (&page, &timeline.newer) {
+        return Ok(Redirect::new("/").into());
     }
 
-    // Query for draft images
-    let result = sqlx::query!(
-            "SELECT image_thumbnail_path, image_medium_path, image_full_path FROM image_drafts"
-        )
-        .fetch_all(&mut db_conn)
-        .await?;
+    // Pinned posts sit above the first page, and are left out of the timeline
+    // itself so they don't show up twice
+    let pinned_posts = match page {
+        Page::First => PostRepo::pinned(&mut db_conn, logged_in).await?,
+        _ => Vec::new()
+    };
 
-    let draft_images: Vec<Image> = result.into_iter().map(|row| {
-        Image {
-            full_path: row.image_full_path.unwrap(),
-            medium_path: row.image_medium_path.unwrap(),
-            thumbnail_path: row.image_thumbnail_path.unwrap()
-        }
-    }).collect();
+    let draft_images = MediaRepo::drafts(&mut db_conn).await?;
 
-    context.insert("posts", &posts);
+    context.insert("pinned_posts", &pinned_posts);
+    context.insert("posts", &timeline.posts);
     context.insert("draft_images", &draft_images);
     context.insert("logged_in", &logged_in);
     context.insert("csrf_token", &csrf_token);
-    context.insert("view_more", &(posts.len() >= config.posts_per_page as usize));
+    context.insert("newer_cursor", &timeline.newer.map(|cursor| cursor.encode()));
+    context.insert("older_cursor", &timeline.older.map(|cursor| cursor.encode()));
 
     if let Some(m) = messages {
         context.insert("messages", &m.messages);
@@ -176,17 +405,34 @@ pub async fn user_login(req: Request<State>) -> tide::Result<tide::Response> {
 pub async fn user_login_post(mut req: Request<State>) -> tide::Result<tide::Response> {
     let login_form: LoginFormInput = req.body_form().await?;
     let csrf_token = req.session().get::<String>("csrf_token").unwrap();
-    let config = &req.state().config.clone();
+    let state = req.state();
+    let mut db_conn = state.db_pool.acquire().await?;
 
-    // TODO: use hashing instead of plaintext comparisons
-    if login_form.username == config.admin_username &&
-        login_form.password == config.admin_password &&
-        login_form.csrf_token == csrf_token {
+    // Users with a password set from the command line log in with it, and
+    // the admin user falls back to ADMIN_PASSWORD until then
+    let password_hash = UserRepo::password_hash(&mut db_conn, &login_form.username).await?;
+
+    drop(db_conn);
+
+    let password_correct = match password_hash {
+        Some(password_hash) => super::passwords::verify_password(&login_form.password, &password_hash).await,
+        None => login_form.username == state.config.admin_username &&
+            login_form.password == state.config.admin_password
+    };
+
+    if password_correct && login_form.csrf_token == csrf_token {
 
         req.session_mut().insert("logged_in", true).unwrap();
 
+        // Pages that need a login, like the IndieAuth consent screen, ask to
+        // be sent back to once it's done
+        let redirect = req.session().get::<String>("login_redirect")
+            .unwrap_or_else(|| "/".to_string());
+
+        req.session_mut().remove("login_redirect");
+
         // Login correct, set session
-        Ok(Redirect::new("/").into())
+        Ok(Redirect::new(redirect).into())
     } else {
         req.session_mut().insert(
             "messages",
@@ -198,27 +444,57 @@ pub async fn user_login_post(mut req: Request<State>) -> tide::Result<tide::Resp
 }
 
 /// User profile view
-pub async fn user_profile(req: Request<State>) -> tide::Result<Response> {
+pub async fn user_profile(mut req: Request<State>) -> tide::Result<Response> {
+    // A newly created token is shown exactly once
+    let new_api_token = req.session().get::<String>("new_api_token");
+    req.session_mut().remove("new_api_token");
+
     let state: &State = req.state();
     let session = req.session();
     let messages: Option<&MessageFlashes> = req.ext();
     let tera: &tera::Tera = &state.tera;
 
     let mut context = tera::Context::new();
-    let mut db_conn = state.sqlite_pool.acquire().await?;
+    let mut db_conn = state.db_pool.acquire().await?;
 
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
     let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
 
-    let row = sqlx::query!(
-            "SELECT name, username, bio FROM users WHERE rowid=1"
-        )
-        .fetch_one(&mut db_conn)
-        .await?;
+    let user = UserRepo::owner(&mut db_conn).await?;
+
+    let pinned_posts = PostRepo::pinned(&mut db_conn, logged_in).await?;
+    let profile_links = super::relme::profile_links(&mut db_conn, 1).await?;
+
+    if logged_in {
+        let result: Vec<(i64, String, String, String, Option<String>)> = sqlx::query_as(
+                r#"SELECT rowid AS token_id, name, scopes, created_timestamp, last_used_timestamp
+                FROM api_tokens WHERE user_id=1 ORDER BY created_timestamp desc"#
+            )
+            .fetch_all(&mut db_conn)
+            .await?;
 
-    context.insert("name" , &row.name);
-    context.insert("username", &row.username);
-    context.insert("bio", &row.bio);
+        let api_tokens: Vec<ApiToken> = result.into_iter().map(|(token_id, name, scopes, created_timestamp, last_used_timestamp)| {
+            ApiToken {
+                token_id,
+                name,
+                scopes: scopes.split_whitespace().map(str::to_string).collect(),
+                created_timestamp,
+                last_used_timestamp
+            }
+        }).collect();
+
+        context.insert("api_tokens", &api_tokens);
+        context.insert("api_token_scopes", super::tokens::SCOPES);
+        context.insert("new_api_token", &new_api_token);
+        context.insert("csrf_token", &csrf_token);
+    }
+
+    context.insert("name" , &user.name);
+    context.insert("username", &user.username);
+    context.insert("bio", &user.bio);
     context.insert("logged_in", &logged_in);
+    context.insert("pinned_posts", &pinned_posts);
+    context.insert("profile_links", &profile_links);
 
     if let Some(m) = messages {
         context.insert("messages", &m.messages);
@@ -227,6 +503,78 @@ pub async fn user_profile(req: Request<State>) -> tide::Result<Response> {
     tera.render_response("profile.html", &context)
 }
 
+/// Create a personal access token. The form has a checkbox for each scope,
+/// so it's read as a list of pairs rather than a struct.
+pub async fn user_token_create(mut req: Request<State>) -> tide::Result<Response> {
+    let session = req.session();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let form_input: Vec<(String, String)> = req.body_form().await?;
+
+    let field = |key: &str| form_input.iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.trim().to_string());
+
+    let scopes: Vec<String> = form_input.iter()
+        .filter(|(name, value)| name == "scope" && super::tokens::is_valid_scope(value))
+        .map(|(_, value)| value.to_string())
+        .collect();
+
+    if !logged_in {
+        Ok(tide::Response::builder(400).body("Forbidden").build())
+    } else if field("csrf-token").as_deref() != Some(csrf_token.as_str()) {
+        Ok(tide::Response::builder(400).body("Invalid CSRF").build())
+    } else {
+        let name = field("name").unwrap_or_default();
+
+        let message = if name.is_empty() {
+            "API tokens need a name.".to_string()
+        } else if scopes.is_empty() {
+            "API tokens need at least one scope.".to_string()
+        } else {
+            let token = super::tokens::create_token(req.state(), &name, &scopes).await?;
+
+            req.session_mut().insert("new_api_token", token).unwrap();
+
+            format!("Created the API token \"{}\". Copy it now, it won't be shown again.", name)
+        };
+
+        req.session_mut().insert("messages", message).unwrap();
+
+        Ok(Redirect::new("/user/profile").into())
+    }
+}
+
+/// Revoke a personal access token, any client using it stops working
+pub async fn user_token_revoke(mut req: Request<State>) -> tide::Result<Response> {
+    let session = req.session();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let form_input: TokenRevokeFormInput = req.body_form().await?;
+    let token_id: i64 = req.param("token_id")?.parse()?;
+
+    if !logged_in {
+        Ok(tide::Response::builder(400).body("Forbidden").build())
+    } else if form_input.csrf_token != csrf_token {
+        Ok(tide::Response::builder(400).body("Invalid CSRF").build())
+    } else {
+        let mut db_conn = req.state().db_pool.acquire().await?;
+
+        sqlx::query("DELETE FROM api_tokens WHERE rowid=$1 AND user_id=1")
+            .bind(token_id)
+            .execute(&mut db_conn)
+            .await?;
+
+        req.session_mut().insert("messages", "API token revoked.".to_string()).unwrap();
+
+        Ok(Redirect::new("/user/profile").into())
+    }
+}
+
 /// User profile edit
 pub async fn user_profile_edit(req: Request<State>) -> tide::Result<Response> {
     let state: &State = req.state();
@@ -247,17 +595,19 @@ pub async fn user_profile_edit(req: Request<State>) -> tide::Result<Response> {
         )
     } else {
         let mut context = tera::Context::new();
-        let mut db_conn = state.sqlite_pool.acquire().await?;
+        let mut db_conn = state.db_pool.acquire().await?;
 
-        let row = sqlx::query!(
-                "SELECT name, username, bio FROM users WHERE rowid=1"
-            )
-            .fetch_one(&mut db_conn)
-            .await?;
+        let user = UserRepo::owner(&mut db_conn).await?;
+
+        let links: Vec<String> = super::relme::profile_links(&mut db_conn, 1).await?
+            .into_iter()
+            .map(|link| link.url)
+            .collect();
 
-        context.insert("name" , &row.name);
-        context.insert("username", &row.username);
-        context.insert("bio", &row.bio);
+        context.insert("name" , &user.name);
+        context.insert("username", &user.username);
+        context.insert("bio", &user.bio);
+        context.insert("links", &links.join("\n"));
         context.insert("csrf_token", &csrf_token);
 
         if let Some(m) = messages {
@@ -268,6 +618,24 @@ pub async fn user_profile_edit(req: Request<State>) -> tide::Result<Response> {
     }
 }
 
+/// Download an export archive of the whole account
+pub async fn user_export(req: Request<State>) -> tide::Result<Response> {
+    let logged_in = req.session().get::<bool>("logged_in").unwrap_or(false);
+
+    if !logged_in {
+        return Ok(Redirect::new("/user/login").into());
+    }
+
+    let data = super::archive::export(req.state()).await?;
+    let filename = format!("microbloggy-export-{}.tar", Utc::now().format("%Y-%m-%d"));
+
+    Ok(Response::builder(200)
+        .body(data)
+        .content_type("application/x-tar")
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+        .build())
+}
+
 /// Update user profile
 pub async fn user_profile_update(mut req: Request<State>) -> tide::Result<Response> {
     let state: &State = req.state();
@@ -284,7 +652,7 @@ pub async fn user_profile_update(mut req: Request<State>) -> tide::Result<Respon
                 .build()
         )
     } else {
-        let mut db_conn = state.sqlite_pool.acquire().await?;
+        let mut db_conn = state.db_pool.acquire().await?;
 
         let form_input: ProfileUpdateFormInput = req.body_form().await?;
 
@@ -292,14 +660,27 @@ pub async fn user_profile_update(mut req: Request<State>) -> tide::Result<Respon
          if form_input.csrf_token != csrf_token {
             Ok(tide::Response::builder(400).body("Invalid CSRF").build())
         } else {
-            sqlx::query!(
-                    "UPDATE users SET name=?1, bio=?2 WHERE rowid=?3",
-                    form_input.name,
-                    form_input.bio,
-                    1
-                )
-                .execute(&mut db_conn)
-                .await?;
+            let links = match super::relme::parse_links(&form_input.links) {
+                Ok(links) => links,
+                Err(message) => {
+                    req.session_mut().insert("messages", message).unwrap();
+
+                    return Ok(tide::Redirect::new("/user/profile/edit").into());
+                }
+            };
+
+            UserRepo::update_profile(&mut db_conn, 1, &form_input.name, &form_input.bio).await?;
+
+            super::relme::save_links(req.state(), 1, &links).await?;
+
+            // Check new links right away rather than on the next scheduled run
+            let state = req.state().clone();
+
+            async_std::task::spawn(async move {
+                if let Err(e) = super::relme::verify_links(&state).await {
+                    tide::log::error!("Failed to verify profile links", { error: e.to_string() });
+                }
+            });
 
             Ok(tide::Redirect::new("/user/profile").into())
         }
@@ -316,37 +697,44 @@ pub async fn post_view(req: Request<State>) -> tide::Result<Response> {
     let csrf_token = session.get::<String>("csrf_token").unwrap();
     let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
 
-    let mut db_conn = state.sqlite_pool.acquire().await?;
+    let mut db_conn = state.db_pool.acquire().await?;
     let post_id: i64 = req.param("post_id").unwrap().parse().unwrap();
 
-    let row = sqlx::query!(
-                r#"SELECT users.username, users.name, users.rowid AS user_id,
-                    posts.content, posts.posted_timestamp, posts.images, posts.short_url
-                FROM users, posts
-                WHERE users.rowid=posts.user_id AND posts.rowid=?"#,
-            post_id)
-        .fetch_one(&mut db_conn)
-        .await?;
+    let post = match PostRepo::find(&mut db_conn, post_id).await? {
+        Some(post) => post,
+        None => return Ok(Response::new(404))
+    };
+
+    if !post.is_visible(logged_in) {
+        return Ok(Response::new(404));
+    }
+
+    // Fediverse servers fetch the same URL for the post's Note
+    if super::activitypub::wants_activity_json(&req) {
+        if !super::activitypub::is_federated(&post) {
+            return Ok(Response::new(404));
+        }
 
-    // TODO: this will just panic if the post doesn't exist instead of 404ing
+        let mut note = super::activitypub::note(state, &post);
+        note["@context"] = json!("https://www.w3.org/ns/activitystreams");
+
+        return Ok(Response::builder(200)
+            .body(note.to_string())
+            .content_type(super::activitypub::CONTENT_TYPE)
+            .build());
+    }
+
+    let mentions = post_mentions(&mut db_conn, post_id, logged_in).await?;
+
+    let in_reply_to = PostRepo::in_reply_to(&mut db_conn, post_id).await?;
 
     let mut context = tera::Context::new();
 
     context.insert("csrf_token", &csrf_token);
     context.insert("logged_in", &logged_in);
-    context.insert(
-        "post",
-        &Post{
-            username: row.username,
-            name: row.name,
-            user_id: row.user_id.unwrap(),
-            post_id: post_id,
-            content: row.content,
-            posted_timestamp: row.posted_timestamp,
-            short_url: row.short_url,
-            images: serde_json::from_str(row.images.as_str()).unwrap(),
-        }
-    );
+    context.insert("post", &post);
+    context.insert("mentions", &mentions);
+    context.insert("in_reply_to", &in_reply_to);
 
     if let Some(m) = messages {
         context.insert("messages", &m.messages);
@@ -365,37 +753,29 @@ pub async fn post_view_share(req: Request<State>) -> tide::Result<Response> {
     let csrf_token = session.get::<String>("csrf_token").unwrap();
     let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
 
-    let mut db_conn = state.sqlite_pool.acquire().await?;
+    let mut db_conn = state.db_pool.acquire().await?;
     let short_url: String = req.param("short_url").unwrap().to_string();
 
-    let row = sqlx::query!(
-                r#"SELECT users.username, users.name, users.rowid AS user_id,
-                    posts.content, posts.rowid as post_id, posts.posted_timestamp, posts.images, posts.short_url
-                FROM users, posts
-                WHERE users.rowid=posts.user_id AND posts.short_url=?"#,
-            short_url)
-        .fetch_one(&mut db_conn)
-        .await?;
+    let post = match PostRepo::find_by_short_url(&mut db_conn, &short_url).await? {
+        Some(post) => post,
+        None => return Ok(Response::new(404))
+    };
 
-    // TODO: this will just panic if the post doesn't exist instead of 404ing
+    // Shared links to trashed posts are gone for good, not just hidden
+    if post.deleted_timestamp.is_some() {
+        return Ok(Response::new(410));
+    }
+
+    // Scheduled and private posts are only visible to the author
+    if !post.is_visible(logged_in) {
+        return Ok(Response::new(404));
+    }
 
     let mut context = tera::Context::new();
 
     context.insert("csrf_token", &csrf_token);
     context.insert("logged_in", &logged_in);
-    context.insert(
-        "post",
-        &Post{
-            username: row.username,
-            name: row.name,
-            user_id: row.user_id.unwrap(),
-            post_id: row.post_id.unwrap(),
-            content: row.content,
-            posted_timestamp: row.posted_timestamp,
-            short_url: row.short_url,
-            images: serde_json::from_str(row.images.as_str()).unwrap(),
-        }
-    );
+    context.insert("post", &post);
 
     if let Some(m) = messages {
         context.insert("messages", &m.messages);
@@ -416,65 +796,105 @@ pub async fn post_create(mut req: Request<State>) -> tide::Result<Response> {
     if !logged_in {
         Ok(Redirect::new("/").into())
     } else {
-        let mut db_conn = (&req.state()).sqlite_pool.acquire().await?;
-
         let form_input: PostFormInput = req.body_form().await?;
 
         // Validate CSRF token
         if form_input.csrf_token != csrf_token {
             Ok(Redirect::new("/").into())
         } else {
-            // Query for draft images
-            let result = sqlx::query!(
-                    "SELECT image_thumbnail_path, image_medium_path, image_full_path FROM image_drafts"
-                )
-                .fetch_all(&mut db_conn)
-                .await?;
-
-            let draft_images: Vec<Image> = result.into_iter().map(|row| {
-                Image {
-                    full_path: row.image_full_path.unwrap(),
-                    medium_path: row.image_medium_path.unwrap(),
-                    thumbnail_path: row.image_thumbnail_path.unwrap()
+            // An empty publish time means publish immediately
+            let publish_at = match form_input.publish_at.as_deref().map(str::trim) {
+                None | Some("") => None,
+                Some(value) => match super::scheduler::parse_publish_at(value) {
+                    Some(timestamp) => Some(timestamp),
+                    None => {
+                        req.session_mut().insert(
+                            "messages",
+                            "Invalid publish time - the post was not created.".to_string()
+                        ).unwrap();
+
+                        return Ok(Redirect::new("/").into());
+                    }
                 }
-            }).collect();
+            };
 
-            let now = Utc::now().to_rfc3339();
+            create_post(req.state(), &form_input.content, publish_at, form_input.visibility).await?;
 
-            // TODO: hardcoded user id of 1 should be dynamic, probably
-            let post_images: String = serde_json::to_string(&draft_images).unwrap();
+            if publish_at.is_some_and(|publish_at| publish_at > Utc::now()) {
+                Ok(Redirect::new("/post/scheduled").into())
+            } else {
+                let response: Response = Redirect::new("/").into();
 
-            sqlx::query!(
-                    "INSERT INTO posts (user_id, content, posted_timestamp, images) VALUES (?, ?, ?, ?)",
-                    1,
-                    form_input.content,
-                    now,
-                    post_images
-                ).execute(&mut db_conn)
-                .await?;
+                Ok(response)
+            }
+        }
+    }
+}
+
+/// Everything needed to create a post
+pub struct NewPost<'a> {
+    pub content: &'a str,
+    pub short_url: Option<&'a str>,
+    pub publish_at: Option<DateTime<Utc>>,
+    pub visibility: Visibility,
+    pub images: Vec<Image>
+}
+
+/// Create a post with the current draft images attached. Posts with a publish
+/// time in the future are held back until the scheduler publishes them.
+pub async fn create_post(
+    state: &State,
+    content: &str,
+    publish_at: Option<DateTime<Utc>>,
+    visibility: Visibility
+) -> tide::Result<i64> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let draft_images = MediaRepo::drafts(&mut db_conn).await?;
+
+    let post_id = insert_post(state, NewPost {
+        content,
+        short_url: None,
+        publish_at,
+        visibility,
+        images: draft_images
+    }).await?;
+
+    MediaRepo::clear_drafts(&mut db_conn).await?;
+
+    Ok(post_id)
+}
 
-            sqlx::query!("DELETE FROM image_drafts")
-                .execute(&mut db_conn)
-                .await?;
+/// Store a new post, and let the scheduler know if it went live right away
+pub async fn insert_post(state: &State, post: NewPost<'_>) -> tide::Result<i64> {
+    let mut db_conn = state.db_pool.acquire().await?;
 
-            let response: Response = Redirect::new("/").into();
+    let now = Utc::now();
+    let publish_at = post.publish_at.unwrap_or(now);
 
-            Ok(response)
-        }
+    let scheduled = publish_at > now;
+    let posted_timestamp = if scheduled { publish_at } else { now }.to_rfc3339();
+    let published = !scheduled;
+
+    // TODO: hardcoded user id of 1 should be dynamic, probably
+    let post_id = PostRepo::insert(&mut db_conn, &post, &posted_timestamp, published).await?;
+
+    if published {
+        super::scheduler::post_published(state, post_id).await?;
     }
+
+    Ok(post_id)
 }
 
 /// Edit a post
 pub async fn post_edit(mut req: Request<State>) -> tide::Result<Response> {
-    let state = req.state();
     let session = req.session();
 
     let csrf_token = session.get::<String>("csrf_token").unwrap();
     let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
 
-    let mut db_conn = state.sqlite_pool.acquire().await?;
     let form_input: PostEditFormInput = req.body_form().await?;
-    let post_id = req.param("post_id").unwrap();
+    let post_id: i64 = req.param("post_id")?.parse()?;
 
     if !logged_in {
         Ok(tide::Response::builder(400).body("Forbidden").build())
@@ -489,14 +909,14 @@ pub async fn post_edit(mut req: Request<State>) -> tide::Result<Response> {
                 false => Some(form_input.short_url)
             };
 
-            sqlx::query!(
-                    "UPDATE posts SET content=?, short_url=? WHERE rowid=?",
-                    form_input.content,
-                    short_url,
-                    post_id
-                )
-                .execute(&mut db_conn)
-                .await?;
+            let mut tx = req.state().db_pool.begin().await?;
+
+            PostRepo::revise(&mut tx, post_id, &form_input.content, &short_url).await?;
+            PostRepo::set_visibility(&mut tx, post_id, form_input.visibility.as_str()).await?;
+
+            tx.commit().await?;
+
+            super::scheduler::post_edited(req.state(), post_id).await?;
 
             Ok(
                 tide::Redirect::new(
@@ -516,9 +936,9 @@ pub async fn post_delete(mut req: Request<State>) -> tide::Result<Response> {
     let csrf_token = session.get::<String>("csrf_token").unwrap();
     let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
 
-    let mut db_conn = state.sqlite_pool.acquire().await?;
+    let mut db_conn = state.db_pool.acquire().await?;
     let form_input: PostDeleteFormInput = req.body_form().await?;
-    let post_id = req.param("post_id").unwrap();
+    let post_id: i64 = req.param("post_id")?.parse()?;
 
     if !logged_in {
         Ok(tide::Response::builder(400).body("Forbidden").build())
@@ -527,12 +947,14 @@ pub async fn post_delete(mut req: Request<State>) -> tide::Result<Response> {
         if form_input.csrf_token != csrf_token {
             Ok(tide::Response::builder(400).body("Invalid CSRF").build())
         } else {
-            sqlx::query!(
-                    "DELETE FROM posts WHERE rowid=?",
-                    post_id
-                )
-                .execute(&mut db_conn)
-                .await?;
+            if PostRepo::trash(&mut db_conn, post_id).await? {
+                super::scheduler::post_trashed(req.state(), post_id).await?;
+            }
+
+            req.session_mut().insert(
+                "messages",
+                "Post moved to the trash.".to_string()
+            ).unwrap();
 
             Ok(tide::Redirect::new("/").into())
         }
@@ -541,56 +963,15 @@ pub async fn post_delete(mut req: Request<State>) -> tide::Result<Response> {
 
 /// Upload an image
 pub async fn put_image_upload(req: Request<State>) -> tide::Result<Response> {
-    let state = req.state();
     let session = req.session();
 
-    let uploads_path = &state.config.uploads_path;
     let _csrf_token = session.get::<String>("csrf_token").unwrap();
     let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
 
     if logged_in {
-        let mut db_conn = req.state().sqlite_pool.acquire().await?;
-
-        let image_id = rand::random::<u64>();
-
-        let image_resizer = super::images::GmImageConvert::new(
-            state.config.graphicsmagick_path.to_str().unwrap().to_string()
-        );
-
-        let filename_original = format!("{}", image_id);
-        let filename_full = format!("{}_full.jpg", image_id);
-        let filename_medium = format!("{}_medium.jpg", image_id);
-        let filename_thumbnail = format!("{}_thumbnail.jpg", image_id);
-
-        let path_original = uploads_path.join(&filename_original);
-        let path_full = uploads_path.join(&filename_full);
-        let path_medium = uploads_path.join(&filename_medium);
-        let path_thumbnail = uploads_path.join(&filename_thumbnail);
+        let state = req.state().clone();
 
-        {
-            let file = async_std::fs::File::create(
-                uploads_path.join(&filename_original)
-            ).await?;
-
-            async_std::io::copy(req, file).await?;
-        }
-
-        // Generate resized images
-        image_resizer.convert_image(path_original.as_path(), path_full.as_path()).await?;
-        image_resizer.thumbnail_image(path_full.as_path(), path_medium.as_path(), 600, 600).await?;
-        image_resizer.thumbnail_image(path_medium.as_path(), path_thumbnail.as_path(), 120, 120).await?;
-
-        sqlx::query!(
-                r#"INSERT INTO image_drafts
-                    (image_thumbnail_path, image_medium_path, image_full_path)
-                    VALUES (?, ?, ?)"#,
-                filename_thumbnail,
-                filename_medium,
-                filename_full
-            ).execute(&mut db_conn)
-            .await?;
-
-        async_std::fs::remove_file(path_original.as_path()).await?;
+        save_image_upload(&state, req).await?;
 
         Ok(
             tide::Response::builder(200)
@@ -605,3 +986,662 @@ pub async fn put_image_upload(req: Request<State>) -> tide::Result<Response> {
         )
     }
 }
+
+/// Resize an uploaded image and keep it as a draft for the next post
+pub async fn save_image_upload<R>(state: &State, reader: R) -> tide::Result<Image>
+where
+    R: async_std::io::Read + Unpin
+{
+    let image = resize_image(state, reader).await?;
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    MediaRepo::add_draft(&mut db_conn, &image).await?;
+
+    Ok(image)
+}
+
+/// Store an image in the uploads directory at the sizes posts show it at
+pub async fn resize_image<R>(state: &State, reader: R) -> tide::Result<Image>
+where
+    R: async_std::io::Read + Unpin
+{
+    let uploads_path = &state.config.uploads_path;
+
+    let image_id = rand::random::<u64>();
+
+    let image_resizer = super::images::GmImageConvert::new(
+        state.config.graphicsmagick_path.to_str().unwrap().to_string()
+    );
+
+    let filename_original = format!("{}", image_id);
+    let filename_full = format!("{}_full.jpg", image_id);
+    let filename_medium = format!("{}_medium.jpg", image_id);
+    let filename_thumbnail = format!("{}_thumbnail.jpg", image_id);
+
+    let path_original = uploads_path.join(&filename_original);
+    let path_full = uploads_path.join(&filename_full);
+    let path_medium = uploads_path.join(&filename_medium);
+    let path_thumbnail = uploads_path.join(&filename_thumbnail);
+
+    {
+        let file = async_std::fs::File::create(
+            uploads_path.join(&filename_original)
+        ).await?;
+
+        async_std::io::copy(reader, file).await?;
+    }
+
+    // Generate resized images
+    let started = std::time::Instant::now();
+
+    let resized = async {
+        image_resizer.convert_image(path_original.as_path(), path_full.as_path()).await?;
+        image_resizer.thumbnail_image(path_full.as_path(), path_medium.as_path(), 600, 600).await?;
+        image_resizer.thumbnail_image(path_medium.as_path(), path_thumbnail.as_path(), 120, 120).await
+    }.await;
+
+    state.metrics.observe_upload(started.elapsed(), resized.is_ok());
+    resized?;
+
+    async_std::fs::remove_file(path_original.as_path()).await?;
+
+    Ok(Image {
+        full_path: filename_full,
+        medium_path: filename_medium,
+        thumbnail_path: filename_thumbnail
+    })
+}
+
+/// List scheduled posts that haven't been published yet
+pub async fn post_scheduled(req: Request<State>) -> tide::Result<Response> {
+    let state = req.state();
+    let session = req.session();
+    let tera = &state.tera;
+    let messages: Option<&MessageFlashes> = req.ext();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    if !logged_in {
+        return Ok(Redirect::new("/user/login").into());
+    }
+
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let posts = PostRepo::scheduled(&mut db_conn).await?;
+
+    let mut context = tera::Context::new();
+
+    context.insert("posts", &posts);
+    context.insert("logged_in", &logged_in);
+    context.insert("csrf_token", &csrf_token);
+
+    if let Some(m) = messages {
+        context.insert("messages", &m.messages);
+    }
+
+    tera.render_response("scheduled.html", &context)
+}
+
+/// Move a scheduled post to a different publish time
+pub async fn post_reschedule(mut req: Request<State>) -> tide::Result<Response> {
+    let session = req.session();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let form_input: PostRescheduleFormInput = req.body_form().await?;
+    let post_id: i64 = req.param("post_id")?.parse()?;
+
+    if !logged_in {
+        Ok(tide::Response::builder(400).body("Forbidden").build())
+    } else if form_input.csrf_token != csrf_token {
+        Ok(tide::Response::builder(400).body("Invalid CSRF").build())
+    } else {
+        let publish_at = match super::scheduler::parse_publish_at(&form_input.publish_at) {
+            Some(timestamp) if timestamp > Utc::now() => timestamp.to_rfc3339(),
+            _ => {
+                req.session_mut().insert(
+                    "messages",
+                    "Scheduled posts need a publish time in the future.".to_string()
+                ).unwrap();
+
+                return Ok(Redirect::new("/post/scheduled").into());
+            }
+        };
+
+        let mut db_conn = req.state().db_pool.acquire().await?;
+
+        PostRepo::reschedule(&mut db_conn, post_id, &publish_at).await?;
+
+        Ok(Redirect::new("/post/scheduled").into())
+    }
+}
+
+/// Publish a scheduled post right away
+pub async fn post_publish(mut req: Request<State>) -> tide::Result<Response> {
+    let session = req.session();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let form_input: PostPublishFormInput = req.body_form().await?;
+    let post_id: i64 = req.param("post_id")?.parse()?;
+
+    if !logged_in {
+        Ok(tide::Response::builder(400).body("Forbidden").build())
+    } else if form_input.csrf_token != csrf_token {
+        Ok(tide::Response::builder(400).body("Invalid CSRF").build())
+    } else {
+        let state = req.state();
+        let mut db_conn = state.db_pool.acquire().await?;
+        let now = Utc::now().to_rfc3339();
+
+        if PostRepo::publish(&mut db_conn, post_id, &now).await? {
+            super::scheduler::post_published(state, post_id).await?;
+        }
+
+        Ok(Redirect::new(format!("/post/view/{}", post_id)).into())
+    }
+}
+
+/// List every stored version of a post
+pub async fn post_history(req: Request<State>) -> tide::Result<Response> {
+    let state = req.state();
+    let session = req.session();
+    let tera = &state.tera;
+    let messages: Option<&MessageFlashes> = req.ext();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let mut db_conn = state.db_pool.acquire().await?;
+    let post_id: i64 = req.param("post_id")?.parse()?;
+
+    let post = match PostRepo::find(&mut db_conn, post_id).await? {
+        Some(post) if post.is_visible(logged_in) => post,
+        _ => return Ok(Response::new(404))
+    };
+
+    let mut revisions: Vec<Revision> = PostRepo::revisions(&mut db_conn, post_id).await?
+        .into_iter()
+        .map(|revision| {
+            Revision {
+                revision_id: Some(revision.revision_id),
+                content: revision.content,
+                short_url: revision.short_url,
+                revised_timestamp: Some(revision.revised_timestamp)
+            }
+        })
+        .collect();
+
+    revisions.push(Revision {
+        revision_id: None,
+        content: post.content,
+        short_url: post.short_url,
+        revised_timestamp: None
+    });
+
+    // Newest first, each paired with the version it replaced for diff links
+    let mut versions = Vec::new();
+
+    for (index, revision) in revisions.iter().enumerate().rev() {
+        let previous = match index {
+            0 => None,
+            _ => revisions[index - 1].revision_id
+        };
+
+        versions.push(json!({
+            "number": index + 1,
+            "revision": revision,
+            "previous_revision_id": previous
+        }));
+    }
+
+    let mut context = tera::Context::new();
+
+    context.insert("post_id", &post_id);
+    context.insert("edited_timestamp", &post.edited_timestamp);
+    context.insert("versions", &versions);
+    context.insert("csrf_token", &csrf_token);
+    context.insert("logged_in", &logged_in);
+
+    if let Some(m) = messages {
+        context.insert("messages", &m.messages);
+    }
+
+    tera.render_response("post_history.html", &context)
+}
+
+/// Show a line diff between two versions of a post. `from` defaults to the
+/// most recent revision and `to` defaults to the current version.
+pub async fn post_diff(req: Request<State>) -> tide::Result<Response> {
+    let state = req.state();
+    let session = req.session();
+    let tera = &state.tera;
+
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let mut db_conn = state.db_pool.acquire().await?;
+    let post_id: i64 = req.param("post_id")?.parse()?;
+    let query: DiffQuery = req.query()?;
+
+    let content = match PostRepo::find(&mut db_conn, post_id).await? {
+        Some(post) if post.is_visible(logged_in) => post.content,
+        _ => return Ok(Response::new(404))
+    };
+
+    // Without a `from` this diffs against the most recent revision
+    let from = PostRepo::revision(&mut db_conn, post_id, query.from).await?
+        .map(|revision| revision.content);
+
+    let to = match query.to {
+        Some(revision_id) => PostRepo::revision(&mut db_conn, post_id, Some(revision_id)).await?
+            .map(|revision| revision.content),
+        None => Some(content)
+    };
+
+    let (from, to) = match (from, to) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Ok(Response::new(404))
+    };
+
+    let diff_lines: Vec<DiffLine> = similar::TextDiff::from_lines(&from, &to)
+        .iter_all_changes()
+        .map(|change| {
+            DiffLine {
+                kind: match change.tag() {
+                    similar::ChangeTag::Delete => "delete",
+                    similar::ChangeTag::Insert => "insert",
+                    similar::ChangeTag::Equal => "equal"
+                },
+                text: change.value().trim_end_matches('\n').to_string()
+            }
+        })
+        .collect();
+
+    let mut context = tera::Context::new();
+
+    context.insert("post_id", &post_id);
+    context.insert("diff_lines", &diff_lines);
+    context.insert("logged_in", &logged_in);
+
+    tera.render_response("post_diff.html", &context)
+}
+
+/// Restore an older revision of a post. The version it replaces is kept as a
+/// revision too, so restoring can always be undone.
+pub async fn post_restore(mut req: Request<State>) -> tide::Result<Response> {
+    let session = req.session();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let form_input: PostRestoreFormInput = req.body_form().await?;
+    let post_id: i64 = req.param("post_id")?.parse()?;
+    let revision_id: i64 = req.param("revision_id")?.parse()?;
+
+    if !logged_in {
+        return Ok(tide::Response::builder(400).body("Forbidden").build());
+    } else if form_input.csrf_token != csrf_token {
+        return Ok(tide::Response::builder(400).body("Invalid CSRF").build());
+    }
+
+    let mut tx = req.state().db_pool.begin().await?;
+
+    let revision = match PostRepo::revision(&mut tx, post_id, Some(revision_id)).await? {
+        Some(revision) => revision,
+        None => return Ok(Response::new(404))
+    };
+
+    // The old short URL may have been given to another post since
+    let short_url = match &revision.short_url {
+        Some(old_short_url) if PostRepo::short_url_taken(&mut tx, old_short_url, post_id).await? => {
+            req.session_mut().insert(
+                "messages",
+                "Restored the post, but its old short URL is now used by another post.".to_string()
+            ).unwrap();
+
+            PostRepo::find(&mut tx, post_id).await?.and_then(|post| post.short_url)
+        },
+        _ => revision.short_url
+    };
+
+    PostRepo::revise(&mut tx, post_id, &revision.content, &short_url).await?;
+
+    tx.commit().await?;
+
+    super::scheduler::post_edited(req.state(), post_id).await?;
+
+    Ok(Redirect::new(format!("/post/view/{}", post_id)).into())
+}
+
+/// List posts in the trash
+pub async fn post_trash(req: Request<State>) -> tide::Result<Response> {
+    let state = req.state();
+    let session = req.session();
+    let tera = &state.tera;
+    let messages: Option<&MessageFlashes> = req.ext();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    if !logged_in {
+        return Ok(Redirect::new("/user/login").into());
+    }
+
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let posts = PostRepo::trashed(&mut db_conn).await?;
+
+    let mut context = tera::Context::new();
+
+    context.insert("posts", &posts);
+    context.insert("logged_in", &logged_in);
+    context.insert("csrf_token", &csrf_token);
+    context.insert("trash_retention_days", &state.config.trash_retention_days);
+
+    if let Some(m) = messages {
+        context.insert("messages", &m.messages);
+    }
+
+    tera.render_response("trash.html", &context)
+}
+
+/// Move a post out of the trash
+pub async fn post_untrash(mut req: Request<State>) -> tide::Result<Response> {
+    let session = req.session();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let form_input: PostUntrashFormInput = req.body_form().await?;
+    let post_id: i64 = req.param("post_id")?.parse()?;
+
+    if !logged_in {
+        Ok(tide::Response::builder(400).body("Forbidden").build())
+    } else if form_input.csrf_token != csrf_token {
+        Ok(tide::Response::builder(400).body("Invalid CSRF").build())
+    } else {
+        let mut db_conn = req.state().db_pool.acquire().await?;
+
+        PostRepo::untrash(&mut db_conn, post_id).await?;
+
+        Ok(Redirect::new(format!("/post/view/{}", post_id)).into())
+    }
+}
+
+/// Permanently delete a post that is already in the trash
+pub async fn post_purge(mut req: Request<State>) -> tide::Result<Response> {
+    let session = req.session();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let form_input: PostPurgeFormInput = req.body_form().await?;
+    let post_id: i64 = req.param("post_id")?.parse()?;
+
+    if !logged_in {
+        Ok(tide::Response::builder(400).body("Forbidden").build())
+    } else if form_input.csrf_token != csrf_token {
+        Ok(tide::Response::builder(400).body("Invalid CSRF").build())
+    } else {
+        super::trash::purge_post(req.state(), post_id).await?;
+
+        Ok(Redirect::new("/post/trash").into())
+    }
+}
+
+/// Pin a post above the timeline, or unpin it
+pub async fn post_pin(mut req: Request<State>) -> tide::Result<Response> {
+    let session = req.session();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let form_input: PostPinFormInput = req.body_form().await?;
+    let post_id: i64 = req.param("post_id")?.parse()?;
+
+    if !logged_in {
+        Ok(tide::Response::builder(400).body("Forbidden").build())
+    } else if form_input.csrf_token != csrf_token {
+        Ok(tide::Response::builder(400).body("Invalid CSRF").build())
+    } else {
+        let mut db_conn = req.state().db_pool.acquire().await?;
+
+        let pinned_timestamp = match form_input.pinned {
+            true => Some(Utc::now().to_rfc3339()),
+            false => None
+        };
+
+        PostRepo::set_pinned(&mut db_conn, post_id, pinned_timestamp.as_deref()).await?;
+
+        Ok(Redirect::new(format!("/post/view/{}", post_id)).into())
+    }
+}
+
+/// Number of posts in one month of the archive
+#[derive(Serialize)]
+pub struct ArchiveMonth {
+    month: u32,
+    name: String,
+    count: i64
+}
+
+#[derive(Serialize)]
+pub struct ArchiveYear {
+    year: i32,
+    count: i64,
+    months: Vec<ArchiveMonth>
+}
+
+/// Posts made on today's date in an earlier year
+#[derive(Serialize)]
+pub struct OnThisDayYear {
+    year: i32,
+    years_ago: i32,
+    posts: Vec<Post>
+}
+
+/// The UTC instant a local date starts at in the site's timezone
+fn local_midnight(timezone: &chrono_tz::Tz, date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_hms(0, 0, 0);
+
+    // A few zones skip midnight when daylight saving starts, in which case
+    // the day begins an hour later
+    let start = timezone.from_local_datetime(&midnight).earliest()
+        .or_else(|| timezone.from_local_datetime(&(midnight + chrono::Duration::hours(1))).earliest())
+        .unwrap();
+
+    start.with_timezone(&Utc)
+}
+
+/// Post counts for every local (year, month) that has visible posts
+async fn archive_counts(
+    db_conn: &mut sqlx::AnyConnection,
+    logged_in: bool,
+    timezone: &chrono_tz::Tz
+) -> tide::Result<std::collections::BTreeMap<(i32, u32), i64>> {
+    let posted_timestamps = PostRepo::posted_timestamps(db_conn, logged_in).await?;
+
+    // Months are bucketed here rather than in SQL, since SQLite knows nothing
+    // about the site's timezone
+    let mut counts = std::collections::BTreeMap::new();
+
+    for posted_timestamp in posted_timestamps {
+        if let Ok(timestamp) = DateTime::parse_from_rfc3339(&posted_timestamp) {
+            let local = timestamp.with_timezone(timezone);
+
+            *counts.entry((local.year(), local.month())).or_insert(0) += 1;
+        }
+    }
+
+    Ok(counts)
+}
+
+/// List the years and months that have posts
+pub async fn archive(req: Request<State>) -> tide::Result<Response> {
+    let state = req.state();
+    let session = req.session();
+    let messages: Option<&MessageFlashes> = req.ext();
+    let tera = &state.tera;
+
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let mut db_conn = state.db_pool.acquire().await?;
+    let mut context = tera::Context::new();
+
+    let counts = archive_counts(&mut db_conn, logged_in, &state.config.timezone).await?;
+
+    // Newest year first, and newest month first within it
+    let mut years: Vec<ArchiveYear> = Vec::new();
+
+    for ((year, month), count) in counts.into_iter().rev() {
+        let archive_month = ArchiveMonth {
+            month,
+            name: NaiveDate::from_ymd(year, month, 1).format("%B").to_string(),
+            count
+        };
+
+        match years.last_mut() {
+            Some(archive_year) if archive_year.year == year => {
+                archive_year.count += count;
+                archive_year.months.push(archive_month);
+            },
+            _ => years.push(ArchiveYear {
+                year,
+                count,
+                months: vec![archive_month]
+            })
+        }
+    }
+
+    context.insert("years", &years);
+    context.insert("logged_in", &logged_in);
+
+    if let Some(m) = messages {
+        context.insert("messages", &m.messages);
+    }
+
+    tera.render_response("archive.html", &context)
+}
+
+/// Every post from one month, rendered like the index
+pub async fn archive_month(req: Request<State>) -> tide::Result<Response> {
+    let state = req.state();
+    let session = req.session();
+    let messages: Option<&MessageFlashes> = req.ext();
+    let tera = &state.tera;
+    let timezone = &state.config.timezone;
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let year: Option<i32> = req.param("year")?.parse().ok();
+    let month: Option<u32> = req.param("month")?.parse().ok();
+
+    let first_day = match (year, month) {
+        (Some(year), Some(month)) => NaiveDate::from_ymd_opt(year, month, 1),
+        _ => None
+    };
+
+    let first_day = match first_day {
+        Some(first_day) => first_day,
+        None => return Ok(tide::Response::builder(404).body("Not found").build())
+    };
+
+    let next_month = match first_day.month() {
+        12 => NaiveDate::from_ymd(first_day.year() + 1, 1, 1),
+        month => NaiveDate::from_ymd(first_day.year(), month + 1, 1)
+    };
+
+    let mut db_conn = state.db_pool.acquire().await?;
+    let mut context = tera::Context::new();
+
+    let posts = PostRepo::between(
+        &mut db_conn,
+        logged_in,
+        &local_midnight(timezone, first_day),
+        &local_midnight(timezone, next_month)
+    ).await?;
+
+    let pinned_posts: Vec<Post> = Vec::new();
+    let draft_images: Vec<Image> = Vec::new();
+    let no_cursor: Option<String> = None;
+
+    context.insert("archive_heading", &first_day.format("%B %Y").to_string());
+    context.insert("pinned_posts", &pinned_posts);
+    context.insert("posts", &posts);
+    context.insert("draft_images", &draft_images);
+    context.insert("logged_in", &logged_in);
+    context.insert("csrf_token", &csrf_token);
+    context.insert("newer_cursor", &no_cursor);
+    context.insert("older_cursor", &no_cursor);
+
+    if let Some(m) = messages {
+        context.insert("messages", &m.messages);
+    }
+
+    tera.render_response("index.html", &context)
+}
+
+/// Posts from today's date in earlier years, by the site's timezone
+pub async fn archive_on_this_day(req: Request<State>) -> tide::Result<Response> {
+    let state = req.state();
+    let session = req.session();
+    let messages: Option<&MessageFlashes> = req.ext();
+    let tera = &state.tera;
+    let timezone = &state.config.timezone;
+
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let mut db_conn = state.db_pool.acquire().await?;
+    let mut context = tera::Context::new();
+
+    let today = Utc::now().with_timezone(timezone).date().naive_local();
+
+    let counts = archive_counts(&mut db_conn, logged_in, timezone).await?;
+
+    // Only look at years that have posts in this month at all
+    let mut years: Vec<i32> = counts.keys()
+        .filter(|(year, month)| *year < today.year() && *month == today.month())
+        .map(|(year, _)| *year)
+        .collect();
+
+    years.reverse();
+
+    let mut on_this_day = Vec::new();
+
+    for year in years {
+        // February 29th only comes around in leap years
+        let date = match NaiveDate::from_ymd_opt(year, today.month(), today.day()) {
+            Some(date) => date,
+            None => continue
+        };
+
+        let posts = PostRepo::between(
+            &mut db_conn,
+            logged_in,
+            &local_midnight(timezone, date),
+            &local_midnight(timezone, date.succ())
+        ).await?;
+
+        if !posts.is_empty() {
+            on_this_day.push(OnThisDayYear {
+                year,
+                years_ago: today.year() - year,
+                posts
+            });
+        }
+    }
+
+    context.insert("today", &today.format("%B %-d").to_string());
+    context.insert("years", &on_this_day);
+    context.insert("logged_in", &logged_in);
+
+    if let Some(m) = messages {
+        context.insert("messages", &m.messages);
+    }
+
+    tera.render_response("on_this_day.html", &context)
+}
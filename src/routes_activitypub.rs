@@ -0,0 +1,282 @@
+use super::State;
+use super::activitypub::{self, CONTENT_TYPE};
+use super::repo::{PostRepo, User, UserRepo};
+
+use chrono::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tide::{Request, Response, Result};
+
+// ActivityPub (https://www.w3.org/TR/activitypub/) lets fediverse users
+// follow the blog. Each user is an actor found through WebFinger; posts go
+// out to followers from the delivery queue in activitypub.rs.
+
+/// How many of the latest posts the outbox lists
+const OUTBOX_SIZE: i64 = 20;
+
+#[derive(Deserialize)]
+pub struct WebfingerQuery {
+    resource: Option<String>
+}
+
+fn activity_response(status: u16, body: &Value) -> Response {
+    Response::builder(status)
+        .body(body.to_string())
+        .content_type(CONTENT_TYPE)
+        .build()
+}
+
+async fn find_user(state: &State, username: &str) -> Result<Option<User>> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    UserRepo::find_by_username(&mut db_conn, username).await
+}
+
+/// Resolve acct:user@host (or an actor URL) to the actor
+pub async fn webfinger(req: Request<State>) -> Result<Response> {
+    let state = req.state();
+    let query: WebfingerQuery = req.query()?;
+
+    let resource = match query.resource {
+        Some(resource) => resource,
+        None => return Ok(Response::builder(400).body("A resource is required").build())
+    };
+
+    let actor_prefix = format!("{}/users/", state.config.site_url);
+
+    let username = match resource.strip_prefix("acct:").and_then(|acct| acct.rsplit_once('@')) {
+        Some((username, domain)) if domain == activitypub::domain(state) => username.to_string(),
+        Some(_) => return Ok(Response::new(404)),
+        None => match resource.strip_prefix(&actor_prefix) {
+            Some(username) => username.to_string(),
+            None => return Ok(Response::new(404))
+        }
+    };
+
+    if find_user(state, &username).await?.is_none() {
+        return Ok(Response::new(404));
+    }
+
+    let body = json!({
+        "subject": format!("acct:{}@{}", username, activitypub::domain(state)),
+        "aliases": [activitypub::actor_url(state, &username)],
+        "links": [
+            {
+                "rel": "self",
+                "type": CONTENT_TYPE,
+                "href": activitypub::actor_url(state, &username)
+            },
+            {
+                "rel": "http://webfinger.net/rel/profile-page",
+                "type": "text/html",
+                "href": format!("{}/user/profile", state.config.site_url)
+            }
+        ]
+    });
+
+    Ok(Response::builder(200)
+        .body(body.to_string())
+        .content_type("application/jrd+json")
+        .header("Access-Control-Allow-Origin", "*")
+        .build())
+}
+
+/// A user's actor document, including the key their activities are signed with
+pub async fn actor(req: Request<State>) -> Result<Response> {
+    let state = req.state();
+    let username = req.param("username")?;
+
+    let user = match find_user(state, username).await? {
+        Some(user) => user,
+        None => return Ok(Response::new(404))
+    };
+
+    let (_, public_key) = activitypub::user_key(state, user.user_id).await?;
+    let actor_url = activitypub::actor_url(state, username);
+
+    let body = json!({
+        "@context": ["https://www.w3.org/ns/activitystreams", "https://w3id.org/security/v1"],
+        "id": actor_url,
+        "type": "Person",
+        "preferredUsername": username,
+        "name": user.name,
+        "summary": user.bio,
+        "url": format!("{}/user/profile", state.config.site_url),
+        "inbox": format!("{}/inbox", actor_url),
+        "outbox": format!("{}/outbox", actor_url),
+        "followers": format!("{}/followers", actor_url),
+        "publicKey": {
+            "id": format!("{}#main-key", actor_url),
+            "owner": actor_url,
+            "publicKeyPem": public_key
+        }
+    });
+
+    Ok(activity_response(200, &body))
+}
+
+/// The user's latest federated posts, as Create activities
+pub async fn outbox(req: Request<State>) -> Result<Response> {
+    let state = req.state();
+    let username = req.param("username")?;
+
+    let user_id = match find_user(state, username).await? {
+        Some(user) => user.user_id,
+        None => return Ok(Response::new(404))
+    };
+
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let (total,): (i64,) = sqlx::query_as(
+            r#"SELECT COUNT(*) AS total FROM posts
+            WHERE user_id=$1 AND published=1 AND deleted_timestamp IS NULL AND visibility != 'private'"#
+        )
+        .bind(user_id)
+        .fetch_one(&mut db_conn)
+        .await?;
+
+    let rows: Vec<(i64,)> = sqlx::query_as(
+            r#"SELECT rowid AS post_id FROM posts
+            WHERE user_id=$1 AND published=1 AND deleted_timestamp IS NULL AND visibility != 'private'
+            ORDER BY posted_timestamp DESC LIMIT $2"#
+        )
+        .bind(user_id)
+        .bind(OUTBOX_SIZE)
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    let mut items = Vec::new();
+
+    for (post_id,) in rows {
+        if let Some(post) = PostRepo::find(&mut db_conn, post_id).await? {
+            items.push(activitypub::activity(state, &post, "Create"));
+        }
+    }
+
+    let body = json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{}/outbox", activitypub::actor_url(state, username)),
+        "type": "OrderedCollection",
+        "totalItems": total,
+        "orderedItems": items
+    });
+
+    Ok(activity_response(200, &body))
+}
+
+/// How many followers a user has. Who they are isn't published.
+pub async fn followers(req: Request<State>) -> Result<Response> {
+    let state = req.state();
+    let username = req.param("username")?;
+
+    let user_id = match find_user(state, username).await? {
+        Some(user) => user.user_id,
+        None => return Ok(Response::new(404))
+    };
+
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let (total,): (i64,) = sqlx::query_as("SELECT COUNT(*) AS total FROM activitypub_followers WHERE user_id=$1")
+        .bind(user_id)
+        .fetch_one(&mut db_conn)
+        .await?;
+
+    let body = json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{}/followers", activitypub::actor_url(state, username)),
+        "type": "OrderedCollection",
+        "totalItems": total
+    });
+
+    Ok(activity_response(200, &body))
+}
+
+/// An id that may be given inline or as an embedded object
+fn object_id(value: &Value) -> Option<&str> {
+    value.as_str().or_else(|| value["id"].as_str())
+}
+
+/// Receive activities for a user. Only signed requests are accepted, and the
+/// signer has to be the actor the activity claims to be from.
+pub async fn inbox(mut req: Request<State>) -> Result<Response> {
+    let body = req.body_bytes().await?;
+    let state = req.state();
+    let username = req.param("username")?;
+
+    let user_id = match find_user(state, username).await? {
+        Some(user) => user.user_id,
+        None => return Ok(Response::new(404))
+    };
+
+    let signer = match activitypub::verify_signature(&req, &body).await {
+        Some(signer) => signer,
+        None => return Ok(Response::builder(401).body("Invalid signature").build())
+    };
+
+    let activity: Value = match serde_json::from_slice(&body) {
+        Ok(activity) => activity,
+        Err(_) => return Ok(Response::builder(400).body("Invalid activity").build())
+    };
+
+    if object_id(&activity["actor"]) != Some(signer.as_str()) {
+        return Ok(Response::builder(401).body("Activity wasn't signed by its actor").build());
+    }
+
+    let actor_url = activitypub::actor_url(state, username);
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    match activity["type"].as_str() {
+        Some("Follow") if object_id(&activity["object"]) == Some(actor_url.as_str()) => {
+            let inbox = match activitypub::fetch_document(&state.config, &signer).await {
+                Some(document) => document["inbox"].as_str().map(|inbox| inbox.to_string()),
+                None => None
+            };
+
+            let inbox = match inbox {
+                Some(inbox) => inbox,
+                None => return Ok(Response::builder(400).body("Couldn't find the follower's inbox").build())
+            };
+
+            let now = Utc::now().to_rfc3339();
+
+            sqlx::query(
+                    r#"INSERT INTO activitypub_followers (user_id, actor, inbox, followed_timestamp) VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (user_id, actor) DO UPDATE SET inbox=excluded.inbox"#
+                )
+                .bind(user_id)
+                .bind(&signer)
+                .bind(&inbox)
+                .bind(&now)
+                .execute(&mut db_conn)
+                .await?;
+
+            let accept = json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "id": format!("{}#accept-{}", actor_url, Utc::now().timestamp_millis()),
+                "type": "Accept",
+                "actor": actor_url,
+                "object": activity
+            });
+
+            activitypub::queue_delivery(state, user_id, &inbox, &accept).await?;
+        },
+        Some("Undo") if activity["object"]["type"].as_str() == Some("Follow") => {
+            sqlx::query("DELETE FROM activitypub_followers WHERE user_id=$1 AND actor=$2")
+                .bind(user_id)
+                .bind(&signer)
+                .execute(&mut db_conn)
+                .await?;
+        },
+        // An account deleting itself
+        Some("Delete") if object_id(&activity["object"]) == Some(signer.as_str()) => {
+            sqlx::query("DELETE FROM activitypub_followers WHERE user_id=$1 AND actor=$2")
+                .bind(user_id)
+                .bind(&signer)
+                .execute(&mut db_conn)
+                .await?;
+        },
+        _ => {}
+    }
+
+    Ok(Response::new(202))
+}
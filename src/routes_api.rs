@@ -1,46 +1,48 @@
 use super::{State, MessageFlashes};
+use super::{openapi, routes, scheduler, tokens};
+use super::routes::{Image, Post, Visibility};
+use super::pagination::Page;
+use super::repo::{MediaRepo, PostRepo, UserRepo};
 
 use tide_tera::prelude::*;
-use tide::{Request, Response, Redirect, Result};
+use tide::{Endpoint, Request, Response, Redirect, Result};
+use tide::http::Method;
 use tide::prelude::json;
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 use chrono::prelude::*;
 use std::vec::Vec;
 
 
-#[derive(Serialize)]
-struct DraftImageResponse {
-    full_path: String,
-    thumbnail_path: String
+#[derive(Serialize, JsonSchema)]
+pub struct DraftImageResponse {
+    pub full_path: String,
+    pub thumbnail_path: String
 }
 
-#[derive(Serialize)]
-struct IndexResponse {
-    draft_images: Vec<DraftImageResponse>
+#[derive(Serialize, JsonSchema)]
+pub struct IndexResponse {
+    pub draft_images: Vec<DraftImageResponse>
 }
 
 
 pub async fn index_api(req: Request<State>) -> Result<Response> {
     let state = req.state();
     let session = req.session();
-    let mut db_conn = state.sqlite_pool.acquire().await?;
+    let mut db_conn = state.db_pool.acquire().await?;
 
     let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
 
     if logged_in {
-        // Query for draft images
-        let result = sqlx::query!(
-                "SELECT image_thumbnail_path, image_full_path FROM image_drafts"
-            )
-            .fetch_all(&mut db_conn)
-            .await?;
-
-        let draft_images: Vec<DraftImageResponse> = result.into_iter().map(|row| {
-            DraftImageResponse {
-                full_path: row.image_full_path.unwrap(),
-                thumbnail_path: row.image_thumbnail_path.unwrap()
-            }
-        }).collect();
+        let draft_images: Vec<DraftImageResponse> = MediaRepo::drafts(&mut db_conn).await?
+            .into_iter()
+            .map(|image| {
+                DraftImageResponse {
+                    full_path: image.full_path,
+                    thumbnail_path: image.thumbnail_path
+                }
+            })
+            .collect();
 
         Ok(
             json!({
@@ -49,19 +51,471 @@ pub async fn index_api(req: Request<State>) -> Result<Response> {
             .into()
         )
     } else {
-        Ok(
-        // TODO: get the proper 400 response
-        //     Response::builder(400)
-        //         .body(json!({
-        //             "unauthorized": true
-        //         }))
-        //         .content_type(tide::http::mime::JSON)
-        //         .build()
+        Ok(error_response(401, "unauthorized", "You need to be logged in"))
+    }
+}
 
-            json!({
-                "unauthorized": true
-            })
-            .into()
-        )
+
+// Version 1 of the JSON API. Reads are open to anyone and only return what an
+// anonymous visitor could see, unless the request comes from the logged in
+// user or carries an API token. Anything that changes data needs to be logged in, or to carry an API
+// token with the right scope.
+
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub message: String
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct AuthorResponse {
+    pub username: String,
+    pub name: String
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ImageResponse {
+    pub full_url: String,
+    pub medium_url: String,
+    pub thumbnail_url: String
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct PostResponse {
+    pub id: i64,
+    pub url: String,
+    pub short_url: Option<String>,
+    pub share_url: Option<String>,
+    pub author: AuthorResponse,
+    pub content: String,
+    pub visibility: Visibility,
+    pub published: bool,
+    pub posted_timestamp: String,
+    pub edited_timestamp: Option<String>,
+    pub deleted_timestamp: Option<String>,
+    pub pinned_timestamp: Option<String>,
+    pub images: Vec<ImageResponse>
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TimelineResponse {
+    pub pinned_posts: Vec<PostResponse>,
+    pub posts: Vec<PostResponse>,
+    pub newer_cursor: Option<String>,
+    pub older_cursor: Option<String>
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ProfileResponse {
+    pub username: String,
+    pub name: String,
+    pub bio: String
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct DraftsResponse {
+    pub draft_images: Vec<ImageResponse>
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct TimelineQuery {
+    pub before: Option<String>,
+    pub after: Option<String>
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct PostCreateRequest {
+    pub content: String,
+    /// RFC 3339 timestamp, posts in the future are scheduled
+    pub publish_at: Option<String>,
+    #[serde(default)]
+    pub visibility: Visibility
+}
+
+/// Fields left out are kept as they are. An empty short URL removes it.
+#[derive(Deserialize, JsonSchema)]
+pub struct PostUpdateRequest {
+    pub content: Option<String>,
+    pub short_url: Option<String>,
+    pub visibility: Option<Visibility>
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ProfileUpdateRequest {
+    pub name: Option<String>,
+    pub bio: Option<String>
+}
+
+impl From<Image> for ImageResponse {
+    fn from(image: Image) -> ImageResponse {
+        ImageResponse {
+            full_url: format!("/uploads/{}", image.full_path),
+            medium_url: format!("/uploads/{}", image.medium_path),
+            thumbnail_url: format!("/uploads/{}", image.thumbnail_path)
+        }
+    }
+}
+
+impl From<Post> for PostResponse {
+    fn from(post: Post) -> PostResponse {
+        PostResponse {
+            id: post.post_id,
+            url: format!("/post/view/{}", post.post_id),
+            share_url: post.short_url.as_ref().map(|short_url| format!("/post/share/{}", short_url)),
+            short_url: post.short_url,
+            author: AuthorResponse {
+                username: post.username,
+                name: post.name
+            },
+            content: post.content,
+            // The column only ever holds one of these, but don't widen who
+            // can see a post if it somehow doesn't
+            visibility: Visibility::parse(&post.visibility).unwrap_or(Visibility::Private),
+            published: post.published,
+            posted_timestamp: post.posted_timestamp,
+            edited_timestamp: post.edited_timestamp,
+            deleted_timestamp: post.deleted_timestamp,
+            pinned_timestamp: post.pinned_timestamp,
+            images: post.images.into_iter().map(ImageResponse::from).collect()
+        }
+    }
+}
+
+fn json_response<T: Serialize>(status: u16, body: &T) -> Response {
+    Response::builder(status)
+        .body(json!(body))
+        .content_type(tide::http::mime::JSON)
+        .build()
+}
+
+fn error_response(status: u16, error: &str, message: &str) -> Response {
+    json_response(status, &ErrorResponse {
+        error: error.to_string(),
+        message: message.to_string()
+    })
+}
+
+fn not_found() -> Response {
+    error_response(404, "not_found", "No such resource")
+}
+
+fn logged_in(req: &Request<State>) -> bool {
+    req.session().get::<bool>("logged_in").unwrap_or(false)
+}
+
+/// Whether a read may see private and scheduled posts, which any valid API
+/// token or the logged in user can. A token that isn't valid gets the error
+/// response to send, rather than quietly falling back to the anonymous view.
+async fn reader_logged_in(req: &Request<State>) -> Result<std::result::Result<bool, Response>> {
+    match tokens::bearer_token(req) {
+        Some(token) => Ok(match tokens::verify_token(req.state(), &token).await? {
+            Some(_) => Ok(true),
+            None => Err(error_response(401, "invalid_token", "Unknown or revoked API token"))
+        }),
+        None => Ok(Ok(logged_in(req)))
+    }
+}
+
+/// Check that the request may do something that needs `scope`, returning the
+/// error response to send if it may not. API tokens need to have been granted
+/// the scope, or for IndieAuth's narrower scopes like `create`, the personal
+/// access token scope covering it. Browser sessions can do anything, but changes have to carry the
+/// CSRF token in a header since there's no form for it to go in.
+async fn check_scope(req: &Request<State>, scope: &str) -> Result<Option<Response>> {
+    if let Some(token) = tokens::bearer_token(req) {
+        return Ok(match tokens::verify_token(req.state(), &token).await? {
+            None => Some(error_response(401, "invalid_token", "Unknown or revoked API token")),
+            Some(token) if !token.allows(scope) => Some(error_response(
+                403,
+                "insufficient_scope",
+                &format!("This API token doesn't have the {} scope", tokens::personal_scope(scope))
+            )),
+            Some(_) => None
+        });
+    }
+
+    if !logged_in(req) {
+        return Ok(Some(error_response(401, "unauthorized", "You need to be logged in")));
+    }
+
+    if req.method() == tide::http::Method::Get {
+        return Ok(None);
+    }
+
+    let csrf_token = req.session().get::<String>("csrf_token");
+
+    match req.header("X-CSRF-Token") {
+        Some(header) if Some(header.as_str()) == csrf_token.as_deref() => Ok(None),
+        _ => Ok(Some(error_response(403, "invalid_csrf", "Missing or invalid X-CSRF-Token header")))
+    }
+}
+
+fn post_id_param(req: &Request<State>) -> Option<i64> {
+    req.param("post_id").ok()?.parse().ok()
+}
+
+/// GET /api/v1/timeline
+pub async fn timeline(req: Request<State>) -> Result<Response> {
+    let state = req.state();
+
+    let logged_in = match reader_logged_in(&req).await? {
+        Ok(logged_in) => logged_in,
+        Err(response) => return Ok(response)
+    };
+
+    let query: TimelineQuery = match req.query() {
+        Ok(query) => query,
+        Err(_) => return Ok(error_response(400, "invalid_query", "Invalid query string"))
+    };
+
+    let page = match Page::from_query(query.before.as_deref(), query.after.as_deref()) {
+        Some(page) => page,
+        None => return Ok(error_response(400, "invalid_cursor", "Invalid page cursor"))
+    };
+
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let timeline = routes::timeline_page(
+        &mut db_conn,
+        logged_in,
+        &page,
+        state.config.posts_per_page as i64
+    ).await?;
+
+    let pinned_posts = match page {
+        Page::First => PostRepo::pinned(&mut db_conn, logged_in).await?,
+        _ => Vec::new()
+    };
+
+    Ok(json_response(200, &TimelineResponse {
+        pinned_posts: pinned_posts.into_iter().map(PostResponse::from).collect(),
+        posts: timeline.posts.into_iter().map(PostResponse::from).collect(),
+        newer_cursor: timeline.newer.map(|cursor| cursor.encode()),
+        older_cursor: timeline.older.map(|cursor| cursor.encode())
+    }))
+}
+
+/// POST /api/v1/posts
+pub async fn post_create(mut req: Request<State>) -> Result<Response> {
+    if let Some(response) = check_scope(&req, "create").await? {
+        return Ok(response);
     }
+
+    let input: PostCreateRequest = match req.body_json().await {
+        Ok(input) => input,
+        Err(error) => return Ok(error_response(400, "invalid_body", &error.to_string()))
+    };
+
+    if input.content.trim().is_empty() {
+        return Ok(error_response(400, "invalid_body", "Content can't be empty"));
+    }
+
+    let publish_at = match input.publish_at.as_deref() {
+        None => None,
+        Some(value) => match scheduler::parse_publish_at(value) {
+            Some(publish_at) => Some(publish_at),
+            None => return Ok(error_response(400, "invalid_body", "publish_at must be an RFC 3339 timestamp"))
+        }
+    };
+
+    let state = req.state();
+    let post_id = routes::create_post(state, &input.content, publish_at, input.visibility).await?;
+
+    let mut db_conn = state.db_pool.acquire().await?;
+    let post = PostRepo::find(&mut db_conn, post_id).await?.unwrap();
+
+    let mut response = json_response(201, &PostResponse::from(post));
+    response.insert_header("Location", format!("/api/v1/posts/{}", post_id));
+
+    Ok(response)
+}
+
+/// GET /api/v1/posts/:post_id
+pub async fn post_get(req: Request<State>) -> Result<Response> {
+    let post_id = match post_id_param(&req) {
+        Some(post_id) => post_id,
+        None => return Ok(not_found())
+    };
+
+    let logged_in = match reader_logged_in(&req).await? {
+        Ok(logged_in) => logged_in,
+        Err(response) => return Ok(response)
+    };
+
+    let mut db_conn = req.state().db_pool.acquire().await?;
+
+    match PostRepo::find(&mut db_conn, post_id).await? {
+        Some(post) if post.is_visible(logged_in) => {
+            Ok(json_response(200, &PostResponse::from(post)))
+        },
+        _ => Ok(not_found())
+    }
+}
+
+/// PATCH /api/v1/posts/:post_id
+pub async fn post_update(mut req: Request<State>) -> Result<Response> {
+    if let Some(response) = check_scope(&req, "update").await? {
+        return Ok(response);
+    }
+
+    let post_id = match post_id_param(&req) {
+        Some(post_id) => post_id,
+        None => return Ok(not_found())
+    };
+
+    let input: PostUpdateRequest = match req.body_json().await {
+        Ok(input) => input,
+        Err(error) => return Ok(error_response(400, "invalid_body", &error.to_string()))
+    };
+
+    let mut tx = req.state().db_pool.begin().await?;
+
+    let post = match PostRepo::find(&mut tx, post_id).await? {
+        Some(post) => post,
+        None => return Ok(not_found())
+    };
+
+    let content = input.content.unwrap_or(post.content);
+
+    let short_url = match input.short_url {
+        None => post.short_url,
+        Some(short_url) if short_url.is_empty() => None,
+        Some(short_url) => Some(short_url)
+    };
+
+    if content.trim().is_empty() {
+        return Ok(error_response(400, "invalid_body", "Content can't be empty"));
+    }
+
+    if let Some(short_url) = &short_url {
+        if PostRepo::short_url_taken(&mut tx, short_url, post_id).await? {
+            return Ok(error_response(409, "conflict", "Another post already uses that short URL"));
+        }
+    }
+
+    PostRepo::revise(&mut tx, post_id, &content, &short_url).await?;
+
+    if let Some(visibility) = input.visibility {
+        PostRepo::set_visibility(&mut tx, post_id, visibility.as_str()).await?;
+    }
+
+    let post = PostRepo::find(&mut tx, post_id).await?.unwrap();
+
+    tx.commit().await?;
+
+    scheduler::post_edited(req.state(), post_id).await?;
+
+    Ok(json_response(200, &PostResponse::from(post)))
+}
+
+/// DELETE /api/v1/posts/:post_id moves the post to the trash
+pub async fn post_delete(req: Request<State>) -> Result<Response> {
+    if let Some(response) = check_scope(&req, "delete").await? {
+        return Ok(response);
+    }
+
+    let post_id = match post_id_param(&req) {
+        Some(post_id) => post_id,
+        None => return Ok(not_found())
+    };
+
+    let mut db_conn = req.state().db_pool.acquire().await?;
+
+    match PostRepo::trash(&mut db_conn, post_id).await? {
+        true => {
+            scheduler::post_trashed(req.state(), post_id).await?;
+
+            Ok(Response::new(204))
+        },
+        false => Ok(not_found())
+    }
+}
+
+async fn profile(db_conn: &mut sqlx::AnyConnection) -> Result<ProfileResponse> {
+    let user = UserRepo::owner(db_conn).await?;
+
+    Ok(ProfileResponse {
+        username: user.username,
+        name: user.name,
+        bio: user.bio
+    })
+}
+
+/// GET /api/v1/profile
+pub async fn profile_get(req: Request<State>) -> Result<Response> {
+    let mut db_conn = req.state().db_pool.acquire().await?;
+
+    Ok(json_response(200, &profile(&mut db_conn).await?))
+}
+
+/// PATCH /api/v1/profile
+pub async fn profile_update(mut req: Request<State>) -> Result<Response> {
+    if let Some(response) = check_scope(&req, "profile:write").await? {
+        return Ok(response);
+    }
+
+    let input: ProfileUpdateRequest = match req.body_json().await {
+        Ok(input) => input,
+        Err(error) => return Ok(error_response(400, "invalid_body", &error.to_string()))
+    };
+
+    let mut db_conn = req.state().db_pool.acquire().await?;
+    let current = profile(&mut db_conn).await?;
+
+    let name = input.name.unwrap_or(current.name);
+    let bio = input.bio.unwrap_or(current.bio);
+
+    UserRepo::update_profile(&mut db_conn, 1, &name, &bio).await?;
+
+    Ok(json_response(200, &profile(&mut db_conn).await?))
+}
+
+/// POST /api/v1/uploads takes the raw image as the body. The image is kept as
+/// a draft and attached to the next post that's created.
+pub async fn upload_create(req: Request<State>) -> Result<Response> {
+    if let Some(response) = check_scope(&req, "media").await? {
+        return Ok(response);
+    }
+
+    let state = req.state().clone();
+    let image = routes::save_image_upload(&state, req).await?;
+
+    Ok(json_response(201, &ImageResponse::from(image)))
+}
+
+/// GET /api/v1/uploads/drafts
+pub async fn upload_drafts(req: Request<State>) -> Result<Response> {
+    if let Some(response) = check_scope(&req, "media").await? {
+        return Ok(response);
+    }
+
+    let mut db_conn = req.state().db_pool.acquire().await?;
+
+    let draft_images = MediaRepo::drafts(&mut db_conn).await?
+        .into_iter()
+        .map(ImageResponse::from)
+        .collect();
+
+    Ok(json_response(200, &DraftsResponse { draft_images }))
+}
+
+/// GET /api/openapi.json
+pub async fn openapi(_req: Request<State>) -> Result<Response> {
+    Ok(json_response(200, &openapi::document()))
+}
+
+/// Every /api/v1 route, for main to register. The OpenAPI document has to
+/// describe exactly these, which a test checks.
+pub fn routes() -> Vec<(Method, &'static str, Box<dyn Endpoint<State>>)> {
+    vec![
+        (Method::Get, "/api/v1/timeline", Box::new(timeline)),
+        (Method::Post, "/api/v1/posts", Box::new(post_create)),
+        (Method::Get, "/api/v1/posts/:post_id", Box::new(post_get)),
+        (Method::Patch, "/api/v1/posts/:post_id", Box::new(post_update)),
+        (Method::Delete, "/api/v1/posts/:post_id", Box::new(post_delete)),
+        (Method::Get, "/api/v1/profile", Box::new(profile_get)),
+        (Method::Patch, "/api/v1/profile", Box::new(profile_update)),
+        (Method::Post, "/api/v1/uploads", Box::new(upload_create)),
+        (Method::Get, "/api/v1/uploads/drafts", Box::new(upload_drafts))
+    ]
 }
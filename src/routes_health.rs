@@ -0,0 +1,65 @@
+use super::State;
+use super::health;
+
+use tide::prelude::json;
+use tide::{Request, Response, Result};
+
+// Probes for whatever runs the server, and metrics for Prometheus. None of
+// these need a login, so keep them away from the public internet if that
+// matters.
+
+/// GET /healthz - the server is up and answering requests
+pub async fn healthz(_req: Request<State>) -> Result<Response> {
+    Ok(Response::builder(200).body("ok").build())
+}
+
+/// GET /readyz - the server isn't shutting down, and can reach the database,
+/// write uploads and resize images. Responds 503 with the failing checks
+/// otherwise.
+pub async fn readyz(req: Request<State>) -> Result<Response> {
+    let state = req.state();
+
+    // Draining servers stop taking new traffic
+    let shutdown = match state.shutdown.is_stopping() {
+        true => Err("shutting down".to_string()),
+        false => Ok(())
+    };
+
+    let checks = [
+        ("shutdown", shutdown),
+        ("database", health::check_database(state).await),
+        ("uploads", health::check_uploads(&state.config.uploads_path).await),
+        ("image_backend", health::check_image_backend(&state.config).await)
+    ];
+
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+
+    let mut results = serde_json::Map::new();
+
+    for (name, result) in checks {
+        let result = match result {
+            Ok(()) => "ok".to_string(),
+            Err(error) => error
+        };
+
+        results.insert(name.to_string(), result.into());
+    }
+
+    let body = json!({
+        "status": if ready { "ready" } else { "unavailable" },
+        "checks": results
+    });
+
+    Ok(Response::builder(if ready { 200 } else { 503 }).body(body).build())
+}
+
+/// GET /metrics - request, upload and database pool metrics in Prometheus'
+/// text format
+pub async fn metrics(req: Request<State>) -> Result<Response> {
+    let state = req.state();
+
+    Ok(Response::builder(200)
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render(&state.db_pool))
+        .build())
+}
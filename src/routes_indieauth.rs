@@ -0,0 +1,479 @@
+use super::{State, MessageFlashes};
+use super::repo::UserRepo;
+use super::tokens;
+
+use tide_tera::prelude::*;
+use tide::{Request, Response, Redirect, Result};
+use tide::http::Url;
+use tide::prelude::json;
+use serde::{Serialize, Deserialize};
+use chrono::prelude::*;
+use sha2::{Digest, Sha256};
+use std::vec::Vec;
+
+// IndieAuth (https://indieauth.spec.indieweb.org/) lets the site's URL be
+// used to sign in elsewhere, and lets clients like Micropub apps ask for an
+// access token. Tokens end up in the same table as personal access tokens,
+// so they're listed and can be revoked on the profile page.
+
+/// Scopes a client can ask for, with a description for the consent screen
+pub const SCOPES: &[(&str, &str)] = &[
+    ("profile", "See your name"),
+    ("create", "Create posts"),
+    ("update", "Edit posts"),
+    ("delete", "Delete and undelete posts"),
+    ("media", "Upload images")
+];
+
+/// How long an authorization code can be redeemed for
+const CODE_LIFETIME_MINUTES: i64 = 10;
+
+#[derive(Deserialize)]
+pub struct AuthorizationQuery {
+    response_type: Option<String>,
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    scope: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct CodeRedemptionInput {
+    grant_type: Option<String>,
+    code: String,
+    client_id: String,
+    redirect_uri: String,
+    code_verifier: String
+}
+
+#[derive(Deserialize)]
+pub struct RevocationInput {
+    token: String
+}
+
+/// A requested scope as shown on the consent screen
+#[derive(Serialize)]
+pub struct ScopeRequest {
+    name: String,
+    description: String
+}
+
+/// An authorization code waiting to be redeemed
+#[derive(sqlx::FromRow)]
+struct CodeRow {
+    code_id: i64,
+    client_id: String,
+    redirect_uri: String,
+    code_challenge: String,
+    scopes: String,
+    created_timestamp: String
+}
+
+/// What an authorization code was issued for
+struct Grant {
+    client_id: String,
+    scopes: Vec<String>
+}
+
+fn me(state: &State) -> String {
+    format!("{}/", state.config.site_url)
+}
+
+fn oauth_error(error: &str, description: &str) -> Response {
+    Response::builder(400)
+        .body(json!({
+            "error": error,
+            "error_description": description
+        }))
+        .content_type(tide::http::mime::JSON)
+        .build()
+}
+
+/// Clients are identified by a URL, and may only send the user back to a URL
+/// on the same host
+fn validate_client(client_id: &str, redirect_uri: &str) -> std::result::Result<(), &'static str> {
+    let client = Url::parse(client_id).map_err(|_| "client_id must be a URL")?;
+    let redirect = Url::parse(redirect_uri).map_err(|_| "redirect_uri must be a URL")?;
+
+    if !["http", "https"].contains(&client.scheme()) {
+        return Err("client_id must be an http or https URL");
+    }
+
+    if client.origin() != redirect.origin() {
+        return Err("redirect_uri must be on the same host as client_id");
+    }
+
+    Ok(())
+}
+
+fn random_code() -> String {
+    let bytes: [u8; 32] = rand::random();
+
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// PKCE: the verifier the client redeems the code with has to hash to the
+/// challenge it sent when asking for the code
+fn verify_code_challenge(code_verifier: &str, code_challenge: &str) -> bool {
+    let digest = Sha256::digest(code_verifier.as_bytes());
+
+    base64::encode_config(digest, base64::URL_SAFE_NO_PAD) == code_challenge
+}
+
+fn redirect_with(redirect_uri: &str, params: &[(&str, &str)]) -> Result<Response> {
+    let mut url = Url::parse(redirect_uri)?;
+
+    url.query_pairs_mut().extend_pairs(params);
+
+    Ok(Redirect::new(url.as_str()).into())
+}
+
+/// GET /.well-known/oauth-authorization-server
+pub async fn metadata(req: Request<State>) -> Result<Response> {
+    let site_url = &req.state().config.site_url;
+
+    Ok(
+        Response::builder(200)
+            .body(json!({
+                "issuer": me(req.state()),
+                "authorization_endpoint": format!("{}/auth", site_url),
+                "token_endpoint": format!("{}/token", site_url),
+                "revocation_endpoint": format!("{}/token/revoke", site_url),
+                "revocation_endpoint_auth_methods_supported": ["none"],
+                "response_types_supported": ["code"],
+                "grant_types_supported": ["authorization_code"],
+                "code_challenge_methods_supported": ["S256"],
+                "scopes_supported": SCOPES.iter().map(|(name, _)| *name).collect::<Vec<&str>>()
+            }))
+            .content_type(tide::http::mime::JSON)
+            .build()
+    )
+}
+
+/// GET /auth shows the consent screen, after logging in if needed
+pub async fn authorize(mut req: Request<State>) -> Result<Response> {
+    let logged_in = req.session().get::<bool>("logged_in").unwrap_or(false);
+
+    if !logged_in {
+        let url = req.url();
+        let redirect = format!("{}?{}", url.path(), url.query().unwrap_or(""));
+
+        req.session_mut().insert("login_redirect", redirect).unwrap();
+        req.session_mut().insert(
+            "messages",
+            "Log in to continue signing in to another site.".to_string()
+        ).unwrap();
+
+        return Ok(Redirect::new("/user/login").into());
+    }
+
+    let query: AuthorizationQuery = match req.query() {
+        Ok(query) => query,
+        Err(_) => return Ok(Response::builder(400).body("Invalid authorization request").build())
+    };
+
+    if let Err(message) = validate_client(&query.client_id, &query.redirect_uri) {
+        return Ok(Response::builder(400).body(message).build());
+    }
+
+    // From here on problems can be reported back to the client
+    if query.response_type.as_deref().unwrap_or("code") != "code" {
+        return redirect_with(&query.redirect_uri, &[
+            ("error", "unsupported_response_type"),
+            ("state", &query.state)
+        ]);
+    }
+
+    if query.code_challenge.is_none() || query.code_challenge_method.as_deref() != Some("S256") {
+        return redirect_with(&query.redirect_uri, &[
+            ("error", "invalid_request"),
+            ("error_description", "PKCE with S256 is required"),
+            ("state", &query.state)
+        ]);
+    }
+
+    let state = req.state();
+    let session = req.session();
+    let messages: Option<&MessageFlashes> = req.ext();
+    let tera = &state.tera;
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+
+    // Scopes we don't know about are left out rather than refused
+    let requested: Vec<&str> = query.scope.as_deref().unwrap_or("").split_whitespace().collect();
+
+    let scopes: Vec<ScopeRequest> = SCOPES.iter()
+        .filter(|(name, _)| requested.contains(name))
+        .map(|(name, description)| ScopeRequest {
+            name: name.to_string(),
+            description: description.to_string()
+        })
+        .collect();
+
+    let client_host = Url::parse(&query.client_id)?.host_str().unwrap_or("").to_string();
+
+    let mut context = tera::Context::new();
+
+    context.insert("csrf_token", &csrf_token);
+    context.insert("me", &me(state));
+    context.insert("client_id", &query.client_id);
+    context.insert("client_host", &client_host);
+    context.insert("redirect_uri", &query.redirect_uri);
+    context.insert("state", &query.state);
+    context.insert("code_challenge", &query.code_challenge);
+    context.insert("scopes", &scopes);
+
+    if let Some(m) = messages {
+        context.insert("messages", &m.messages);
+    }
+
+    tera.render_response("authorize.html", &context)
+}
+
+/// POST /auth/approve handles the consent screen. The form has a checkbox for
+/// each scope, so it's read as a list of pairs.
+pub async fn authorize_approve(mut req: Request<State>) -> Result<Response> {
+    let session = req.session();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let form_input: Vec<(String, String)> = req.body_form().await?;
+
+    let field = |key: &str| form_input.iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.to_string())
+        .unwrap_or_default();
+
+    if !logged_in {
+        return Ok(Response::builder(400).body("Forbidden").build());
+    } else if field("csrf-token") != csrf_token {
+        return Ok(Response::builder(400).body("Invalid CSRF").build());
+    }
+
+    let client_id = field("client_id");
+    let redirect_uri = field("redirect_uri");
+    let state_param = field("state");
+    let code_challenge = field("code_challenge");
+
+    if let Err(message) = validate_client(&client_id, &redirect_uri) {
+        return Ok(Response::builder(400).body(message).build());
+    }
+
+    if field("decision") != "approve" {
+        return redirect_with(&redirect_uri, &[
+            ("error", "access_denied"),
+            ("state", &state_param)
+        ]);
+    }
+
+    let scopes: Vec<&str> = form_input.iter()
+        .filter(|(name, value)| name == "scope" && SCOPES.iter().any(|(scope, _)| scope == value))
+        .map(|(_, value)| value.as_str())
+        .collect();
+
+    let scopes = scopes.join(" ");
+    let code = random_code();
+    let code_hash = tokens::hash_token(&code);
+
+    let now = Utc::now();
+    let now_timestamp = now.to_rfc3339();
+    let expired = (now - chrono::Duration::minutes(CODE_LIFETIME_MINUTES)).to_rfc3339();
+
+    let mut db_conn = req.state().db_pool.acquire().await?;
+
+    // Clear out codes that were never redeemed
+    sqlx::query("DELETE FROM indieauth_codes WHERE created_timestamp < $1")
+        .bind(&expired)
+        .execute(&mut db_conn)
+        .await?;
+
+    sqlx::query(
+            r#"INSERT INTO indieauth_codes
+                (code_hash, client_id, redirect_uri, code_challenge, scopes, created_timestamp)
+            VALUES ($1, $2, $3, $4, $5, $6)"#
+        )
+        .bind(&code_hash)
+        .bind(client_id)
+        .bind(&redirect_uri)
+        .bind(&code_challenge)
+        .bind(&scopes)
+        .bind(&now_timestamp)
+        .execute(&mut db_conn)
+        .await?;
+
+    let issuer = me(req.state());
+
+    redirect_with(&redirect_uri, &[
+        ("code", &code),
+        ("state", &state_param),
+        ("iss", &issuer)
+    ])
+}
+
+/// Check an authorization code and use it up. Codes only work once, for the
+/// client they were issued to, within a few minutes.
+async fn redeem_code(state: &State, input: &CodeRedemptionInput) -> Result<std::result::Result<Grant, Response>> {
+    if input.grant_type.as_deref().unwrap_or("authorization_code") != "authorization_code" {
+        return Ok(Err(oauth_error("unsupported_grant_type", "Only authorization_code is supported")));
+    }
+
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let code_hash = tokens::hash_token(&input.code);
+    let expired = (Utc::now() - chrono::Duration::minutes(CODE_LIFETIME_MINUTES)).to_rfc3339();
+
+    let row: Option<CodeRow> = sqlx::query_as(
+            r#"SELECT rowid AS code_id, client_id, redirect_uri, code_challenge, scopes, created_timestamp
+            FROM indieauth_codes WHERE code_hash=$1"#
+        )
+        .bind(&code_hash)
+        .fetch_optional(&mut db_conn)
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(Err(oauth_error("invalid_grant", "Unknown or already used authorization code")))
+    };
+
+    let deleted = sqlx::query("DELETE FROM indieauth_codes WHERE rowid=$1")
+        .bind(row.code_id)
+        .execute(&mut db_conn)
+        .await?;
+
+    // Someone else redeemed it in the meantime
+    if deleted.rows_affected() == 0 {
+        return Ok(Err(oauth_error("invalid_grant", "Unknown or already used authorization code")));
+    }
+
+    if row.created_timestamp < expired {
+        return Ok(Err(oauth_error("invalid_grant", "The authorization code has expired")));
+    }
+
+    if row.client_id != input.client_id || row.redirect_uri != input.redirect_uri {
+        return Ok(Err(oauth_error("invalid_grant", "The authorization code was issued to another client")));
+    }
+
+    if !verify_code_challenge(&input.code_verifier, &row.code_challenge) {
+        return Ok(Err(oauth_error("invalid_grant", "The code verifier doesn't match the code challenge")));
+    }
+
+    Ok(Ok(Grant {
+        client_id: row.client_id,
+        scopes: row.scopes.split_whitespace().map(str::to_string).collect()
+    }))
+}
+
+async fn profile_response(state: &State, grant: &Grant) -> Result<serde_json::Value> {
+    if !grant.scopes.iter().any(|scope| scope == "profile") {
+        return Ok(json!(null));
+    }
+
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let user = UserRepo::owner(&mut db_conn).await?;
+
+    Ok(json!({
+        "name": user.name,
+        "url": me(state)
+    }))
+}
+
+/// POST /auth redeems a code for the user's identity only, for clients that
+/// just want to sign the user in
+pub async fn authorize_redeem(mut req: Request<State>) -> Result<Response> {
+    let input: CodeRedemptionInput = match req.body_form().await {
+        Ok(input) => input,
+        Err(_) => return Ok(oauth_error("invalid_request", "Missing code, client_id, redirect_uri or code_verifier"))
+    };
+
+    let state = req.state();
+
+    let grant = match redeem_code(state, &input).await? {
+        Ok(grant) => grant,
+        Err(response) => return Ok(response)
+    };
+
+    let mut body = json!({ "me": me(state) });
+    let profile = profile_response(state, &grant).await?;
+
+    if !profile.is_null() {
+        body["profile"] = profile;
+    }
+
+    Ok(
+        Response::builder(200)
+            .body(body)
+            .content_type(tide::http::mime::JSON)
+            .build()
+    )
+}
+
+/// POST /token redeems a code for an access token. The older way of revoking
+/// tokens, with `action=revoke`, is handled here too.
+pub async fn token(mut req: Request<State>) -> Result<Response> {
+    let body = req.body_string().await?;
+
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(&body).unwrap_or_default();
+
+    if pairs.iter().any(|(name, value)| name == "action" && value == "revoke") {
+        if let Some((_, token)) = pairs.iter().find(|(name, _)| name == "token") {
+            tokens::revoke_token(req.state(), token).await?;
+        }
+
+        return Ok(Response::new(200));
+    }
+
+    let input: CodeRedemptionInput = match serde_urlencoded::from_str(&body) {
+        Ok(input) => input,
+        Err(_) => return Ok(oauth_error("invalid_request", "Missing code, client_id, redirect_uri or code_verifier"))
+    };
+
+    let state = req.state();
+
+    let grant = match redeem_code(state, &input).await? {
+        Ok(grant) => grant,
+        Err(response) => return Ok(response)
+    };
+
+    if grant.scopes.is_empty() {
+        return Ok(oauth_error("invalid_grant", "No scopes were granted, so there's no token to issue"));
+    }
+
+    let access_token = tokens::create_token(state, &grant.client_id, &grant.scopes).await?;
+
+    let mut body = json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "scope": grant.scopes.join(" "),
+        "me": me(state)
+    });
+
+    let profile = profile_response(state, &grant).await?;
+
+    if !profile.is_null() {
+        body["profile"] = profile;
+    }
+
+    Ok(
+        Response::builder(200)
+            .body(body)
+            .content_type(tide::http::mime::JSON)
+            .header("Cache-Control", "no-store")
+            .build()
+    )
+}
+
+/// POST /token/revoke, as in RFC 7009. Unknown tokens aren't an error.
+pub async fn token_revoke(mut req: Request<State>) -> Result<Response> {
+    let input: RevocationInput = match req.body_form().await {
+        Ok(input) => input,
+        Err(_) => return Ok(oauth_error("invalid_request", "Missing token"))
+    };
+
+    tokens::revoke_token(req.state(), &input.token).await?;
+
+    Ok(Response::new(200))
+}
@@ -0,0 +1,521 @@
+use super::State;
+use super::repo::{MediaRepo, PostRepo};
+use super::routes::{self, Image, NewPost, Visibility};
+use super::tokens;
+use super::scheduler;
+
+use tide::{Request, Response, Result};
+use tide::prelude::json;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::vec::Vec;
+
+// Micropub (https://www.w3.org/TR/micropub/) lets third party clients post
+// as the user. Requests are authenticated with the same API tokens as the
+// JSON API. Micropub's own scopes are accepted, as are our broader ones.
+
+/// Properties of an h-entry. Every property is a list of values, which are
+/// strings or, for things like HTML content, objects.
+type Properties = BTreeMap<String, Vec<Value>>;
+
+/// A parsed Micropub request, whether it came in form encoded or as JSON
+#[derive(Default)]
+struct MicropubRequest {
+    action: Option<String>,
+    url: Option<String>,
+    access_token: Option<String>,
+    properties: Properties,
+    replace: Properties,
+    add: Properties,
+    delete: Vec<String>
+}
+
+fn error_response(status: u16, error: &str, description: &str) -> Response {
+    Response::builder(status)
+        .body(json!({
+            "error": error,
+            "error_description": description
+        }))
+        .content_type(tide::http::mime::JSON)
+        .build()
+}
+
+fn invalid_request(description: &str) -> Response {
+    error_response(400, "invalid_request", description)
+}
+
+/// Check the token sent with a request, returning the error response to send
+/// if it isn't allowed to do `scope`. Any valid token can make queries.
+async fn check_token(
+    req: &Request<State>,
+    access_token: Option<&str>,
+    scope: Option<&str>
+) -> Result<Option<Response>> {
+    let token = match tokens::bearer_token(req).or_else(|| access_token.map(str::to_string)) {
+        Some(token) => token,
+        None => return Ok(Some(error_response(401, "unauthorized", "An access token is required")))
+    };
+
+    Ok(match (tokens::verify_token(req.state(), &token).await?, scope) {
+        (None, _) => Some(error_response(403, "forbidden", "Unknown or revoked access token")),
+        (Some(token), Some(scope)) if !token.allows(scope) => Some(error_response(
+            401,
+            "insufficient_scope",
+            &format!("This access token doesn't have the {} scope", scope)
+        )),
+        _ => None
+    })
+}
+
+fn values_to_properties(object: Option<&Map<String, Value>>) -> Properties {
+    object.map(|object| object.iter().map(|(name, values)| {
+        let values = match values {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()]
+        };
+
+        (name.clone(), values)
+    }).collect()).unwrap_or_default()
+}
+
+fn parse_json(body: Value) -> MicropubRequest {
+    let string = |key: &str| body[key].as_str().map(str::to_string);
+
+    let delete = match &body["delete"] {
+        Value::Array(names) => names.iter().filter_map(|name| name.as_str().map(str::to_string)).collect(),
+        // Deleting particular values isn't supported, so treat it as deleting the properties
+        Value::Object(object) => object.keys().cloned().collect(),
+        _ => Vec::new()
+    };
+
+    MicropubRequest {
+        action: string("action"),
+        url: string("url"),
+        access_token: None,
+        properties: values_to_properties(body["properties"].as_object()),
+        replace: values_to_properties(body["replace"].as_object()),
+        add: values_to_properties(body["add"].as_object()),
+        delete
+    }
+}
+
+/// Form encoded requests flatten everything into one level, with `[]` marking
+/// properties that have several values
+fn parse_form(pairs: Vec<(String, String)>) -> MicropubRequest {
+    let mut request = MicropubRequest::default();
+
+    for (key, value) in pairs {
+        let name = key.trim_end_matches("[]").to_string();
+
+        match name.as_str() {
+            "h" => (),
+            "action" => request.action = Some(value),
+            "url" => request.url = Some(value),
+            "access_token" => request.access_token = Some(value),
+            _ => request.properties.entry(name).or_default().push(Value::String(value))
+        }
+    }
+
+    request
+}
+
+/// The plain value of a property, whether it was sent as a string or as an
+/// object like `{"html": "..."}` or `{"value": "...", "alt": "..."}`
+fn first_string(properties: &Properties, name: &str) -> Option<String> {
+    properties.get(name)?.first().and_then(value_string)
+}
+
+fn value_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Object(object) => ["html", "value", "text"].iter()
+            .find_map(|key| object.get(*key).and_then(Value::as_str))
+            .map(str::to_string),
+        _ => None
+    }
+}
+
+fn parse_visibility(value: Option<String>) -> std::result::Result<Option<Visibility>, &'static str> {
+    match value.as_deref() {
+        None => Ok(None),
+        Some(value) => Visibility::parse(value)
+            .map(Some)
+            .ok_or("visibility must be public, unlisted or private")
+    }
+}
+
+async fn short_url_taken(state: &State, short_url: &str, post_id: i64) -> Result<bool> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    PostRepo::short_url_taken(&mut db_conn, short_url, post_id).await
+}
+
+/// GET /micropub handles the `q` queries
+pub async fn micropub_query(req: Request<State>) -> Result<Response> {
+    let pairs: Vec<(String, String)> = req.url().query_pairs().into_owned().collect();
+    let param = |key: &str| pairs.iter().find(|(name, _)| name == key).map(|(_, value)| value.clone());
+
+    if let Some(response) = check_token(&req, param("access_token").as_deref(), None).await? {
+        return Ok(response);
+    }
+
+    let state = req.state();
+    let site_url = &state.config.site_url;
+
+    let body = match param("q").as_deref() {
+        Some("config") => json!({
+            "media-endpoint": format!("{}/micropub/media", site_url),
+            "syndicate-to": [],
+            "q": ["config", "source", "syndicate-to"],
+            "post-types": [
+                { "type": "note", "name": "Note" },
+                { "type": "photo", "name": "Photo" }
+            ]
+        }),
+        Some("syndicate-to") => json!({ "syndicate-to": [] }),
+        Some("source") => {
+            let post_id = match param("url") {
+                Some(url) => routes::post_id_from_url(state, &url).await?,
+                None => return Ok(invalid_request("q=source needs a url"))
+            };
+
+            let mut db_conn = state.db_pool.acquire().await?;
+
+            let post = match post_id {
+                Some(post_id) => PostRepo::find(&mut db_conn, post_id).await?,
+                None => None
+            };
+
+            let post = match post {
+                Some(post) => post,
+                None => return Ok(error_response(404, "not_found", "No post at that URL"))
+            };
+
+            let mut properties = Map::new();
+
+            properties.insert("content".into(), json!([post.content]));
+            properties.insert("published".into(), json!([post.posted_timestamp]));
+            properties.insert("visibility".into(), json!([post.visibility]));
+            properties.insert("photo".into(), json!(post.images.iter().map(|image| {
+                format!("{}/uploads/{}", site_url, image.full_path)
+            }).collect::<Vec<String>>()));
+
+            if let Some(short_url) = post.short_url {
+                properties.insert("mp-slug".into(), json!([short_url]));
+            }
+
+            // Asking for particular properties leaves out the type
+            let wanted: Vec<&String> = pairs.iter()
+                .filter(|(name, _)| name == "properties[]" || name == "properties")
+                .map(|(_, value)| value)
+                .collect();
+
+            if wanted.is_empty() {
+                json!({
+                    "type": ["h-entry"],
+                    "properties": properties
+                })
+            } else {
+                let properties: Map<String, Value> = properties.into_iter()
+                    .filter(|(name, _)| wanted.contains(&name))
+                    .collect();
+
+                json!({ "properties": properties })
+            }
+        },
+        _ => return Ok(invalid_request("Unsupported query"))
+    };
+
+    Ok(
+        Response::builder(200)
+            .body(body)
+            .content_type(tide::http::mime::JSON)
+            .build()
+    )
+}
+
+/// POST /micropub creates, updates, deletes and undeletes posts
+pub async fn micropub(mut req: Request<State>) -> Result<Response> {
+    let is_json = req.content_type()
+        .map(|mime| mime.essence() == "application/json")
+        .unwrap_or(false);
+
+    let request = if is_json {
+        match req.body_json::<Value>().await {
+            Ok(body) => parse_json(body),
+            Err(_) => return Ok(invalid_request("Invalid JSON"))
+        }
+    } else {
+        match req.body_form::<Vec<(String, String)>>().await {
+            Ok(pairs) => parse_form(pairs),
+            Err(_) => return Ok(invalid_request("Invalid form body"))
+        }
+    };
+
+    let action = request.action.clone().unwrap_or_else(|| "create".to_string());
+
+    match action.as_str() {
+        "create" | "update" | "delete" | "undelete" => (),
+        _ => return Ok(invalid_request("Unsupported action"))
+    }
+
+    if let Some(response) = check_token(&req, request.access_token.as_deref(), Some(&action)).await? {
+        return Ok(response);
+    }
+
+    if action == "create" {
+        return create(req.state(), request).await;
+    }
+
+    let post_id = match &request.url {
+        Some(url) => routes::post_id_from_url(req.state(), url).await?,
+        None => return Ok(invalid_request("A url is required"))
+    };
+
+    let post_id = match post_id {
+        Some(post_id) => post_id,
+        None => return Ok(invalid_request("No post at that URL"))
+    };
+
+    let state = req.state();
+
+    match action.as_str() {
+        "delete" => {
+            let mut db_conn = state.db_pool.acquire().await?;
+            if PostRepo::trash(&mut db_conn, post_id).await? {
+                scheduler::post_trashed(state, post_id).await?;
+            }
+
+            Ok(Response::new(204))
+        },
+        "undelete" => {
+            let mut db_conn = state.db_pool.acquire().await?;
+            PostRepo::untrash(&mut db_conn, post_id).await?;
+
+            Ok(Response::new(204))
+        },
+        _ => update(state, post_id, request).await
+    }
+}
+
+async fn create(state: &State, request: MicropubRequest) -> Result<Response> {
+    let properties = &request.properties;
+    let site_url = &state.config.site_url;
+
+    let content = match first_string(properties, "content").or_else(|| first_string(properties, "name")) {
+        Some(content) if !content.trim().is_empty() => content,
+        _ => return Ok(invalid_request("content is required"))
+    };
+
+    let publish_at = match first_string(properties, "published") {
+        Some(published) => match super::scheduler::parse_publish_at(&published) {
+            Some(publish_at) => Some(publish_at),
+            None => return Ok(invalid_request("published must be an RFC 3339 timestamp"))
+        },
+        None => None
+    };
+
+    let visibility = match parse_visibility(first_string(properties, "visibility")) {
+        Ok(visibility) => visibility.unwrap_or_default(),
+        Err(message) => return Ok(invalid_request(message))
+    };
+
+    let short_url = first_string(properties, "mp-slug").filter(|slug| !slug.is_empty());
+
+    if let Some(short_url) = &short_url {
+        if short_url_taken(state, short_url, 0).await? {
+            return Ok(invalid_request("Another post already uses that mp-slug"));
+        }
+    }
+
+    // Photos have to come from our media endpoint, where they're waiting as
+    // draft images
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let draft_images = MediaRepo::drafts(&mut db_conn).await?;
+
+    let mut images = Vec::new();
+
+    for photo in properties.get("photo").into_iter().flatten().filter_map(value_string) {
+        let upload = photo.strip_prefix(site_url.as_str()).unwrap_or(&photo);
+
+        let image = draft_images.iter().find(|image| {
+            upload.strip_prefix("/uploads/") == Some(image.full_path.as_str())
+        });
+
+        match image {
+            Some(image) => images.push(Image {
+                full_path: image.full_path.clone(),
+                medium_path: image.medium_path.clone(),
+                thumbnail_path: image.thumbnail_path.clone()
+            }),
+            None => return Ok(invalid_request("Photos need to be uploaded to the media endpoint first"))
+        }
+    }
+
+    for image in &images {
+        MediaRepo::remove_draft(&mut db_conn, &image.full_path).await?;
+    }
+
+    let post_id = routes::insert_post(state, NewPost {
+        content: &content,
+        short_url: short_url.as_deref(),
+        publish_at,
+        visibility,
+        images
+    }).await?;
+
+    let mut response = Response::new(201);
+    response.insert_header("Location", format!("{}/post/view/{}", site_url, post_id));
+
+    Ok(response)
+}
+
+async fn update(state: &State, post_id: i64, request: MicropubRequest) -> Result<Response> {
+    let mut tx = state.db_pool.begin().await?;
+
+    let post = match PostRepo::find(&mut tx, post_id).await? {
+        Some(post) => post,
+        None => return Ok(invalid_request("No post at that URL"))
+    };
+
+    let mut content = post.content;
+    let mut short_url = post.short_url;
+    let mut visibility = None;
+
+    if request.delete.iter().any(|name| name == "content") {
+        return Ok(invalid_request("Posts can't be left without content"));
+    }
+
+    if request.delete.iter().any(|name| name == "mp-slug") {
+        short_url = None;
+    }
+
+    // Adding to single valued properties works the same as replacing them
+    for properties in &[&request.add, &request.replace] {
+        if let Some(value) = first_string(properties, "content") {
+            content = value;
+        }
+
+        if let Some(value) = first_string(properties, "mp-slug") {
+            short_url = Some(value).filter(|slug| !slug.is_empty());
+        }
+
+        match parse_visibility(first_string(properties, "visibility")) {
+            Ok(Some(value)) => visibility = Some(value),
+            Ok(None) => (),
+            Err(message) => return Ok(invalid_request(message))
+        }
+    }
+
+    if content.trim().is_empty() {
+        return Ok(invalid_request("Posts can't be left without content"));
+    }
+
+    if let Some(short_url) = &short_url {
+        if short_url_taken(state, short_url, post_id).await? {
+            return Ok(invalid_request("Another post already uses that mp-slug"));
+        }
+    }
+
+    PostRepo::revise(&mut tx, post_id, &content, &short_url).await?;
+
+    if let Some(visibility) = visibility {
+        PostRepo::set_visibility(&mut tx, post_id, visibility.as_str()).await?;
+    }
+
+    tx.commit().await?;
+
+    scheduler::post_edited(state, post_id).await?;
+
+    Ok(Response::new(204))
+}
+
+/// The field name from a part's Content-Disposition header. Only the `name`
+/// parameter counts, so a part with `filename="file"` isn't the `file` field.
+fn multipart_field_name(headers: &[u8]) -> Option<String> {
+    let headers = String::from_utf8_lossy(headers);
+
+    let disposition = headers.split("\r\n").find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+
+        match name.trim().eq_ignore_ascii_case("content-disposition") {
+            true => Some(value.to_string()),
+            false => None
+        }
+    })?;
+
+    disposition.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+
+        match name.trim().eq_ignore_ascii_case("name") {
+            true => Some(value.trim().trim_matches('"').to_string()),
+            false => None
+        }
+    })
+}
+
+/// Pull one field out of a multipart/form-data body
+fn multipart_field(body: &[u8], boundary: &str, field: &str) -> Option<Vec<u8>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+
+    let find = |haystack: &[u8], needle: &[u8]| {
+        haystack.windows(needle.len()).position(|window| window == needle)
+    };
+
+    let mut rest = &body[find(body, &delimiter)? + delimiter.len()..];
+
+    loop {
+        let end = find(rest, &delimiter)?;
+        let part = &rest[..end];
+
+        // Each part is headers, a blank line, then the content followed by a
+        // line break that belongs to the delimiter
+        if let Some(split) = find(part, b"\r\n\r\n") {
+            let headers = &part[..split];
+            let content = &part[split + 4..];
+            let content = content.strip_suffix(b"\r\n").unwrap_or(content);
+
+            if multipart_field_name(headers).as_deref() == Some(field) {
+                return Some(content.to_vec());
+            }
+        }
+
+        rest = &rest[end + delimiter.len()..];
+    }
+}
+
+/// POST /micropub/media takes an image as the `file` field of a multipart
+/// upload and runs it through the same pipeline as the post form
+pub async fn micropub_media(mut req: Request<State>) -> Result<Response> {
+    if let Some(response) = check_token(&req, None, Some("media")).await? {
+        return Ok(response);
+    }
+
+    let boundary = req.content_type()
+        .filter(|mime| mime.essence() == "multipart/form-data")
+        .and_then(|mime| mime.param("boundary").map(|boundary| boundary.to_string()));
+
+    let boundary = match boundary {
+        Some(boundary) => boundary,
+        None => return Ok(invalid_request("Expected a multipart/form-data upload"))
+    };
+
+    let body = req.body_bytes().await?;
+
+    let file = match multipart_field(&body, &boundary, "file") {
+        Some(file) => file,
+        None => return Ok(invalid_request("The upload needs a file field"))
+    };
+
+    let state = req.state();
+    let image = routes::save_image_upload(state, async_std::io::Cursor::new(file)).await?;
+
+    let mut response = Response::new(201);
+    response.insert_header(
+        "Location",
+        format!("{}/uploads/{}", state.config.site_url, image.full_path)
+    );
+
+    Ok(response)
+}
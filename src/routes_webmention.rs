@@ -0,0 +1,145 @@
+use super::State;
+use super::repo::PostRepo;
+use super::routes;
+
+use chrono::prelude::*;
+use serde::Deserialize;
+use tide::http::Url;
+use tide::{Redirect, Request, Response, Result};
+
+// Webmention (https://www.w3.org/TR/webmention/) lets other sites tell us
+// they've linked to one of our posts. Mentions are accepted here and checked
+// later by the background worker in webmention.rs.
+
+#[derive(Deserialize)]
+pub struct WebmentionFormInput {
+    source: Option<String>,
+    target: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct MentionModerateFormInput {
+    #[serde(rename = "csrf-token")]
+    csrf_token: String
+}
+
+fn bad_request(message: &str) -> Response {
+    Response::builder(400).body(message).build()
+}
+
+fn is_http_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| url.scheme() == "http" || url.scheme() == "https")
+}
+
+/// Receive a webmention. The source is verified asynchronously, so all this
+/// checks is that the target is one of our public posts.
+pub async fn webmention_receive(mut req: Request<State>) -> Result<Response> {
+    let form_input: WebmentionFormInput = match req.body_form().await {
+        Ok(form_input) => form_input,
+        Err(_) => return Ok(bad_request("Expected a form with source and target"))
+    };
+
+    let state = req.state();
+
+    let (source, target) = match (form_input.source, form_input.target) {
+        (Some(source), Some(target)) => (source, target),
+        _ => return Ok(bad_request("Both source and target are required"))
+    };
+
+    if !is_http_url(&source) || !is_http_url(&target) {
+        return Ok(bad_request("Source and target must be http(s) URLs"));
+    }
+
+    if source == target {
+        return Ok(bad_request("Source and target must differ"));
+    }
+
+    if !target.starts_with(&format!("{}/", state.config.site_url)) {
+        return Ok(bad_request("Target is not on this site"));
+    }
+
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let post = match routes::post_id_from_url(state, &target).await? {
+        Some(post_id) => PostRepo::find(&mut db_conn, post_id).await?,
+        None => None
+    };
+
+    let post_id = match post {
+        Some(post) if post.is_visible(false) => post.post_id,
+        _ => return Ok(bad_request("Target does not accept webmentions"))
+    };
+
+    let now = Utc::now().to_rfc3339();
+
+    // A repeat mention gets verified again, since the source may have changed
+    // or dropped its link. Rejected mentions stay rejected.
+    sqlx::query(
+            r#"INSERT INTO webmentions (post_id, source, target, received_timestamp) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (source, target) DO UPDATE SET
+                status='pending', received_timestamp=excluded.received_timestamp
+            WHERE webmentions.status != 'rejected'"#
+        )
+        .bind(post_id)
+        .bind(&source)
+        .bind(&target)
+        .bind(&now)
+        .execute(&mut db_conn)
+        .await?;
+
+    Ok(Response::builder(202).body("Webmention accepted for verification").build())
+}
+
+/// Show a verified webmention on its post
+pub async fn mention_approve(req: Request<State>) -> Result<Response> {
+    moderate(req, true).await
+}
+
+/// Hide a webmention for good, even if the source sends it again
+pub async fn mention_reject(req: Request<State>) -> Result<Response> {
+    moderate(req, false).await
+}
+
+async fn moderate(mut req: Request<State>, approve: bool) -> Result<Response> {
+    let session = req.session();
+
+    let csrf_token = session.get::<String>("csrf_token").unwrap();
+    let logged_in = session.get::<bool>("logged_in").unwrap_or(false);
+
+    let form_input: MentionModerateFormInput = req.body_form().await?;
+    let mention_id: i64 = req.param("mention_id")?.parse()?;
+
+    if !logged_in {
+        return Ok(Response::builder(400).body("Forbidden").build());
+    }
+
+    if form_input.csrf_token != csrf_token {
+        return Ok(Response::builder(400).body("Invalid CSRF").build());
+    }
+
+    let mut db_conn = req.state().db_pool.acquire().await?;
+
+    let mention: Option<(i64,)> = sqlx::query_as("SELECT post_id FROM webmentions WHERE rowid=$1")
+        .bind(mention_id)
+        .fetch_optional(&mut db_conn)
+        .await?;
+
+    let post_id = match mention {
+        Some((post_id,)) => post_id,
+        None => return Ok(Response::new(404))
+    };
+
+    if approve {
+        sqlx::query("UPDATE webmentions SET approved=1 WHERE rowid=$1")
+            .bind(mention_id)
+            .execute(&mut db_conn)
+            .await?;
+    } else {
+        sqlx::query("UPDATE webmentions SET status='rejected', approved=0 WHERE rowid=$1")
+            .bind(mention_id)
+            .execute(&mut db_conn)
+            .await?;
+    }
+
+    Ok(Redirect::new(format!("/post/view/{}", post_id)).into())
+}
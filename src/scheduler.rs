@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use chrono::prelude::*;
+
+use super::State;
+
+/// How often the background publisher checks for scheduled posts that are due
+const PUBLISH_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Parse a publish time submitted by a form. Accepts RFC 3339 timestamps, which
+/// is what the frontend sends after converting from the browser's local time.
+pub fn parse_publish_at(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value.trim())
+        .ok()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
+/// Called exactly once per post, at the moment it becomes visible to
+/// everyone. Side effects of publishing belong here rather than in the
+/// handlers, so that scheduled posts trigger them when they go live.
+pub async fn post_published(state: &State, post_id: i64) -> tide::Result<()> {
+    tide::log::info!("Post published", { post_id: post_id });
+
+    super::webmention::queue_post(state, post_id).await?;
+    super::activitypub::post_published(state, post_id).await?;
+
+    Ok(())
+}
+
+/// Called after a post's content or visibility is changed
+pub async fn post_edited(state: &State, post_id: i64) -> tide::Result<()> {
+    super::webmention::queue_post(state, post_id).await?;
+    super::activitypub::post_edited(state, post_id).await?;
+
+    Ok(())
+}
+
+/// Called when a post is moved to the trash
+pub async fn post_trashed(state: &State, post_id: i64) -> tide::Result<()> {
+    super::activitypub::post_trashed(state, post_id).await?;
+
+    Ok(())
+}
+
+/// Publish every scheduled post whose time has come, returning their ids
+pub async fn publish_due_posts(state: &State) -> tide::Result<Vec<i64>> {
+    let mut db_conn = state.db_pool.acquire().await?;
+    let now = Utc::now().to_rfc3339();
+
+    let result: Vec<(i64,)> = sqlx::query_as(
+            r#"SELECT rowid AS post_id FROM posts
+            WHERE published=0 AND deleted_timestamp IS NULL AND posted_timestamp <= $1"#
+        )
+        .bind(now)
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    let mut post_ids = Vec::new();
+
+    for (post_id,) in result {
+        // Guard on published=0 so a post published by hand in the meantime
+        // doesn't fire its side effects twice
+        let updated = sqlx::query("UPDATE posts SET published=1 WHERE rowid=$1 AND published=0")
+            .bind(post_id)
+            .execute(&mut db_conn)
+            .await?;
+
+        if updated.rows_affected() > 0 {
+            post_published(state, post_id).await?;
+            post_ids.push(post_id);
+        }
+    }
+
+    Ok(post_ids)
+}
+
+/// Spawn the background task that publishes scheduled posts
+pub fn spawn_publisher(state: State) {
+    let shutdown = state.shutdown.clone();
+
+    shutdown.spawn_job("publish scheduled posts", PUBLISH_CHECK_INTERVAL, move || {
+        let state = state.clone();
+
+        async move { publish_due_posts(&state).await.map(|_| ()) }
+    });
+}
@@ -0,0 +1,152 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use async_std::channel::{Receiver, Sender};
+use async_std::prelude::*;
+use async_std::task::JoinHandle;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use tide::{Middleware, Next, Request};
+
+use super::State;
+
+// On SIGTERM or SIGINT the server stops accepting connections, then waits for
+// requests in progress and background jobs mid-run to finish before closing
+// the database. A second signal exits right away.
+
+const SIGNALS: &[i32] = &[SIGTERM, SIGINT];
+
+/// How often draining checks whether everything has finished
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Shared by the server and background jobs, so they can tell when to stop and
+/// shutdown can tell when they have
+#[derive(Clone)]
+pub struct Shutdown {
+    /// Closed when shutdown starts, waking everything waiting on it
+    trigger: Sender<()>,
+    stopping: Receiver<()>,
+    busy: Arc<AtomicUsize>
+}
+
+/// Held while a request or job is running; shutdown waits for these
+pub struct Busy(Arc<AtomicUsize>);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (trigger, stopping) = async_std::channel::bounded(1);
+
+        Shutdown {
+            trigger,
+            stopping,
+            busy: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.trigger.is_closed()
+    }
+
+    /// Mark some work as started; it's finished when the guard is dropped
+    pub fn busy(&self) -> Busy {
+        self.busy.fetch_add(1, Ordering::SeqCst);
+
+        Busy(self.busy.clone())
+    }
+
+    /// Wait between runs of a background job. Returns false, early if need be,
+    /// once shutdown has started and the job should stop.
+    pub async fn sleep(&self, interval: Duration) -> bool {
+        let stopping = self.stopping.clone();
+
+        async_std::future::timeout(interval, async move { stopping.recv().await.ok() })
+            .await
+            .is_err()
+    }
+
+    /// Run a background job every `interval` until shutdown starts. A run in
+    /// progress counts as busy work, and a failed run is logged and tried
+    /// again next time.
+    pub fn spawn_job<F, Fut>(&self, name: &'static str, interval: Duration, job: F) -> JoinHandle<()>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = tide::Result<()>> + Send + 'static
+    {
+        let shutdown = self.clone();
+
+        async_std::task::spawn(async move {
+            loop {
+                let busy = shutdown.busy();
+
+                if let Err(e) = job().await {
+                    tide::log::error!("Background job failed", { job: name, error: e.to_string() });
+                }
+
+                drop(busy);
+
+                if !shutdown.sleep(interval).await {
+                    break;
+                }
+            }
+        })
+    }
+
+    /// Start shutting down, then wait up to `timeout` for busy work to finish.
+    /// Returns how much work was still going when it gave up.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.trigger.close();
+
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let busy = self.busy.load(Ordering::SeqCst);
+
+            if busy == 0 || Instant::now() >= deadline {
+                return busy;
+            }
+
+            async_std::task::sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+    }
+}
+
+/// Wait for SIGTERM or SIGINT. After the first, another one exits immediately.
+pub async fn signalled() -> std::io::Result<()> {
+    let force = Arc::new(AtomicBool::new(false));
+
+    for signal in SIGNALS {
+        signal_hook::flag::register_conditional_shutdown(*signal, 1, force.clone())?;
+    }
+
+    let mut signals = signal_hook::iterator::Signals::new(SIGNALS)?;
+
+    async_std::task::spawn_blocking(move || signals.forever().next()).await;
+    force.store(true, Ordering::SeqCst);
+
+    Ok(())
+}
+
+/// Serve until asked to stop. Once the listener is dropped no more connections
+/// are accepted, though requests already in progress carry on.
+pub async fn serve_until_signalled(app: tide::Server<State>, bind_host: String) -> std::io::Result<()> {
+    app.listen(bind_host).race(signalled()).await
+}
+
+/// Counts requests in progress, so shutdown can wait for them
+pub struct TrackRequests;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for TrackRequests {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let _busy = req.state().shutdown.busy();
+
+        Ok(next.run(req).await)
+    }
+}
@@ -0,0 +1,264 @@
+use std::ops::Deref;
+use std::os::unix::fs::PermissionsExt;
+
+use regex::Regex;
+use sqlx::Connection;
+use tempfile::TempDir;
+use tide::http::Url;
+use tide_testing::TideTestingExt;
+use tide_testing::surf::{Body, RequestBuilder, Response};
+
+use super::config::Config;
+use super::State;
+
+// Each test gets a server of its own, with a throwaway database and uploads
+// directory that are removed with it. Databases are SQLite files unless
+// TEST_DATABASE_URL points at a PostgreSQL server, in which case a scratch
+// database is created (and dropped) there.
+
+/// Stands in for GraphicsMagick: "convert <source> [-thumbnail WxH] <dest>" becomes a copy,
+/// and "version" succeeds
+const FAKE_GM: &str = "#!/bin/sh\n[ \"$1\" = version ] && exit 0\nshift\nsource=$1\nfor dest; do :; done\ncp \"$source\" \"$dest\"\n";
+
+/// The config tests start from, before the builder fills in storage
+pub fn test_config() -> Config {
+    Config {
+        admin_username: "testuser".to_string(),
+        admin_password: "testpassword".to_string(),
+        database_url: "sqlite::memory:".to_string(),
+        session_secret: "testsessionsecrettestsessionsecrettestsessionsecret".to_string(),
+        bind_host: "127.0.0.1:8080".to_string(),
+        site_url: "http://127.0.0.1:8080".to_string(),
+        posts_per_page: 20,
+        trash_retention_days: 30,
+        timezone: chrono_tz::UTC,
+        graphicsmagick_path: "gm".into(),
+        restore_path: None,
+        backup_path: None,
+        backup_interval_hours: 0,
+        backup_retention: 7,
+        shutdown_timeout_seconds: 30,
+        // Other sites in tests are stand-ins listening on 127.0.0.1
+        allow_private_addresses: true,
+        uploads_path: "/tmp".into(),
+    }
+}
+
+/// A scratch PostgreSQL database, dropped along with the test app
+struct ScratchDatabase {
+    server_url: String,
+    name: String
+}
+
+impl ScratchDatabase {
+    async fn create(server_url: &str) -> ScratchDatabase {
+        let name = format!("microbloggy_test_{}", rand::random::<u32>());
+        let mut connection = sqlx::AnyConnection::connect(server_url).await.unwrap();
+
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(&mut connection)
+            .await
+            .unwrap();
+
+        ScratchDatabase {
+            server_url: server_url.to_string(),
+            name
+        }
+    }
+
+    fn url(&self) -> String {
+        let mut url = Url::parse(&self.server_url).unwrap();
+        url.set_path(&self.name);
+
+        url.to_string()
+    }
+}
+
+pub struct TestAppBuilder {
+    config: Config,
+    directory: TempDir
+}
+
+impl TestAppBuilder {
+    /// Adjust the config before the app starts
+    pub fn config(mut self, configure: impl FnOnce(&mut Config)) -> Self {
+        configure(&mut self.config);
+        self
+    }
+
+    pub async fn build(mut self) -> TestApp {
+        // Tests that picked their own database keep it
+        let scratch_database = match std::env::var("TEST_DATABASE_URL") {
+            Ok(server_url) if self.config.database_url == default_database_url_for(&self.directory) => {
+                let database = ScratchDatabase::create(&server_url).await;
+                self.config.database_url = database.url();
+
+                Some(database)
+            },
+            _ => None
+        };
+
+        let tera = super::load_templates().unwrap();
+        let db_pool = super::bootstrap_database(&self.config).await.unwrap();
+
+        let state = State {
+            tera,
+            db_pool,
+            config: self.config.clone(),
+            metrics: std::sync::Arc::new(super::metrics::Metrics::new()),
+            shutdown: super::shutdown::Shutdown::new()
+        };
+
+        TestApp {
+            server: super::build_server(state, &self.config),
+            config: self.config,
+            directory: self.directory,
+            scratch_database,
+            cookie: None,
+            csrf_token: None
+        }
+    }
+}
+
+fn default_database_url_for(directory: &TempDir) -> String {
+    format!("sqlite:{}", directory.path().join("microbloggy.sqlite").display())
+}
+
+/// A running app for a test, which can also act as a browser session
+pub struct TestApp {
+    server: tide::Server<State>,
+    pub config: Config,
+    pub directory: TempDir,
+    scratch_database: Option<ScratchDatabase>,
+    cookie: Option<String>,
+    csrf_token: Option<String>
+}
+
+impl TestApp {
+    /// A fresh app using a temporary directory for its database and uploads.
+    /// Image uploads go through a stand-in for GraphicsMagick that copies files.
+    pub fn builder() -> TestAppBuilder {
+        let directory = tempfile::Builder::new()
+            .prefix("microbloggy-test-")
+            .tempdir()
+            .unwrap();
+
+        let uploads_path = directory.path().join("uploads");
+        std::fs::create_dir(&uploads_path).unwrap();
+
+        let graphicsmagick_path = directory.path().join("fake-gm");
+        std::fs::write(&graphicsmagick_path, FAKE_GM).unwrap();
+        std::fs::set_permissions(&graphicsmagick_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = Config {
+            database_url: default_database_url_for(&directory),
+            uploads_path,
+            graphicsmagick_path,
+            ..test_config()
+        };
+
+        TestAppBuilder {
+            config,
+            directory
+        }
+    }
+
+    /// Open a session like a browser would, by loading the login page. This
+    /// picks up the session cookie and the session's CSRF token.
+    pub async fn start_session(&mut self) {
+        let mut response = self.server.get("/user/login").await.unwrap();
+
+        let cookie = response.header("Set-Cookie")
+            .and_then(|values| values.as_str().split(';').next())
+            .expect("The login page should start a session")
+            .to_string();
+
+        let body = response.body_string().await.unwrap();
+        let csrf_token = Regex::new(r#"name="csrf-token" value="([^"]+)""#).unwrap()
+            .captures(&body)
+            .expect("The login page should have a CSRF token")[1]
+            .to_string();
+
+        self.cookie = Some(cookie);
+        self.csrf_token = Some(csrf_token);
+    }
+
+    /// Log the session in as the admin user, starting one if needed
+    pub async fn login(&mut self) {
+        if self.cookie.is_none() {
+            self.start_session().await;
+        }
+
+        let username = self.config.admin_username.clone();
+        let password = self.config.admin_password.clone();
+
+        let response = self.submit("/user/login", &[("username", &username), ("password", &password)]).await;
+
+        assert_eq!(response.header("Location").map(|location| location.as_str()), Some("/"), "Logging in failed");
+    }
+
+    pub fn csrf_token(&self) -> &str {
+        self.csrf_token.as_deref().expect("Start a session first")
+    }
+
+    fn with_session(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.cookie {
+            Some(cookie) => request.header("Cookie", cookie.as_str()),
+            None => request
+        }
+    }
+
+    /// A GET request carrying the session cookie
+    pub fn session_get(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.with_session(self.server.get(url))
+    }
+
+    /// A POST request carrying the session cookie
+    pub fn session_post(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.with_session(self.server.post(url))
+    }
+
+    /// A PUT request carrying the session cookie
+    pub fn session_put(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.with_session(self.server.put(url))
+    }
+
+    /// Submit a form in the session, with its CSRF token filled in
+    pub async fn submit(&self, url: impl AsRef<str>, fields: &[(&str, &str)]) -> Response {
+        let mut fields = fields.to_vec();
+        fields.push(("csrf-token", self.csrf_token()));
+
+        self.session_post(url)
+            .body(Body::from_form(&fields).unwrap())
+            .await
+            .unwrap()
+    }
+}
+
+impl Deref for TestApp {
+    type Target = tide::Server<State>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.server
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        if let Some(database) = self.scratch_database.take() {
+            let db_pool = self.server.state().db_pool.clone();
+
+            // Drop can't await, so the cleanup gets a thread of its own
+            std::thread::spawn(move || async_std::task::block_on(async move {
+                db_pool.close().await;
+
+                let mut connection = sqlx::AnyConnection::connect(&database.server_url).await.unwrap();
+
+                sqlx::query(&format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", database.name))
+                    .execute(&mut connection)
+                    .await
+                    .unwrap();
+            })).join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use super::State;
+
+use chrono::prelude::*;
+use sha2::{Digest, Sha256};
+
+/// Scopes a personal access token can be granted, with a description for the
+/// profile page
+pub const SCOPES: &[(&str, &str)] = &[
+    ("post:write", "Create, edit and delete posts"),
+    ("media:write", "Upload images"),
+    ("profile:write", "Update the profile")
+];
+
+pub const TOKEN_PREFIX: &str = "mbt_";
+
+/// The personal access token scope that covers each narrower IndieAuth scope,
+/// so tokens from either place work with both Micropub and the JSON API
+const INDIEAUTH_EQUIVALENTS: &[(&str, &str)] = &[
+    ("create", "post:write"),
+    ("update", "post:write"),
+    ("delete", "post:write"),
+    ("undelete", "post:write"),
+    ("media", "media:write")
+];
+
+/// A token that was presented with a request and found to be valid
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub scopes: Vec<String>
+}
+
+impl Token {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+
+    /// Whether the token may do something needing `scope`, either because it
+    /// was granted that scope or the personal access token scope covering it.
+    /// Undeleting is allowed to anyone who can delete.
+    pub fn allows(&self, scope: &str) -> bool {
+        self.has_scope(scope)
+            || self.has_scope(personal_scope(scope))
+            || (scope == "undelete" && self.has_scope("delete"))
+    }
+}
+
+/// The personal access token scope covering an IndieAuth scope, or the scope
+/// itself if there isn't one
+pub fn personal_scope(scope: &str) -> &str {
+    INDIEAUTH_EQUIVALENTS.iter()
+        .find(|(indieauth, _)| *indieauth == scope)
+        .map_or(scope, |(_, personal)| personal)
+}
+
+pub fn is_valid_scope(scope: &str) -> bool {
+    SCOPES.iter().any(|(name, _)| *name == scope)
+}
+
+/// Tokens are long random strings, so a plain SHA-256 is enough to keep them
+/// safe at rest
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub fn generate_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+
+    format!("{}{}", TOKEN_PREFIX, base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+}
+
+/// Store a new token and return it. This is the only time the token itself
+/// is available.
+pub async fn create_token(state: &State, name: &str, scopes: &[String]) -> tide::Result<String> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let token = generate_token();
+    let token_hash = hash_token(&token);
+    let scopes = scopes.join(" ");
+    let now = Utc::now().to_rfc3339();
+
+    sqlx::query(
+            r#"INSERT INTO api_tokens (user_id, name, token_hash, scopes, created_timestamp)
+            VALUES ($1, $2, $3, $4, $5)"#
+        )
+        .bind(1_i64)
+        .bind(name)
+        .bind(token_hash)
+        .bind(scopes)
+        .bind(now)
+        .execute(&mut db_conn)
+        .await?;
+
+    Ok(token)
+}
+
+/// Look up a token presented by a client, and record that it was used
+pub async fn verify_token(state: &State, token: &str) -> tide::Result<Option<Token>> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let token_hash = hash_token(token);
+
+    let row: Option<(i64, String)> = sqlx::query_as("SELECT rowid AS token_id, scopes FROM api_tokens WHERE token_hash=$1")
+        .bind(token_hash)
+        .fetch_optional(&mut db_conn)
+        .await?;
+
+    let (token_id, scopes) = match row {
+        Some(row) => row,
+        None => return Ok(None)
+    };
+
+    let now = Utc::now().to_rfc3339();
+
+    sqlx::query("UPDATE api_tokens SET last_used_timestamp=$1 WHERE rowid=$2")
+        .bind(now)
+        .bind(token_id)
+        .execute(&mut db_conn)
+        .await?;
+
+    Ok(Some(Token {
+        scopes: scopes.split_whitespace().map(str::to_string).collect()
+    }))
+}
+
+/// The token from an `Authorization: Bearer` header, if there is one
+pub fn bearer_token(req: &tide::Request<State>) -> Option<String> {
+    let header = req.header("Authorization")?.as_str();
+    let mut parts = header.splitn(2, ' ');
+
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Some(token.trim().to_string())
+        },
+        _ => None
+    }
+}
+
+/// Revoke a token by its value. Returns false if it didn't exist.
+pub async fn revoke_token(state: &State, token: &str) -> tide::Result<bool> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let token_hash = hash_token(token);
+
+    let result = sqlx::query("DELETE FROM api_tokens WHERE token_hash=$1")
+        .bind(token_hash)
+        .execute(&mut db_conn)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
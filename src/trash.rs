@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use chrono::prelude::*;
+
+use super::State;
+use super::routes::Image;
+
+/// How often the background task looks for trashed posts past their retention
+const PURGE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Permanently delete a trashed post, its revisions and its uploaded images.
+/// Posts that aren't in the trash are left alone.
+pub async fn purge_post(state: &State, post_id: i64) -> tide::Result<bool> {
+    let mut tx = state.db_pool.begin().await?;
+
+    let row: Option<(String,)> = sqlx::query_as("SELECT images FROM posts WHERE rowid=$1 AND deleted_timestamp IS NOT NULL")
+        .bind(post_id)
+        .fetch_optional(&mut tx)
+        .await?;
+
+    let images: Vec<Image> = match row {
+        Some((images,)) => serde_json::from_str(images.as_str())?,
+        None => return Ok(false)
+    };
+
+    for statement in [
+        "DELETE FROM post_revisions WHERE post_id=$1",
+        "DELETE FROM webmentions WHERE post_id=$1",
+        "DELETE FROM webmention_outbox WHERE post_id=$1",
+        "DELETE FROM posts WHERE rowid=$1"
+    ] {
+        sqlx::query(statement)
+            .bind(post_id)
+            .execute(&mut tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    // Files go last, so a failed purge never leaves a post with missing images
+    let uploads_path = &state.config.uploads_path;
+
+    for image in images {
+        for filename in &[image.full_path, image.medium_path, image.thumbnail_path] {
+            if let Err(e) = async_std::fs::remove_file(uploads_path.join(filename)).await {
+                tide::log::warn!("Failed to remove purged upload", {
+                    filename: filename,
+                    error: e.to_string()
+                });
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// Purge every trashed post that has been in the trash longer than the
+/// configured retention period, returning their ids
+pub async fn purge_expired_posts(state: &State) -> tide::Result<Vec<i64>> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let retention = chrono::Duration::days(state.config.trash_retention_days as i64);
+    let cutoff = (Utc::now() - retention).to_rfc3339();
+
+    let result: Vec<(i64,)> = sqlx::query_as("SELECT rowid AS post_id FROM posts WHERE deleted_timestamp <= $1")
+        .bind(cutoff)
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    let mut post_ids = Vec::new();
+
+    for (post_id,) in result {
+        if purge_post(state, post_id).await? {
+            post_ids.push(post_id);
+        }
+    }
+
+    Ok(post_ids)
+}
+
+/// Spawn the background task that empties expired posts from the trash
+pub fn spawn_purger(state: State) {
+    let shutdown = state.shutdown.clone();
+
+    shutdown.spawn_job("purge trashed posts", PURGE_CHECK_INTERVAL, move || {
+        let state = state.clone();
+
+        async move {
+            let post_ids = purge_expired_posts(&state).await?;
+
+            if !post_ids.is_empty() {
+                tide::log::info!("Purged trashed posts", { count: post_ids.len() });
+            }
+
+            Ok(())
+        }
+    });
+}
@@ -0,0 +1,381 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use async_std::io::ReadExt;
+use chrono::prelude::*;
+use regex::Regex;
+use tide::http::Url;
+
+use super::State;
+use super::config::Config;
+use super::outbound;
+use super::repo::PostRepo;
+
+/// How often the background task sends queued webmentions and verifies
+/// received ones
+const WEBMENTION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait on another site before giving up on this attempt
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most of a page we're willing to read when discovering or verifying
+const MAX_PAGE_BYTES: u64 = 1024 * 1024;
+
+/// Sends that keep failing are retried with exponential backoff, then dropped
+const MAX_SEND_ATTEMPTS: i64 = 6;
+
+/// How many queued or received webmentions to handle per check
+const BATCH_SIZE: i64 = 20;
+
+/// Absolute http(s) links in a post's Markdown, leaving out links to this
+/// site. Covers both Markdown links and bare URLs in the text.
+pub fn extract_links(content: &str, site_url: &str) -> BTreeSet<String> {
+    let bare_url = Regex::new(r#"https?://[^\s<>"'()\[\]]+"#).unwrap();
+    let mut links = BTreeSet::new();
+
+    for event in pulldown_cmark::Parser::new(content) {
+        match event {
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Link(_, url, _)) => {
+                links.insert(url.to_string());
+            },
+            pulldown_cmark::Event::Text(text) => {
+                for found in bare_url.find_iter(&text) {
+                    // Trailing punctuation is almost always the sentence's
+                    links.insert(found.as_str().trim_end_matches(|c| ".,;:!?".contains(c)).to_string());
+                }
+            },
+            _ => {}
+        }
+    }
+
+    links.into_iter()
+        .filter(|link| link.starts_with("http://") || link.starts_with("https://"))
+        .filter(|link| !link.starts_with(&format!("{}/", site_url)) && link != site_url)
+        .filter(|link| Url::parse(link).is_ok())
+        .collect()
+}
+
+/// The URL other sites see for one of our posts
+pub fn post_url(state: &State, post_id: i64) -> String {
+    format!("{}/post/view/{}", state.config.site_url, post_id)
+}
+
+/// Queue webmentions for everything a post links to. Called whenever a post
+/// is published or edited. Targets it was previously sent to are queued
+/// again too, so a site whose link was removed can take its mention down.
+pub async fn queue_post(state: &State, post_id: i64) -> tide::Result<()> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let post = match PostRepo::find(&mut db_conn, post_id).await? {
+        Some(post) => post,
+        None => return Ok(())
+    };
+
+    // Only public posts are announced; anything else would leak its content
+    if !post.is_visible(false) || post.visibility != "public" {
+        return Ok(());
+    }
+
+    let mut targets = extract_links(&post.content, &state.config.site_url);
+
+    let previous: Vec<(String,)> = sqlx::query_as("SELECT target FROM webmention_outbox WHERE post_id=$1")
+        .bind(post_id)
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    targets.extend(previous.into_iter().map(|(target,)| target));
+
+    let now = Utc::now().to_rfc3339();
+
+    for target in targets {
+        sqlx::query(
+                r#"INSERT INTO webmention_outbox (post_id, target, next_attempt_timestamp) VALUES ($1, $2, $3)
+                ON CONFLICT (post_id, target) DO UPDATE SET
+                    attempts=0, next_attempt_timestamp=excluded.next_attempt_timestamp,
+                    sent_timestamp=NULL, failed_timestamp=NULL"#
+            )
+            .bind(post_id)
+            .bind(&target)
+            .bind(&now)
+            .execute(&mut db_conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Fetch a page with a timeout and a size limit, returning its status, its
+/// Link headers and as much of its body as we're willing to read. Pages on
+/// private addresses aren't fetched.
+pub async fn fetch(config: &Config, url: &str) -> Option<(u16, Vec<String>, String)> {
+    if !outbound::is_allowed(config, url).await {
+        return None;
+    }
+
+    let request = async {
+        let mut response = surf::get(url).await.ok()?;
+
+        let links = response.header("Link")
+            .map(|values| values.iter().map(|value| value.as_str().to_string()).collect())
+            .unwrap_or_default();
+
+        let mut body = Vec::new();
+
+        response.take_body()
+            .into_reader()
+            .take(MAX_PAGE_BYTES)
+            .read_to_end(&mut body)
+            .await
+            .ok()?;
+
+        Some((u16::from(response.status()), links, String::from_utf8_lossy(&body).into_owned()))
+    };
+
+    async_std::future::timeout(REQUEST_TIMEOUT, request).await.ok().flatten()
+}
+
+/// Attributes of every `<a>` and `<link>` tag on a page, in document order
+pub fn link_tags(html: &str) -> Vec<(Option<String>, Option<String>)> {
+    let tag = Regex::new(r"(?is)<(?:a|link)\s[^>]*>").unwrap();
+    let attribute = Regex::new(r#"(?is)\s(rel|href)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap();
+
+    tag.find_iter(html)
+        .map(|found| {
+            let mut rel = None;
+            let mut href = None;
+
+            for capture in attribute.captures_iter(found.as_str()) {
+                let value = capture.get(2).or_else(|| capture.get(3)).or_else(|| capture.get(4))
+                    .map(|value| value.as_str().replace("&amp;", "&"));
+
+                match capture[1].to_ascii_lowercase().as_str() {
+                    "rel" => rel = rel.or(value),
+                    _ => href = href.or(value)
+                }
+            }
+
+            (rel, href)
+        })
+        .collect()
+}
+
+/// Whether a space separated rel attribute includes a value
+pub fn has_rel(rel: &str, wanted: &str) -> bool {
+    rel.split_whitespace().any(|value| value.eq_ignore_ascii_case(wanted))
+}
+
+/// Find a target's webmention endpoint, preferring the HTTP Link header over
+/// tags in the page itself
+pub async fn discover_endpoint(config: &Config, target: &str) -> Option<String> {
+    let base = Url::parse(target).ok()?;
+    let (status, headers, body) = fetch(config, target).await?;
+
+    if !(200..300).contains(&status) {
+        return None;
+    }
+
+    let header_link = Regex::new(r#"<([^>]*)>\s*;\s*rel\s*=\s*"?([^";,]*)"?"#).unwrap();
+
+    let from_header = headers.iter()
+        .flat_map(|header| header_link.captures_iter(header).collect::<Vec<_>>())
+        .find(|capture| has_rel(&capture[2], "webmention"))
+        .map(|capture| capture[1].to_string());
+
+    let endpoint = from_header.or_else(|| {
+        link_tags(&body).into_iter()
+            .find(|(rel, href)| href.is_some() && rel.as_deref().is_some_and(|rel| has_rel(rel, "webmention")))
+            .and_then(|(_, href)| href)
+    })?;
+
+    // An empty href means the page is its own endpoint
+    let endpoint = base.join(&endpoint).ok()?;
+
+    match endpoint.scheme() {
+        "http" | "https" => Some(endpoint.to_string()),
+        _ => None
+    }
+}
+
+/// Tell a target's endpoint that source links to it. Returns whether the
+/// endpoint accepted it.
+pub async fn send(config: &Config, endpoint: &str, source: &str, target: &str) -> bool {
+    if !outbound::is_allowed(config, endpoint).await {
+        return false;
+    }
+
+    let body = match surf::Body::from_form(&[("source", source), ("target", target)]) {
+        Ok(body) => body,
+        Err(_) => return false
+    };
+
+    let request = surf::post(endpoint).body(body);
+
+    match async_std::future::timeout(REQUEST_TIMEOUT, request).await {
+        Ok(Ok(response)) => response.status().is_success(),
+        _ => false
+    }
+}
+
+/// Send every queued webmention that's due, returning how many were sent
+pub async fn send_due(state: &State) -> tide::Result<usize> {
+    let mut db_conn = state.db_pool.acquire().await?;
+    let now = Utc::now();
+    let now_timestamp = now.to_rfc3339();
+
+    let rows: Vec<(i64, i64, String, i64)> = sqlx::query_as(
+            r#"SELECT rowid AS outbox_id, post_id, target, attempts FROM webmention_outbox
+            WHERE sent_timestamp IS NULL AND failed_timestamp IS NULL AND next_attempt_timestamp <= $1
+            ORDER BY next_attempt_timestamp LIMIT $2"#
+        )
+        .bind(&now_timestamp)
+        .bind(BATCH_SIZE)
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    let mut sent = 0;
+
+    for (outbox_id, post_id, target, attempts) in rows {
+        let source = post_url(state, post_id);
+
+        // No endpoint means the site doesn't take webmentions, so there's
+        // nothing to retry
+        let endpoint = match discover_endpoint(&state.config, &target).await {
+            Some(endpoint) => endpoint,
+            None => {
+                sqlx::query("UPDATE webmention_outbox SET failed_timestamp=$1 WHERE rowid=$2")
+                    .bind(&now_timestamp)
+                    .bind(outbox_id)
+                    .execute(&mut db_conn)
+                    .await?;
+
+                continue;
+            }
+        };
+
+        if send(&state.config, &endpoint, &source, &target).await {
+            let sent_timestamp = Utc::now().to_rfc3339();
+
+            sqlx::query("UPDATE webmention_outbox SET sent_timestamp=$1 WHERE rowid=$2")
+                .bind(&sent_timestamp)
+                .bind(outbox_id)
+                .execute(&mut db_conn)
+                .await?;
+
+            sent += 1;
+            continue;
+        }
+
+        let attempts = attempts + 1;
+
+        if attempts >= MAX_SEND_ATTEMPTS {
+            tide::log::warn!("Giving up on webmention", { target: target, post_id: post_id });
+
+            sqlx::query("UPDATE webmention_outbox SET attempts=$1, failed_timestamp=$2 WHERE rowid=$3")
+                .bind(attempts)
+                .bind(&now_timestamp)
+                .bind(outbox_id)
+                .execute(&mut db_conn)
+                .await?;
+        } else {
+            let next_attempt = (now + chrono::Duration::minutes(1 << attempts)).to_rfc3339();
+
+            sqlx::query("UPDATE webmention_outbox SET attempts=$1, next_attempt_timestamp=$2 WHERE rowid=$3")
+                .bind(attempts)
+                .bind(&next_attempt)
+                .bind(outbox_id)
+                .execute(&mut db_conn)
+                .await?;
+        }
+    }
+
+    Ok(sent)
+}
+
+/// The page's title, if it has one
+fn page_title(html: &str) -> Option<String> {
+    let title = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+
+    title.captures(html)
+        .map(|capture| capture[1].split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|title| !title.is_empty())
+}
+
+/// Check whether a source page really links to the target
+fn links_to(html: &str, source: &str, target: &str) -> bool {
+    let (base, target) = match (Url::parse(source), Url::parse(target)) {
+        (Ok(base), Ok(target)) => (base, target),
+        _ => return false
+    };
+
+    link_tags(html).into_iter()
+        .filter_map(|(_, href)| href)
+        .filter_map(|href| base.join(&href).ok())
+        .any(|url| url == target)
+}
+
+/// Fetch the source of every pending webmention and check it links to us,
+/// returning how many were verified
+pub async fn verify_pending(state: &State) -> tide::Result<usize> {
+    let mut db_conn = state.db_pool.acquire().await?;
+
+    let rows: Vec<(i64, String, String)> = sqlx::query_as(
+            r#"SELECT rowid AS mention_id, source, target FROM webmentions
+            WHERE status='pending' ORDER BY received_timestamp LIMIT $1"#
+        )
+        .bind(BATCH_SIZE)
+        .fetch_all(&mut db_conn)
+        .await?;
+
+    let mut verified = 0;
+
+    for (mention_id, source, target) in rows {
+        let now = Utc::now().to_rfc3339();
+
+        let page = fetch(&state.config, &source).await
+            .filter(|(status, _, _)| (200..300).contains(status))
+            .map(|(_, _, body)| body);
+
+        match page {
+            Some(body) if links_to(&body, &source, &target) => {
+                let title = page_title(&body);
+
+                sqlx::query("UPDATE webmentions SET status='verified', title=$1, verified_timestamp=$2 WHERE rowid=$3")
+                    .bind(&title)
+                    .bind(&now)
+                    .bind(mention_id)
+                    .execute(&mut db_conn)
+                    .await?;
+
+                verified += 1;
+            },
+            _ => {
+                // The link is gone (or never existed), so take the mention down
+                sqlx::query("UPDATE webmentions SET status='invalid', verified_timestamp=$1 WHERE rowid=$2")
+                    .bind(&now)
+                    .bind(mention_id)
+                    .execute(&mut db_conn)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(verified)
+}
+
+/// Spawn the background task that sends and verifies webmentions
+pub fn spawn_worker(state: State) {
+    let shutdown = state.shutdown.clone();
+    let verifier_state = state.clone();
+
+    shutdown.spawn_job("send webmentions", WEBMENTION_CHECK_INTERVAL, move || {
+        let state = state.clone();
+
+        async move { send_due(&state).await.map(|_| ()) }
+    });
+
+    shutdown.spawn_job("verify webmentions", WEBMENTION_CHECK_INTERVAL, move || {
+        let state = verifier_state.clone();
+
+        async move { verify_pending(&state).await.map(|_| ()) }
+    });
+}
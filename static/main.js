@@ -1,7 +1,7 @@
 function resizeTextareas() {
     let textareas = document.getElementsByTagName("textarea");
 
-    for (textarea of textareas) {
+    for (const textarea of textareas) {
         // Don't know why this is necessary
         textarea.style.height = "inherit";
 
@@ -19,11 +19,25 @@ function resizeTextareas() {
     }
 }
 
+/*
+ * Forms send timestamps as UTC, but datetime-local inputs are in the browser's
+ * timezone. Copy each one into its hidden target as an ISO string on submit.
+ */
+function convertLocalDatetimes(form) {
+    let inputs = form.querySelectorAll("input[type=datetime-local][data-utc-target]");
+
+    for (const input of inputs) {
+        let target = form.querySelector("input[name=" + input.dataset.utcTarget + "]");
+
+        target.value = input.value ? new Date(input.value).toISOString() : "";
+    }
+}
+
 window.onload = (e) => {
     // Turn all UTC dates into friendly, browser-timezone-local strings
     let dates = document.getElementsByTagName("time");
 
-    for (date of dates) {
+    for (const date of dates) {
         let dateData = new Date();
 
         dateData.setTime(
@@ -33,6 +47,10 @@ window.onload = (e) => {
         date.textContent = dateData.toLocaleString();
     }
 
+    for (const form of document.forms) {
+        form.addEventListener("submit", (e) => convertLocalDatetimes(e.target));
+    }
+
     // Resize all textareas
     resizeTextareas();
 